@@ -0,0 +1,111 @@
+use std::{ffi::OsString, fs};
+
+use anyhow::{bail, Context};
+
+/// Argument files can include other argument files up to this depth
+const MAX_DEPTH: usize = 16;
+
+/// Replace each `@FILE` argument with the arguments in that file
+///
+/// Arguments in the file are separated by whitespace, and can be quoted with
+/// `'` or `"`. A backslash outside of `'` quotes makes the next character
+/// literal. Argument files can contain `@FILE` arguments too.
+pub fn expand(args: impl IntoIterator<Item = OsString>) -> anyhow::Result<Vec<OsString>> {
+    let mut res = Vec::new();
+    for (i, arg) in args.into_iter().enumerate() {
+        // The program name is not expanded
+        match i {
+            0 => res.push(arg),
+            _ => expand_arg(arg, &mut res, 0)?,
+        }
+    }
+    Ok(res)
+}
+
+fn expand_arg(arg: OsString, res: &mut Vec<OsString>, depth: usize) -> anyhow::Result<()> {
+    let Some(path) = arg.to_str().and_then(|a| a.strip_prefix('@')) else {
+        res.push(arg);
+        return Ok(());
+    };
+
+    if depth >= MAX_DEPTH {
+        bail!("Argument files nested too deeply at {path:?}")
+    }
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Cannot read argument file {path:?}"))?;
+    let words = split(&text).with_context(|| format!("Invalid argument file {path:?}"))?;
+    for word in words {
+        expand_arg(word.into(), res, depth + 1)?;
+    }
+    Ok(())
+}
+
+/// Split text into words, as described for `expand`
+fn split(text: &str) -> anyhow::Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), c) => word.get_or_insert_with(String::new).push(c),
+            (_, '\\') => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => bail!("Backslash at end of file"),
+            },
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if let Some(q) = quote {
+        bail!("Missing closing {q}")
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words() {
+        assert_eq!(
+            split("  --base 0x1000\n\t--gap-fill=0xff  in.elf ").unwrap(),
+            ["--base", "0x1000", "--gap-fill=0xff", "in.elf"],
+        );
+        assert!(split(" \n ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn quotes() {
+        assert_eq!(
+            split(r#"'a b' "c d" e'f g'h "" ''"#).unwrap(),
+            ["a b", "c d", "ef gh", "", ""],
+        );
+        assert_eq!(split(r#"'"' "'""#).unwrap(), ["\"", "'"]);
+    }
+
+    #[test]
+    fn backslashes() {
+        assert_eq!(
+            split(r#"a\ b "c\"d" 'e\f' \'"#).unwrap(),
+            ["a b", "c\"d", "e\\f", "'"],
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert!(split("'abc").is_err());
+        assert!(split("\"abc").is_err());
+        assert!(split("abc\\").is_err());
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    env,
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+    process,
+    time::{Duration, Instant},
+};
+
+use clap::{Args, ValueEnum};
+
+use crate::{info, layout};
+
+#[derive(Debug, Args)]
+pub struct BenchArgs {
+    /// Number of segments in the generated input
+    #[arg(long, default_value_t = 16)]
+    segments: u64,
+
+    /// Size of each segment
+    #[arg(long, value_name = "SIZE", value_parser = layout::parse_size, default_value = "4M")]
+    segment_size: u64,
+
+    /// Size of gaps between segments
+    #[arg(long, value_name = "SIZE", value_parser = layout::parse_size, default_value = "64K")]
+    gap: u64,
+
+    /// How gap sizes vary between segments
+    #[arg(long, value_name = "PATTERN", default_value = "even")]
+    gap_pattern: GapPattern,
+
+    /// How many times to convert with each backend, keeping the fastest
+    #[arg(long, default_value_t = 3)]
+    runs: u32,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum GapPattern {
+    /// Every gap is the same size
+    Even,
+    /// The Nth gap is N times the size
+    Growing,
+    /// Gaps alternate between none and twice the size
+    Alternating,
+}
+
+impl GapPattern {
+    fn gap(self, size: u64, i: u64) -> u64 {
+        match self {
+            GapPattern::Even => size,
+            GapPattern::Growing => size * (i + 1),
+            GapPattern::Alternating => size * 2 * (i % 2),
+        }
+    }
+}
+
+/// Ways of writing the output to compare, as extra options
+fn backends() -> Vec<(&'static str, Vec<&'static str>)> {
+    let mut res = vec![
+        ("seek", vec![]),
+        ("dense", vec!["--dense"]),
+        ("sparse", vec!["--sparse"]),
+        ("gap-fill", vec!["--gap-fill", "0xff"]),
+    ];
+    if cfg!(all(feature = "io-uring", target_os = "linux")) {
+        res.push(("uring", vec!["--io-backend", "uring"]));
+    }
+    res
+}
+
+/// Write a 64-bit little endian ELF file with the given segments, as
+/// addresses and sizes, filled with pseudo-random bytes
+fn write_elf(path: &Path, segments: &[(u64, u64)]) -> anyhow::Result<()> {
+    const EHDR_SIZE: u64 = 0x40;
+    const PHDR_SIZE: u64 = 0x38;
+    const ALIGN: u64 = 0x1000;
+    const PT_LOAD: u32 = 1;
+    const PF_R_X: u32 = 5;
+    const EM_RISCV: u16 = 243;
+
+    let mut offsets = Vec::new();
+    let mut offset = (EHDR_SIZE + PHDR_SIZE * segments.len() as u64).next_multiple_of(ALIGN);
+    for &(address, size) in segments {
+        // Keep offsets and addresses the same modulo the alignment
+        offset += (address.wrapping_sub(offset)) % ALIGN;
+        offsets.push(offset);
+        offset += size;
+    }
+
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(b"\x7fELF\x02\x01\x01")?;
+    w.write_all(&[0; 9])?;
+    w.write_all(&2u16.to_le_bytes())?; // e_type: ET_EXEC
+    w.write_all(&EM_RISCV.to_le_bytes())?;
+    w.write_all(&1u32.to_le_bytes())?; // e_version
+    w.write_all(&segments.first().map_or(0, |s| s.0).to_le_bytes())?; // e_entry
+    w.write_all(&EHDR_SIZE.to_le_bytes())?; // e_phoff
+    w.write_all(&0u64.to_le_bytes())?; // e_shoff
+    w.write_all(&0u32.to_le_bytes())?; // e_flags
+    w.write_all(&(EHDR_SIZE as u16).to_le_bytes())?;
+    w.write_all(&(PHDR_SIZE as u16).to_le_bytes())?;
+    w.write_all(&u16::try_from(segments.len())?.to_le_bytes())?;
+    w.write_all(&0x40u16.to_le_bytes())?; // e_shentsize
+    w.write_all(&0u16.to_le_bytes())?; // e_shnum
+    w.write_all(&0u16.to_le_bytes())?; // e_shstrndx
+
+    for (&(address, size), &offset) in segments.iter().zip(&offsets) {
+        w.write_all(&PT_LOAD.to_le_bytes())?;
+        w.write_all(&PF_R_X.to_le_bytes())?;
+        for value in [offset, address, address, size, size, ALIGN] {
+            w.write_all(&value.to_le_bytes())?;
+        }
+    }
+
+    let mut pos = EHDR_SIZE + PHDR_SIZE * segments.len() as u64;
+    let mut state = 0x2545f4914f6cdd1d_u64;
+    let mut block = vec![0; 0x10000];
+    for (&(_, size), &offset) in segments.iter().zip(&offsets) {
+        io::copy(&mut io::repeat(0).take(offset - pos), &mut w)?;
+        let mut left = size;
+        while left > 0 {
+            for b in block.chunks_exact_mut(8) {
+                // xorshift64
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                b.copy_from_slice(&state.to_le_bytes());
+            }
+            let n = left.min(block.len() as u64);
+            w.write_all(&block[..n as usize])?;
+            left -= n;
+        }
+        pos = offset + size;
+    }
+
+    w.flush()?;
+    Ok(())
+}
+
+/// Generate an input file, and time converting it with each backend
+///
+/// `convert` converts with the given command line arguments for copying.
+pub fn run(
+    args: BenchArgs,
+    mut convert: impl FnMut(&[OsString]) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let dir = env::temp_dir().join(format!("elfcopyflat-bench-{}", process::id()));
+    fs::create_dir(&dir)?;
+    let res = bench(&args, &dir, &mut convert);
+    let _ = fs::remove_dir_all(&dir);
+    res
+}
+
+fn bench(
+    args: &BenchArgs,
+    dir: &Path,
+    convert: &mut dyn FnMut(&[OsString]) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut segments = Vec::new();
+    let mut address = 0x8000_0000;
+    for i in 0..args.segments {
+        segments.push((address, args.segment_size));
+        address += args.segment_size + args.gap_pattern.gap(args.gap, i);
+    }
+
+    let input = dir.join("input.elf");
+    let output = dir.join("output.bin");
+    write_elf(&input, &segments)?;
+    let image_size = segments
+        .last()
+        .map_or(0, |&(a, size)| a + size - 0x8000_0000);
+    let payload = args.segments * args.segment_size;
+    println!(
+        "{} segments, {payload:#x} bytes of contents, image of {image_size:#x} bytes",
+        args.segments,
+    );
+
+    let mut rows = Vec::new();
+    for (name, options) in backends() {
+        let mut best = Duration::MAX;
+        for _ in 0..args.runs.max(1) {
+            let _ = fs::remove_file(&output);
+            let mut copy_args: Vec<OsString> = options.iter().map(OsString::from).collect();
+            copy_args.extend([input.clone().into(), output.clone().into()]);
+
+            let start = Instant::now();
+            convert(&copy_args)?;
+            best = best.min(start.elapsed());
+        }
+
+        let secs = best.as_secs_f64();
+        rows.push(vec![
+            name.to_owned(),
+            format!("{:.3}", secs * 1000.0),
+            format!("{:.1}", payload as f64 / (1 << 20) as f64 / secs),
+        ]);
+    }
+
+    info::print_table(&["Backend", "Time (ms)", "Contents (MiB/s)"], &rows);
+    Ok(())
+}
@@ -0,0 +1,163 @@
+//! `cargo elfcopyflat`: Build a binary with cargo, and convert it to a flat
+//! binary with options from `[package.metadata.elfcopyflat]`
+
+use std::{ffi::OsString, path::PathBuf, process::Stdio};
+
+use anyhow::{bail, Context};
+use clap::Parser;
+use elfcopyflat::build_support::{cargo, Metadata};
+use serde_json::Value;
+
+#[derive(Debug, Parser)]
+#[command(bin_name = "cargo")]
+enum Cargo {
+    /// Build a binary and convert it to a flat binary
+    Elfcopyflat(Opts),
+}
+
+#[derive(Debug, clap::Args)]
+#[command(version)]
+struct Opts {
+    /// Package to build
+    #[arg(long, short)]
+    package: Option<String>,
+
+    /// Binary to build
+    #[arg(long, conflicts_with = "example")]
+    bin: Option<String>,
+
+    /// Example to build
+    #[arg(long)]
+    example: Option<String>,
+
+    /// Build with the release profile
+    #[arg(long, short, conflicts_with = "profile")]
+    release: bool,
+
+    /// Build with this profile
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Build for this target triple
+    #[arg(long)]
+    target: Option<String>,
+
+    /// Features to enable
+    #[arg(long, short = 'F')]
+    features: Vec<String>,
+
+    /// Output file (Default: The built binary with the extension .bin)
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+
+    /// More options for elfcopyflat, which take precedence over the metadata
+    #[arg(last = true)]
+    args: Vec<OsString>,
+}
+
+impl Opts {
+    /// Name of the profile, as used in `[package.metadata.elfcopyflat.profile.NAME]`
+    fn profile_name(&self) -> &str {
+        match (&self.profile, self.release) {
+            (Some(profile), _) => profile,
+            (None, true) => "release",
+            (None, false) => "dev",
+        }
+    }
+}
+
+/// A binary built by cargo
+struct Artifact {
+    package_id: String,
+    name: String,
+    path: PathBuf,
+}
+
+/// Build, returning the executables that were built
+fn build(opts: &Opts) -> anyhow::Result<Vec<Artifact>> {
+    let mut cmd = cargo();
+    cmd.args(["build", "--message-format=json-render-diagnostics"]);
+    let options = [
+        ("--package", &opts.package),
+        ("--bin", &opts.bin),
+        ("--example", &opts.example),
+        ("--profile", &opts.profile),
+        ("--target", &opts.target),
+    ];
+    for (flag, value) in options {
+        if let Some(value) = value {
+            cmd.args([flag, value]);
+        }
+    }
+    if opts.release {
+        cmd.arg("--release");
+    }
+    for features in &opts.features {
+        cmd.args(["--features", features]);
+    }
+
+    let output = cmd
+        .stderr(Stdio::inherit())
+        .output()
+        .context("Cannot run cargo build")?;
+    if !output.status.success() {
+        bail!("cargo build failed")
+    }
+
+    let mut res = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let Ok(message) = serde_json::from_str::<Value>(line) else {
+            continue;
+        };
+        if message["reason"] != "compiler-artifact" {
+            continue;
+        }
+        let Some(path) = message["executable"].as_str() else {
+            continue;
+        };
+        res.push(Artifact {
+            package_id: message["package_id"]
+                .as_str()
+                .unwrap_or_default()
+                .to_owned(),
+            name: message["target"]["name"]
+                .as_str()
+                .unwrap_or_default()
+                .to_owned(),
+            path: path.into(),
+        });
+    }
+    Ok(res)
+}
+
+fn run(opts: Opts) -> anyhow::Result<()> {
+    let artifacts = build(&opts)?;
+    let artifact = match &artifacts[..] {
+        [] => bail!("No binary was built (Use --bin or --example to pick one)"),
+        [artifact] => artifact,
+        _ => {
+            let names: Vec<&str> = artifacts.iter().map(|a| a.name.as_str()).collect();
+            bail!(
+                "More than one binary was built: {} (Use --bin or --example to pick one)",
+                names.join(", "),
+            )
+        }
+    };
+
+    let metadata = Metadata::read(None, Some(&artifact.package_id))?;
+    let output = metadata.flatten(
+        &artifact.path,
+        &artifact.name,
+        opts.profile_name(),
+        opts.output.as_deref(),
+        &opts.args,
+    )?;
+
+    eprintln!("Wrote {}", output.display());
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let Cargo::Elfcopyflat(opts) = Cargo::parse();
+    run(opts)
+}
@@ -0,0 +1,194 @@
+//! Converting built binaries with options from `[package.metadata.elfcopyflat]`
+//!
+//! Keys in the table are long options of `elfcopyflat`, e.g.
+//! `gap-fill = 0xff`. A `[package.metadata.elfcopyflat.profile.NAME]` table
+//! has options for one profile, which replace the ones for all profiles.
+//! `output` is the output file, relative to the package, where `{name}` and
+//! `{profile}` are replaced by the name of the binary and the profile.
+
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{bail, Context};
+use serde_json::{Map, Value};
+
+/// The `[package.metadata.elfcopyflat]` table of a package
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    table: Map<String, Value>,
+    package_dir: PathBuf,
+}
+
+impl Metadata {
+    /// Read the metadata of the package with the manifest `manifest_path`,
+    /// or if not given, the package with the ID `package_id`, with `cargo
+    /// metadata`
+    pub fn read(manifest_path: Option<&Path>, package_id: Option<&str>) -> anyhow::Result<Self> {
+        let mut cmd = cargo();
+        cmd.args(["metadata", "--format-version", "1", "--no-deps"]);
+        if let Some(manifest_path) = manifest_path {
+            cmd.arg("--manifest-path").arg(manifest_path);
+        }
+        let output =
+            (cmd.stderr(Stdio::inherit()).output()).context("Cannot run cargo metadata")?;
+        if !output.status.success() {
+            bail!("cargo metadata failed")
+        }
+
+        let metadata: Value =
+            serde_json::from_slice(&output.stdout).context("Invalid output from cargo metadata")?;
+        let manifest_path = manifest_path.and_then(|p| p.canonicalize().ok());
+        let package = (metadata["packages"].as_array().into_iter().flatten()).find(|p| {
+            match (&manifest_path, package_id) {
+                (Some(path), _) => {
+                    let this = Path::new(p["manifest_path"].as_str().unwrap_or_default());
+                    this.canonicalize().is_ok_and(|this| this == *path)
+                }
+                (None, Some(id)) => p["id"] == id,
+                (None, None) => false,
+            }
+        });
+        let Some(package) = package else {
+            bail!("Cannot find the package in the output of cargo metadata")
+        };
+
+        let package_dir = Path::new(package["manifest_path"].as_str().unwrap_or_default())
+            .parent()
+            .unwrap_or(Path::new("."))
+            .to_owned();
+        let table = match &package["metadata"]["elfcopyflat"] {
+            Value::Object(table) => table.clone(),
+            Value::Null => Map::new(),
+            _ => bail!("[package.metadata.elfcopyflat] is not a table"),
+        };
+
+        Ok(Self { table, package_dir })
+    }
+
+    pub fn package_dir(&self) -> &Path {
+        &self.package_dir
+    }
+
+    /// Convert `artifact`, the binary `name` built with `profile`
+    ///
+    /// The output is `output` if given, or else from the metadata, or else
+    /// `artifact` with the extension `.bin`. Options in `extra` replace the
+    /// ones from the metadata. Returns the output file.
+    pub fn flatten(
+        &self,
+        artifact: &Path,
+        name: &str,
+        profile: &str,
+        output: Option<&Path>,
+        extra: &[OsString],
+    ) -> anyhow::Result<PathBuf> {
+        let mut table = self.table.clone();
+        if let Some(options) = self
+            .table
+            .get("profile")
+            .and_then(|p| p[profile].as_object())
+        {
+            table.extend(options.clone());
+        }
+        table.retain(|key, _| {
+            let flag = format!("--{key}");
+            !(extra.iter().filter_map(|a| a.to_str()))
+                .any(|a| a == flag || a.starts_with(&format!("{flag}=")))
+        });
+
+        let mut args = to_args(&table)?;
+        args.extend(extra.iter().cloned());
+
+        let output = match (output, table.get("output").and_then(Value::as_str)) {
+            (Some(output), _) => output.to_owned(),
+            (None, Some(output)) => self
+                .package_dir
+                .join(output.replace("{name}", name).replace("{profile}", profile)),
+            (None, None) => artifact.with_extension("bin"),
+        };
+        if let Some(dir) = output.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).with_context(|| format!("Cannot create {dir:?}"))?;
+        }
+
+        args.extend(["--force".into(), "--".into()]);
+        args.extend([artifact.into(), output.clone().into()]);
+
+        let status = Command::new(elfcopyflat())
+            .args(&args)
+            .status()
+            .context("Cannot run elfcopyflat")?;
+        if !status.success() {
+            bail!("elfcopyflat failed to convert {artifact:?}")
+        }
+
+        Ok(output)
+    }
+}
+
+/// Convert `artifact` with options from the package being built, as found
+/// from `CARGO_MANIFEST_DIR` and `PROFILE`, returning the output file
+///
+/// This is for build scripts and runners, where cargo sets these variables.
+pub fn flatten_artifact(artifact: &Path) -> anyhow::Result<PathBuf> {
+    let Some(dir) = env::var_os("CARGO_MANIFEST_DIR") else {
+        bail!("CARGO_MANIFEST_DIR is not set (Run this from a build script or runner)")
+    };
+    // Build scripts only see "debug" or "release"
+    let profile = match env::var("PROFILE").as_deref() {
+        Ok("debug") | Err(_) => "dev".to_owned(),
+        Ok(profile) => profile.to_owned(),
+    };
+    let name = artifact.file_stem().unwrap_or_default().to_string_lossy();
+
+    let metadata = Metadata::read(Some(&Path::new(&dir).join("Cargo.toml")), None)?;
+    metadata.flatten(artifact, &name, &profile, None, &[])
+}
+
+/// The cargo running us, or else the one in `PATH`
+pub fn cargo() -> Command {
+    Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into()))
+}
+
+/// `ELFCOPYFLAT` if set, or else the elfcopyflat next to this program, or
+/// else the one in `PATH`
+fn elfcopyflat() -> PathBuf {
+    if let Some(path) = env::var_os("ELFCOPYFLAT") {
+        return path.into();
+    }
+    let name = format!("elfcopyflat{}", env::consts::EXE_SUFFIX);
+    env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.parent()?.join(&name)))
+        .filter(|path| path.exists())
+        .unwrap_or_else(|| name.into())
+}
+
+/// Turn keys of a metadata table into options, leaving out `profile` and
+/// `output`
+fn to_args(table: &Map<String, Value>) -> anyhow::Result<Vec<OsString>> {
+    let mut args = Vec::new();
+    for (key, value) in table {
+        if key == "profile" || key == "output" {
+            continue;
+        }
+        let values = match value {
+            Value::Array(values) => values.clone(),
+            value => vec![value.clone()],
+        };
+        for value in values {
+            match value {
+                Value::Bool(true) => args.push(format!("--{key}").into()),
+                Value::Bool(false) => {}
+                Value::String(s) => args.extend([format!("--{key}").into(), s.into()]),
+                Value::Number(n) => args.extend([format!("--{key}").into(), n.to_string().into()]),
+                _ => bail!("Invalid value for {key:?} in [package.metadata.elfcopyflat]"),
+            }
+        }
+    }
+    Ok(args)
+}
@@ -0,0 +1,236 @@
+use clap::{Args, ValueEnum};
+
+#[derive(Debug, Args)]
+pub struct CompletionsArgs {
+    /// Shell to write completions for
+    shell: Shell,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// An option, as offered for completion
+struct Opt {
+    long: Option<String>,
+    short: Option<char>,
+    help: String,
+    takes_value: bool,
+    values: Vec<String>,
+}
+
+/// Options of a command, and its subcommands with their descriptions and
+/// options
+struct Spec {
+    name: String,
+    opts: Vec<Opt>,
+    subcommands: Vec<(String, String, Vec<Opt>)>,
+}
+
+/// The first line of a help text
+fn summary(help: Option<&clap::builder::StyledStr>) -> String {
+    let help = help.map(|h| h.to_string()).unwrap_or_default();
+    help.lines().next().unwrap_or("").to_owned()
+}
+
+fn opts(cmd: &clap::Command) -> Vec<Opt> {
+    (cmd.get_arguments())
+        .filter(|a| !a.is_positional() && !a.is_hide_set())
+        .map(|a| Opt {
+            long: a.get_long().map(str::to_owned),
+            short: a.get_short(),
+            help: summary(a.get_help()),
+            takes_value: a.get_action().takes_values(),
+            values: (a.get_possible_values().iter())
+                .filter(|v| !v.is_hide_set())
+                .map(|v| v.get_name().to_owned())
+                .collect(),
+        })
+        .collect()
+}
+
+fn spec(mut cmd: clap::Command) -> Spec {
+    cmd.build();
+    let subcommands = (cmd.get_subcommands())
+        .filter(|c| !c.is_hide_set() && c.get_name() != "help")
+        .map(|c| (c.get_name().to_owned(), summary(c.get_about()), opts(c)))
+        .collect();
+
+    Spec {
+        name: cmd.get_name().to_owned(),
+        opts: opts(&cmd),
+        subcommands,
+    }
+}
+
+fn flags(opts: &[Opt]) -> Vec<String> {
+    let long = opts
+        .iter()
+        .filter_map(|o| Some(format!("--{}", o.long.as_ref()?)));
+    let short = opts.iter().filter_map(|o| Some(format!("-{}", o.short?)));
+    long.chain(short).collect()
+}
+
+fn bash(spec: &Spec) -> String {
+    let name = &spec.name;
+    let subcommands: Vec<&str> = spec.subcommands.iter().map(|s| s.0.as_str()).collect();
+    let subcommands = subcommands.join(" ");
+
+    let mut cases = String::new();
+    for (sub, _, opts) in &spec.subcommands {
+        cases += &format!("        {sub}) opts=\"{}\" ;;\n", flags(opts).join(" "));
+    }
+
+    format!(
+        r#"_{name}() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    local cmd="" opts words
+    for word in "${{COMP_WORDS[@]:1:COMP_CWORD-1}}"; do
+        case "$word" in
+            {pattern}) cmd="$word"; break ;;
+        esac
+    done
+
+    case "$cmd" in
+{cases}        *) opts="{top}" ;;
+    esac
+
+    if [[ "$cur" == -* ]]; then
+        COMPREPLY=($(compgen -W "$opts" -- "$cur"))
+    else
+        words=""
+        if [[ -z "$cmd" ]]; then
+            words="{subcommands}"
+        fi
+        COMPREPLY=($(compgen -W "$words" -- "$cur") $(compgen -f -- "$cur"))
+    fi
+}}
+
+complete -o filenames -F _{name} {name}
+"#,
+        pattern = subcommands.replace(' ', "|"),
+        top = flags(&spec.opts).join(" "),
+    )
+}
+
+fn zsh_escape(s: &str) -> String {
+    (s.replace('\\', "\\\\"))
+        .replace('\'', "'\\''")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+        .replace(':', "\\:")
+}
+
+fn zsh_arguments(opts: &[Opt]) -> String {
+    let mut res = String::from("_arguments -s");
+    for o in opts {
+        let help = zsh_escape(&o.help);
+        let value = match (o.takes_value, o.values.is_empty()) {
+            (false, _) => String::new(),
+            (true, true) => ":value:_files".to_owned(),
+            (true, false) => format!(":value:({})", o.values.join(" ")),
+        };
+        for flag in [
+            o.long.as_ref().map(|l| format!("--{l}")),
+            o.short.map(|s| format!("-{s}")),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            res += &format!(" \\\n            '{flag}[{help}]{value}'");
+        }
+    }
+    res += " \\\n            '*:file:_files'";
+    res
+}
+
+fn zsh(spec: &Spec) -> String {
+    let name = &spec.name;
+
+    let mut described = String::new();
+    let mut cases = String::new();
+    for (sub, about, opts) in &spec.subcommands {
+        described += &format!("        '{sub}:{}'\n", zsh_escape(about));
+        cases += &format!("        {sub})\n            {} ;;\n", zsh_arguments(opts));
+    }
+
+    format!(
+        r#"#compdef {name}
+
+_{name}() {{
+    local -a subcommands
+    subcommands=(
+{described}    )
+
+    if (( CURRENT == 2 )) && [[ "$words[2]" != -* ]]; then
+        _describe 'command' subcommands
+        _files
+        return
+    fi
+
+    case "$words[2]" in
+{cases}        *)
+            {top} ;;
+    esac
+}}
+
+_{name} "$@"
+"#,
+        top = zsh_arguments(&spec.opts),
+    )
+}
+
+fn fish_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+fn fish_opts(name: &str, condition: &str, opts: &[Opt]) -> String {
+    let mut res = String::new();
+    for o in opts {
+        res += &format!("complete -c {name} -n '{condition}'");
+        if let Some(long) = &o.long {
+            res += &format!(" -l {long}");
+        }
+        if let Some(short) = o.short {
+            res += &format!(" -s {short}");
+        }
+        if o.takes_value {
+            res += " -r";
+        }
+        if !o.values.is_empty() {
+            res += &format!(" -f -a '{}'", o.values.join(" "));
+        }
+        res += &format!(" -d '{}'\n", fish_escape(&o.help));
+    }
+    res
+}
+
+fn fish(spec: &Spec) -> String {
+    let name = &spec.name;
+    let mut res = fish_opts(name, "__fish_use_subcommand", &spec.opts);
+    for (sub, about, _) in &spec.subcommands {
+        res += &format!(
+            "complete -c {name} -n '__fish_use_subcommand' -a {sub} -d '{}'\n",
+            fish_escape(about),
+        );
+    }
+    for (sub, _, opts) in &spec.subcommands {
+        res += &fish_opts(name, &format!("__fish_seen_subcommand_from {sub}"), opts);
+    }
+    res
+}
+
+/// Print a completion script for `cmd`
+pub fn run(args: CompletionsArgs, cmd: clap::Command) -> anyhow::Result<()> {
+    let spec = spec(cmd);
+    let script = match args.shell {
+        Shell::Bash => bash(&spec),
+        Shell::Zsh => zsh(&spec),
+        Shell::Fish => fish(&spec),
+    };
+    print!("{script}");
+    Ok(())
+}
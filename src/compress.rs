@@ -0,0 +1,55 @@
+use std::io::{self, Write};
+
+use clap::ValueEnum;
+
+/// Compression algorithms for output data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    /// Zstandard
+    Zstd,
+    /// LZ4 (Raw blocks for segment containers, frame format for whole images)
+    Lz4,
+    /// gzip
+    Gzip,
+}
+
+impl Compression {
+    /// Compress a buffer in one go
+    pub fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::Zstd => zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+            Compression::Lz4 => Ok(lz4_flex::block::compress(data)),
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Compress everything `f` writes, streaming it into `w`
+    pub fn write_stream<W: Write>(
+        self,
+        w: W,
+        f: impl FnOnce(&mut dyn Write) -> anyhow::Result<()>,
+    ) -> anyhow::Result<W> {
+        match self {
+            Compression::Zstd => {
+                let mut encoder = zstd::Encoder::new(w, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+                f(&mut encoder)?;
+                Ok(encoder.finish()?)
+            }
+            Compression::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(w);
+                f(&mut encoder)?;
+                Ok(encoder.finish()?)
+            }
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(w, flate2::Compression::default());
+                f(&mut encoder)?;
+                Ok(encoder.finish()?)
+            }
+        }
+    }
+}
@@ -0,0 +1,469 @@
+use std::{ffi::OsString, fs, path::Path};
+
+use anyhow::{bail, Context};
+
+/// A value in a config file
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+/// Keys and values of a table, in order
+type Table = Vec<(String, Value)>;
+
+/// Keys whose values are paths, other than `input` and `output`
+const PATH_KEYS: &[&str] = &[
+    "onto",
+    "append",
+    "encrypt",
+    "manifest",
+    "signing-manifest",
+    "report-json",
+    "emit-ldscript",
+    "emit-dt-reserved",
+    "emit-c-header",
+    "emit-rust",
+    "emit-gdb",
+    "emit-openocd",
+    "emit-renode",
+    "emit-entry",
+];
+
+/// One output to produce, as command line arguments
+#[derive(Debug)]
+pub struct Target {
+    pub name: String,
+    pub args: Vec<OsString>,
+}
+
+/// Read the targets in a config file
+///
+/// The config file is in a subset of TOML. Each `[targets.NAME]` table is a
+/// target, with keys being the long options, e.g. `gap-fill = 0xff`, and
+/// `input` and `output` being the input files and output file. Keys outside
+/// of any table apply to every target, unless the target sets them too.
+///
+/// Relative paths are relative to the directory the config file is in.
+pub fn targets(path: &OsString) -> anyhow::Result<Vec<Target>> {
+    let text = fs::read_to_string(path).with_context(|| format!("Cannot read {path:?}"))?;
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    let (common, tables) = parse(&text).with_context(|| format!("Invalid config file {path:?}"))?;
+
+    let mut res = Vec::new();
+    for (header, table) in tables {
+        let Some(name) = header.strip_prefix("targets.") else {
+            bail!("Unknown table [{header}] in {path:?} (Targets are [targets.NAME])")
+        };
+
+        let mut keys = common.clone();
+        for (key, value) in table {
+            keys.retain(|(k, _)| *k != key);
+            keys.push((key, value));
+        }
+
+        let args =
+            to_args(&keys, dir).with_context(|| format!("Invalid target {name:?} in {path:?}"))?;
+        res.push(Target {
+            name: name.to_owned(),
+            args,
+        });
+    }
+
+    if res.is_empty() {
+        bail!("No targets in {path:?} (Add a [targets.NAME] table)")
+    }
+
+    Ok(res)
+}
+
+/// A path in a config file in `dir`, leaving `-` for standard input or output
+/// alone
+fn resolve(dir: &Path, path: String) -> OsString {
+    match path.as_str() {
+        "-" => path.into(),
+        _ => dir.join(path).into_os_string(),
+    }
+}
+
+/// Turn keys into command line arguments, with inputs and the output last,
+/// and relative paths made relative to `dir`
+fn to_args(keys: &Table, dir: &Path) -> anyhow::Result<Vec<OsString>> {
+    let mut args = Vec::new();
+    let mut positional = Vec::new();
+    let mut output = None;
+
+    for (key, value) in keys {
+        let values = match value {
+            Value::Array(values) => values.clone(),
+            value => vec![value.clone()],
+        };
+
+        for value in values {
+            let value = match value {
+                Value::String(s) => s,
+                Value::Integer(n) => n.to_string(),
+                Value::Boolean(true) => {
+                    args.push(format!("--{key}").into());
+                    continue;
+                }
+                Value::Boolean(false) => continue,
+                Value::Array(_) => bail!("Nested arrays are not allowed for {key:?}"),
+            };
+
+            match key.as_str() {
+                "input" => positional.push(resolve(dir, value)),
+                "output" if output.is_some() => bail!("More than one output"),
+                "output" => output = Some(resolve(dir, value)),
+                "config" => bail!("Config files cannot include other config files"),
+                "patches" => bail!(
+                    "Patches are not supported in config files (Run elfcopyflat patch on the \
+                    output afterwards, or give the image to patch as onto)"
+                ),
+                key if PATH_KEYS.contains(&key) => {
+                    args.push(format!("--{key}").into());
+                    args.push(resolve(dir, value));
+                }
+                _ => {
+                    args.push(format!("--{key}").into());
+                    args.push(value.into());
+                }
+            }
+        }
+    }
+
+    let Some(output) = output else {
+        bail!("Missing output")
+    };
+    if positional.is_empty() {
+        bail!("Missing input")
+    }
+    args.push("--".into());
+    args.extend(positional);
+    args.push(output);
+    Ok(args)
+}
+
+/// Parse keys outside of tables, and tables by header
+fn parse(text: &str) -> anyhow::Result<(Table, Vec<(String, Table)>)> {
+    let mut common = Table::new();
+    let mut tables: Vec<(String, Table)> = Vec::new();
+
+    let mut lines = text.lines().enumerate();
+    while let Some((i, line)) = lines.next() {
+        let line_no = i + 1;
+        let mut line = strip_comment(line).trim().to_owned();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[') {
+            let Some(header) = header.strip_suffix(']') else {
+                bail!("Line {line_no}: Missing ']'")
+            };
+            let header: Vec<String> = (header.split('.'))
+                .map(|part| unquote_key(part.trim()))
+                .collect::<anyhow::Result<_>>()
+                .with_context(|| format!("Line {line_no}"))?;
+            let header = header.join(".");
+            if tables.iter().any(|(h, _)| *h == header) {
+                bail!("Line {line_no}: Table [{header}] given twice")
+            }
+            tables.push((header, Table::new()));
+            continue;
+        }
+
+        // Arrays can span lines
+        while line.contains('=') && bracket_depth(&line) > 0 {
+            let Some((_, next)) = lines.next() else {
+                bail!("Line {line_no}: Missing ']'")
+            };
+            line.push(' ');
+            line.push_str(strip_comment(next).trim());
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            bail!("Line {line_no}: Expected 'key = value'")
+        };
+        let key = unquote_key(key.trim()).with_context(|| format!("Line {line_no}"))?;
+        let (value, rest) = parse_value(value.trim()).with_context(|| format!("Line {line_no}"))?;
+        if !rest.trim().is_empty() {
+            bail!("Line {line_no}: Unexpected {rest:?} after value")
+        }
+
+        let table = match tables.last_mut() {
+            Some((_, table)) => table,
+            None => &mut common,
+        };
+        if table.iter().any(|(k, _)| *k == key) {
+            bail!("Line {line_no}: Key {key:?} given twice")
+        }
+        table.push((key, value));
+    }
+
+    Ok((common, tables))
+}
+
+/// The line without a comment, leaving `#` in strings alone
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+/// How many brackets are open at the end of the line, outside of strings
+fn bracket_depth(line: &str) -> i32 {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '[') => depth += 1,
+            (None, ']') => depth -= 1,
+            _ => {}
+        }
+        escaped = false;
+    }
+    depth
+}
+
+fn unquote_key(key: &str) -> anyhow::Result<String> {
+    if key.starts_with(['"', '\'']) {
+        match parse_value(key)? {
+            (Value::String(s), "") => return Ok(s),
+            _ => bail!("Invalid key {key:?}"),
+        }
+    }
+
+    let bare = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if key.is_empty() || !key.chars().all(bare) {
+        bail!("Invalid key {key:?}")
+    }
+    Ok(key.to_owned())
+}
+
+/// Parse a value at the start of `s`, returning it and the rest of `s`
+fn parse_value(s: &str) -> anyhow::Result<(Value, &str)> {
+    if let Some(rest) = s.strip_prefix('"') {
+        let mut res = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::String(res), &rest[i + 1..])),
+                '\\' => {
+                    let escaped = match chars.next() {
+                        Some((_, 'n')) => '\n',
+                        Some((_, 't')) => '\t',
+                        Some((_, 'r')) => '\r',
+                        Some((_, '"')) => '"',
+                        Some((_, '\\')) => '\\',
+                        other => bail!("Unsupported escape {:?}", other.map(|(_, c)| c)),
+                    };
+                    res.push(escaped);
+                }
+                c => res.push(c),
+            }
+        }
+        bail!("Missing '\"'")
+    }
+
+    if let Some(rest) = s.strip_prefix('\'') {
+        let Some(end) = rest.find('\'') else {
+            bail!("Missing \"'\"")
+        };
+        return Ok((Value::String(rest[..end].to_owned()), &rest[end + 1..]));
+    }
+
+    if let Some(mut rest) = s.strip_prefix('[') {
+        let mut values = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(values), after));
+            }
+            let (value, after) = parse_value(rest)?;
+            values.push(value);
+            rest = after.trim_start();
+            match rest.strip_prefix(',') {
+                Some(after) => rest = after,
+                None if rest.starts_with(']') => {}
+                None => bail!("Expected ',' or ']' in array"),
+            }
+        }
+    }
+
+    let end = s
+        .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+        .unwrap_or(s.len());
+    let (word, rest) = s.split_at(end);
+    let value = match word {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        _ => Value::Integer(parse_integer(word)?),
+    };
+    Ok((value, rest))
+}
+
+fn parse_integer(s: &str) -> anyhow::Result<i64> {
+    let digits = s.replace('_', "");
+    let (negative, digits) = match digits.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, digits.strip_prefix('+').unwrap_or(&digits)),
+    };
+    let (radix, digits) = match digits.get(..2) {
+        Some("0x") => (16, &digits[2..]),
+        Some("0o") => (8, &digits[2..]),
+        Some("0b") => (2, &digits[2..]),
+        _ => (10, digits),
+    };
+    let n = i64::from_str_radix(digits, radix).with_context(|| format!("Invalid value {s:?}"))?;
+    Ok(if negative { -n } else { n })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_owned())
+    }
+
+    #[test]
+    fn tables() {
+        let text = r#"
+# Common to every target
+input = ["a.elf", 'b.elf'] # Two inputs
+base = 0x800_0000
+
+[targets.flash]
+gap-fill = 0xff
+"quoted key" = "x # not a comment"
+list = [
+    1,
+    -2, # Trailing comma
+]
+strip-all = true
+
+[ targets . 'ram' ]
+output = "ram.bin"
+"#;
+        let (common, tables) = parse(text).unwrap();
+        assert_eq!(
+            common,
+            [
+                (
+                    "input".to_owned(),
+                    Value::Array(vec![string("a.elf"), string("b.elf")])
+                ),
+                ("base".to_owned(), Value::Integer(0x800_0000)),
+            ],
+        );
+        assert_eq!(tables.len(), 2);
+        assert_eq!(tables[0].0, "targets.flash");
+        assert_eq!(
+            tables[0].1,
+            [
+                ("gap-fill".to_owned(), Value::Integer(0xff)),
+                ("quoted key".to_owned(), string("x # not a comment")),
+                (
+                    "list".to_owned(),
+                    Value::Array(vec![Value::Integer(1), Value::Integer(-2)])
+                ),
+                ("strip-all".to_owned(), Value::Boolean(true)),
+            ],
+        );
+        assert_eq!(tables[1].0, "targets.ram");
+    }
+
+    #[test]
+    fn strings() {
+        let (value, rest) = parse_value(r#""a\tb\"c\\" rest"#).unwrap();
+        assert_eq!(value, string("a\tb\"c\\"));
+        assert_eq!(rest, " rest");
+        let (value, _) = parse_value(r"'C:\path\'").unwrap();
+        assert_eq!(value, string(r"C:\path\"));
+        assert!(parse_value(r#""\q""#).is_err());
+        assert!(parse_value(r#""open"#).is_err());
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(parse_integer("0x10").unwrap(), 16);
+        assert_eq!(parse_integer("0o17").unwrap(), 15);
+        assert_eq!(parse_integer("-0b101").unwrap(), -5);
+        assert_eq!(parse_integer("+1_000").unwrap(), 1000);
+        assert!(parse_integer("0xg").is_err());
+        assert!(parse_integer("yes").is_err());
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse("a = 1\na = 2").is_err());
+        assert!(parse("[t]\n[t]").is_err());
+        assert!(parse("[t").is_err());
+        assert!(parse("a = [1, 2").is_err());
+        assert!(parse("a = 1 2").is_err());
+        assert!(parse("a").is_err());
+        assert!(parse("a b = 1").is_err());
+    }
+
+    #[test]
+    fn args() {
+        let keys: Table = vec![
+            ("input".to_owned(), Value::Array(vec![string("a.elf")])),
+            ("gap-fill".to_owned(), Value::Integer(255)),
+            ("strip-all".to_owned(), Value::Boolean(true)),
+            ("dense".to_owned(), Value::Boolean(false)),
+            ("report-json".to_owned(), string("-")),
+            ("emit-c-header".to_owned(), string("out.h")),
+            ("output".to_owned(), string("/abs/out.bin")),
+        ];
+        let args = to_args(&keys, Path::new("cfg")).unwrap();
+        let expected = [
+            "--gap-fill",
+            "255",
+            "--strip-all",
+            "--report-json",
+            "-",
+            "--emit-c-header",
+            "cfg/out.h",
+            "--",
+            "cfg/a.elf",
+            "/abs/out.bin",
+        ];
+        assert_eq!(args, expected.map(OsString::from));
+    }
+
+    #[test]
+    fn args_errors() {
+        let output = ("output".to_owned(), string("out.bin"));
+        let input = ("input".to_owned(), string("in.elf"));
+        let dir = Path::new("");
+        assert!(to_args(&vec![input.clone()], dir).is_err());
+        assert!(to_args(&vec![output.clone()], dir).is_err());
+        assert!(to_args(&vec![input.clone(), output.clone(), output.clone()], dir).is_err());
+        let patches = ("patches".to_owned(), Value::Array(vec![string("p.elf")]));
+        assert!(to_args(&vec![input, output, patches], dir).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+//! Constants describing the image, for loader code to include so that it
+//! stays in sync with the image
+
+use std::{ffi::OsString, fmt::Write, fs};
+
+/// What loader code needs to know about the image
+pub struct Constants {
+    /// Prefix of each name in C, as in `IMAGE_BASE`
+    pub prefix: String,
+    pub base: u64,
+    /// Size of the header reserved at the start of the image, before `base`
+    pub header: u64,
+    pub size: u64,
+    pub entry: Option<u64>,
+    pub segments: Vec<Segment>,
+}
+
+pub struct Segment {
+    /// Name used in the names of its constants, as in `SEGMENT0`
+    pub name: String,
+    pub address: u64,
+    /// Offset in the image
+    pub offset: u64,
+    /// Size of the file contents, which are in the image
+    pub size: u64,
+    pub memory_size: u64,
+}
+
+impl Constants {
+    /// Names and values of the constants, without the prefix
+    fn values(&self) -> Vec<(String, u64)> {
+        let mut res = vec![
+            ("BASE".to_owned(), self.base),
+            ("SIZE".to_owned(), self.size),
+        ];
+        if let Some(entry) = self.entry {
+            res.push(("ENTRY".to_owned(), entry));
+            if let Some(offset) = entry.checked_sub(self.base) {
+                res.push(("ENTRY_OFFSET".to_owned(), offset + self.header));
+            }
+        }
+        res.push(("SEGMENT_COUNT".to_owned(), self.segments.len() as u64));
+        for s in &self.segments {
+            res.push((format!("{}_ADDRESS", s.name), s.address));
+            res.push((format!("{}_OFFSET", s.name), s.offset));
+            res.push((format!("{}_SIZE", s.name), s.size));
+            res.push((format!("{}_MEMORY_SIZE", s.name), s.memory_size));
+        }
+        res
+    }
+
+    /// Write a C header defining each constant as a macro
+    pub fn write_c(&self, path: &OsString) -> anyhow::Result<()> {
+        let guard = format!("{}_LAYOUT_H", self.prefix);
+        let mut header = String::new();
+        writeln!(header, "/* Written by elfcopyflat */")?;
+        writeln!(header)?;
+        writeln!(header, "#ifndef {guard}")?;
+        writeln!(header, "#define {guard}")?;
+        writeln!(header)?;
+        for (name, value) in self.values() {
+            match name.as_str() {
+                "SEGMENT_COUNT" => writeln!(header, "#define {}_{name} {value}", self.prefix)?,
+                _ => writeln!(header, "#define {}_{name} {value:#x}ULL", self.prefix)?,
+            }
+        }
+        writeln!(header)?;
+        writeln!(header, "#endif /* {guard} */")?;
+
+        fs::write(path, header)?;
+        Ok(())
+    }
+
+    /// Write a Rust module defining each constant, without the prefix, and
+    /// with `table`, an array of `#[repr(C)]` segment descriptors
+    pub fn write_rust(&self, path: &OsString, table: bool) -> anyhow::Result<()> {
+        let mut module = String::new();
+        writeln!(module, "//! Written by elfcopyflat")?;
+        writeln!(module)?;
+        for (name, value) in self.values() {
+            match name.as_str() {
+                "SEGMENT_COUNT" => writeln!(module, "pub const {name}: usize = {value};")?,
+                _ => writeln!(module, "pub const {name}: u64 = {value:#x};")?,
+            }
+        }
+
+        if table {
+            writeln!(module)?;
+            writeln!(module, "#[repr(C)]")?;
+            writeln!(module, "#[derive(Debug, Clone, Copy)]")?;
+            writeln!(module, "pub struct Segment {{")?;
+            for field in ["address", "offset", "size", "memory_size"] {
+                writeln!(module, "    pub {field}: u64,")?;
+            }
+            writeln!(module, "}}")?;
+            writeln!(module)?;
+            writeln!(module, "pub const SEGMENTS: [Segment; SEGMENT_COUNT] = [")?;
+            for s in &self.segments {
+                writeln!(module, "    Segment {{")?;
+                writeln!(module, "        address: {:#x},", s.address)?;
+                writeln!(module, "        offset: {:#x},", s.offset)?;
+                writeln!(module, "        size: {:#x},", s.size)?;
+                writeln!(module, "        memory_size: {:#x},", s.memory_size)?;
+                writeln!(module, "    }},")?;
+            }
+            writeln!(module, "];")?;
+        }
+
+        fs::write(path, module)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+use std::{io::Write, mem::size_of};
+
+use zerocopy::{AsBytes, FromBytes, FromZeroes, LittleEndian, U16, U32, U64};
+
+use crate::{compress::Compression, layout::Layout, log};
+
+fn compression_id(compression: Compression) -> u16 {
+    match compression {
+        Compression::Zstd => 1,
+        Compression::Lz4 => 2,
+        Compression::Gzip => 3,
+    }
+}
+
+/// Container header, followed by `count` entries, followed by segment data
+///
+/// All fields are little endian.
+#[derive(Debug, Clone, FromZeroes, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct Header {
+    magic: [u8; 4],
+    version: U16<LittleEndian>,
+    compression: U16<LittleEndian>,
+    count: U32<LittleEndian>,
+}
+
+impl Header {
+    pub const MAGIC: [u8; 4] = *b"ECFZ";
+    pub const VERSION: u16 = 1;
+}
+
+/// One compressed segment
+#[derive(Debug, Clone, FromZeroes, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct Entry {
+    /// Address to decompress to
+    address: U64<LittleEndian>,
+    /// Offset of compressed data from start of container
+    offset: U64<LittleEndian>,
+    compressed_size: U64<LittleEndian>,
+    uncompressed_size: U64<LittleEndian>,
+}
+
+/// Write the file contents of each segment, compressed independently, with an
+/// index at the start
+pub fn write(
+    layout: &mut Layout,
+    mut output: impl Write,
+    compression: Compression,
+) -> anyhow::Result<()> {
+    let mut data = Vec::new();
+    let mut entries = Vec::new();
+    let segments = layout.segments.clone();
+    let mut offset = (size_of::<Header>() + size_of::<Entry>() * segments.len()) as u64;
+
+    for s in &segments {
+        let p = &s.phdr;
+        let buf = layout.read_segment(s)?;
+        let compressed = compression.compress(&buf)?;
+
+        log::info!(
+            "Compressed segment at {addr:#x} from {size:#x} to {compressed_size:#x} bytes",
+            addr = p.address(),
+            size = buf.len(),
+            compressed_size = compressed.len(),
+        );
+
+        entries.push(Entry {
+            address: p.address().into(),
+            offset: offset.into(),
+            compressed_size: (compressed.len() as u64).into(),
+            uncompressed_size: (buf.len() as u64).into(),
+        });
+        offset += compressed.len() as u64;
+        data.push(compressed);
+    }
+
+    let header = Header {
+        magic: Header::MAGIC,
+        version: Header::VERSION.into(),
+        compression: compression_id(compression).into(),
+        count: u32::try_from(entries.len())?.into(),
+    };
+
+    output.write_all(header.as_bytes())?;
+    for e in &entries {
+        output.write_all(e.as_bytes())?;
+    }
+    for d in &data {
+        output.write_all(d)?;
+    }
+
+    Ok(())
+}
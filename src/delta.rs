@@ -0,0 +1,172 @@
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::{BufWriter, Read, Seek, Write},
+    mem::size_of,
+};
+
+use clap::Args;
+use clap_num::maybe_hex;
+use zerocopy::{AsBytes, FromBytes, FromZeroes, LittleEndian, U16, U32, U64};
+
+use crate::{
+    elf::Mag,
+    hash::crc32,
+    input,
+    layout::{self, LayoutArgs},
+    log,
+};
+
+#[derive(Debug, Args)]
+pub struct DeltaArgs {
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// Size of blocks to compare and copy
+    #[arg(long, value_name = "SIZE", default_value = "4096", value_parser = maybe_hex::<u32>)]
+    block_size: u32,
+
+    /// Old ELF file or flat binary (`-` for standard input)
+    old: OsString,
+
+    /// New ELF file (`-` for standard input)
+    new: OsString,
+
+    /// Output patch file
+    output: OsString,
+}
+
+/// Patch header, followed by `count` entries, followed by literal data
+///
+/// All fields are little endian.
+#[derive(Debug, Clone, FromZeroes, FromBytes, AsBytes)]
+#[repr(C)]
+struct Header {
+    magic: [u8; 4],
+    version: U16<LittleEndian>,
+    reserved: U16<LittleEndian>,
+    block_size: U32<LittleEndian>,
+    count: U32<LittleEndian>,
+    /// Address of the start of the new image
+    base: U64<LittleEndian>,
+    old_size: U64<LittleEndian>,
+    new_size: U64<LittleEndian>,
+    old_crc32: U32<LittleEndian>,
+    new_crc32: U32<LittleEndian>,
+}
+
+impl Header {
+    const MAGIC: [u8; 4] = *b"ECFD";
+    const VERSION: u16 = 1;
+}
+
+/// How to produce one block of the new image
+#[derive(Debug, Clone, FromZeroes, FromBytes, AsBytes)]
+#[repr(C)]
+struct Entry {
+    /// Index of block in the new image
+    block: U32<LittleEndian>,
+    kind: U32<LittleEndian>,
+    /// For `COPY`, index of block in the old image. For `DATA`, offset of
+    /// literal data from start of patch
+    arg: U64<LittleEndian>,
+}
+
+impl Entry {
+    const COPY: u32 = 1;
+    const DATA: u32 = 2;
+}
+
+/// Read a flat binary, or flatten an ELF file
+fn read_image(path: &OsStr, args: &LayoutArgs) -> anyhow::Result<(Vec<u8>, u64)> {
+    let mut file = input::open(path)?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    if data.starts_with(&Mag::ELFMAG.0) {
+        file.rewind()?;
+        let mut layout = layout::layout_files(&[path.to_owned()], vec![file], args)?;
+        Ok((layout.image.read_to_vec()?, layout.base))
+    } else {
+        Ok((data, args.base.unwrap_or(0)))
+    }
+}
+
+pub fn run(args: DeltaArgs) -> anyhow::Result<()> {
+    if args.block_size == 0 {
+        anyhow::bail!("Block size must be non-zero")
+    }
+
+    let (old, _) = read_image(&args.old, &args.layout)?;
+    let (new, base) = read_image(&args.new, &args.layout)?;
+    let block_size = args.block_size as usize;
+
+    let old_blocks: Vec<&[u8]> = old.chunks(block_size).collect();
+    let mut old_index: HashMap<&[u8], usize> = HashMap::new();
+    for (i, b) in old_blocks.iter().enumerate() {
+        old_index.entry(b).or_insert(i);
+    }
+
+    let mut entries = Vec::new();
+    let mut literals: Vec<&[u8]> = Vec::new();
+
+    for (i, b) in new.chunks(block_size).enumerate() {
+        if old_blocks.get(i) == Some(&b) {
+            continue;
+        }
+
+        let (kind, arg) = match old_index.get(b) {
+            Some(&j) => (Entry::COPY, j as u64),
+            None => {
+                literals.push(b);
+                (Entry::DATA, 0)
+            }
+        };
+
+        entries.push(Entry {
+            block: u32::try_from(i)?.into(),
+            kind: kind.into(),
+            arg: arg.into(),
+        });
+    }
+
+    let mut offset = (size_of::<Header>() + size_of::<Entry>() * entries.len()) as u64;
+    let mut literal_sizes = literals.iter().map(|l| l.len() as u64);
+    for e in entries.iter_mut().filter(|e| e.kind.get() == Entry::DATA) {
+        e.arg = offset.into();
+        offset += literal_sizes.next().unwrap();
+    }
+
+    log::info!(
+        "{changed} of {total} blocks changed, {copies} copied from elsewhere, {literals} literal",
+        changed = entries.len(),
+        total = new.len().div_ceil(block_size),
+        copies = entries.len() - literals.len(),
+        literals = literals.len(),
+    );
+
+    let header = Header {
+        magic: Header::MAGIC,
+        version: Header::VERSION.into(),
+        reserved: 0.into(),
+        block_size: args.block_size.into(),
+        count: u32::try_from(entries.len())?.into(),
+        base: base.into(),
+        old_size: (old.len() as u64).into(),
+        new_size: (new.len() as u64).into(),
+        old_crc32: crc32(&old).into(),
+        new_crc32: crc32(&new).into(),
+    };
+
+    let mut output_file = BufWriter::new(File::create(&args.output)?);
+    output_file.write_all(header.as_bytes())?;
+    for e in &entries {
+        output_file.write_all(e.as_bytes())?;
+    }
+    for l in &literals {
+        output_file.write_all(l)?;
+    }
+    output_file.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,70 @@
+//! Device tree overlays reserving the memory the image is loaded into, so
+//! that an operating system booted alongside it leaves it alone
+
+use std::{ffi::OsString, fmt::Write, fs};
+
+use anyhow::bail;
+
+/// A range of memory taken up by the image
+pub struct Range {
+    /// Name of the node, which should be a valid node name
+    pub name: String,
+    pub address: u64,
+    pub size: u64,
+}
+
+/// A number as `cells` 32-bit cells
+fn cells(value: u64, cells: u32) -> anyhow::Result<String> {
+    match cells {
+        1 if value > u32::MAX.into() => {
+            bail!("{value:#x} does not fit in one cell (Use --dt-cells 2)")
+        }
+        1 => Ok(format!("{value:#x}")),
+        _ => Ok(format!("{:#x} {:#x}", value >> 32, value as u32)),
+    }
+}
+
+/// Write an overlay adding a node under `/reserved-memory` for each range
+///
+/// The addresses and sizes are written with `address_cells` cells, which
+/// should match `#address-cells` and `#size-cells` of `/reserved-memory` in
+/// the base tree.
+pub fn write(
+    path: &OsString,
+    ranges: &[Range],
+    address_cells: u32,
+    no_map: bool,
+) -> anyhow::Result<()> {
+    let mut dts = String::new();
+    writeln!(dts, "// Written by elfcopyflat")?;
+    writeln!(dts)?;
+    writeln!(dts, "/dts-v1/;")?;
+    writeln!(dts, "/plugin/;")?;
+    writeln!(dts)?;
+    writeln!(dts, "/ {{")?;
+    writeln!(dts, "\tfragment@0 {{")?;
+    writeln!(dts, "\t\ttarget-path = \"/reserved-memory\";")?;
+    writeln!(dts, "\t\t__overlay__ {{")?;
+    for (i, range) in ranges.iter().filter(|r| r.size > 0).enumerate() {
+        if i > 0 {
+            writeln!(dts)?;
+        }
+        writeln!(dts, "\t\t\t{}@{:x} {{", range.name, range.address)?;
+        writeln!(
+            dts,
+            "\t\t\t\treg = <{} {}>;",
+            cells(range.address, address_cells)?,
+            cells(range.size, address_cells)?,
+        )?;
+        if no_map {
+            writeln!(dts, "\t\t\t\tno-map;")?;
+        }
+        writeln!(dts, "\t\t\t}};")?;
+    }
+    writeln!(dts, "\t\t}};")?;
+    writeln!(dts, "\t}};")?;
+    writeln!(dts, "}};")?;
+
+    fs::write(path, dts)?;
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use std::{cell::RefCell, fmt};
+
+use anyhow::bail;
+use clap::Args;
+
+use crate::log;
+
+/// Options for which warnings are shown, and which are errors
+#[derive(Debug, Clone, Args)]
+pub struct DiagArgs {
+    /// Fail on warnings, such as those from --check-align or --lenient
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Do not show the warning with this code, e.g. "W005" (Can be given
+    /// multiple times)
+    #[arg(long, value_name = "CODE", value_parser = Warning::parse)]
+    pub allow: Vec<Warning>,
+
+    /// Fail on the warning with this code, e.g. "W005" (Can be given multiple
+    /// times)
+    #[arg(long, value_name = "CODE", value_parser = Warning::parse)]
+    pub deny: Vec<Warning>,
+}
+
+/// Kinds of warnings, each with a stable code
+///
+/// Codes are never reused, so that they can be relied on in scripts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Warning {
+    /// A segment goes past the end of a truncated input file (`--lenient`)
+    TruncatedFile,
+    /// A segment has more file contents than memory (`--lenient`)
+    FileSizeOverMemorySize,
+    /// A segment has file offset and address not congruent modulo its
+    /// alignment (`--check-align`)
+    OffsetMisaligned,
+    /// The base address leaves a segment misaligned (`--check-align`)
+    BaseMisaligned,
+    /// A segment is both writable and executable
+    WritableExecutable,
+    /// `PT_GNU_STACK` asks for an executable stack
+    ExecutableStack,
+    /// A segment with no file contents or no memory is left out
+    /// (`--empty-segments warn`)
+    EmptySegment,
+    /// The base address is far below the first segment
+    LeadingPad,
+    /// Two segments are loaded from the same bytes of the file, and only one
+    /// keeps them (`--shared-file-ranges dedupe`)
+    SharedFileRange,
+}
+
+impl Warning {
+    pub const ALL: &[Warning] = &[
+        Warning::TruncatedFile,
+        Warning::FileSizeOverMemorySize,
+        Warning::OffsetMisaligned,
+        Warning::BaseMisaligned,
+        Warning::WritableExecutable,
+        Warning::ExecutableStack,
+        Warning::EmptySegment,
+        Warning::LeadingPad,
+        Warning::SharedFileRange,
+    ];
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        (Self::ALL.iter())
+            .find(|w| w.code().eq_ignore_ascii_case(s))
+            .copied()
+            .ok_or_else(|| format!("Unknown warning code {s:?}"))
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Warning::TruncatedFile => "W001",
+            Warning::FileSizeOverMemorySize => "W002",
+            Warning::OffsetMisaligned => "W003",
+            Warning::BaseMisaligned => "W004",
+            Warning::WritableExecutable => "W005",
+            Warning::ExecutableStack => "W006",
+            Warning::EmptySegment => "W007",
+            Warning::LeadingPad => "W008",
+            Warning::SharedFileRange => "W009",
+        }
+    }
+}
+
+thread_local! {
+    static EMITTED: RefCell<Vec<(Warning, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Warnings printed since the last call, with their messages
+pub fn take_emitted() -> Vec<(Warning, String)> {
+    EMITTED.with(|e| e.take())
+}
+
+/// Print a warning, or fail with it if it is denied
+pub fn warn(args: &DiagArgs, warning: Warning, message: impl fmt::Display) -> anyhow::Result<()> {
+    let code = warning.code();
+    if args.deny.contains(&warning) {
+        bail!("{message} [{code}] (Denied with --deny {code})")
+    }
+    if args.allow.contains(&warning) {
+        return Ok(());
+    }
+    if args.strict {
+        bail!("{message} [{code}] (Warnings are errors with --strict)")
+    }
+    log::warning!("Warning [{code}]: {message}");
+    EMITTED.with(|e| e.borrow_mut().push((warning, message.to_string())));
+    Ok(())
+}
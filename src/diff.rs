@@ -0,0 +1,108 @@
+use std::{ffi::OsString, fs::File, ops::Range};
+
+use clap::Args;
+use clap_num::maybe_hex;
+
+use crate::{
+    layout::{self, LayoutArgs},
+    verify::{self, Gaps},
+};
+
+#[derive(Debug, Args)]
+pub struct DiffArgs {
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// Fill gaps between segments with this byte instead of zeros, as with
+    /// copying
+    #[arg(long, value_name = "BYTE", value_parser = maybe_hex::<u8>)]
+    gap_fill: Option<u8>,
+
+    /// Existing flat binary to compare against
+    old: OsString,
+
+    /// Input ELF, PE or Mach-O files to make the new image from
+    #[arg(required = true)]
+    input: Vec<OsString>,
+}
+
+pub fn run(args: DiffArgs) -> anyhow::Result<()> {
+    let mut layout = layout::layout(&args.input, &args.layout)?;
+    let old = File::open(&args.old)?;
+    let old_size = old.metadata()?.len();
+    let new_size = layout.image.size();
+    let base = layout.base;
+
+    let gaps = Gaps::Fill(args.gap_fill.unwrap_or(0));
+    let mismatches = verify::compare(&mut layout.image, old, gaps)?;
+
+    // What each part of the image is, by offset
+    let segments: Vec<(Range<u64>, String)> = (layout.segments.iter())
+        .map(|s| {
+            let start = s.phdr.address() - base;
+            let name = match args.input.len() {
+                1 => format!("segment #{}", s.index),
+                _ => format!("segment #{} in {:?}", s.index, args.input[s.input]),
+            };
+            (start..start + s.phdr.file_size(), name)
+        })
+        .collect();
+
+    let describe = |at: u64| -> String {
+        if at >= new_size {
+            return "past the end of the new image".to_owned();
+        }
+        if at >= old_size {
+            return "past the end of the old image".to_owned();
+        }
+        match segments.iter().rev().find(|(r, _)| r.contains(&at)) {
+            Some((_, name)) => name.clone(),
+            None => "gap between segments".to_owned(),
+        }
+    };
+
+    let mut pieces: Vec<(Range<u64>, String)> = Vec::new();
+    for m in &mismatches {
+        // Split where the description may change
+        let mut cuts = vec![m.start, m.end, new_size, old_size];
+        for (r, _) in &segments {
+            cuts.extend([r.start, r.end]);
+        }
+        cuts.retain(|c| m.contains(c) || *c == m.end);
+        cuts.sort();
+        cuts.dedup();
+
+        for cut in cuts.windows(2) {
+            let what = describe(cut[0]);
+            match pieces.last_mut() {
+                Some((last, last_what)) if last.end == cut[0] && *last_what == what => {
+                    last.end = cut[1]
+                }
+                _ => pieces.push((cut[0]..cut[1], what)),
+            }
+        }
+    }
+
+    for (r, what) in &pieces {
+        println!(
+            "{start:#x}..{end:#x} (address {addr_start:#x}..{addr_end:#x}), {size:#x} bytes: {what}",
+            start = r.start,
+            end = r.end,
+            addr_start = base.wrapping_add(r.start),
+            addr_end = base.wrapping_add(r.end),
+            size = r.end - r.start,
+        );
+    }
+
+    let total: u64 = mismatches.iter().map(|m| m.end - m.start).sum();
+    if mismatches.is_empty() {
+        println!("No differences");
+    } else {
+        println!(
+            "{total:#x} bytes differ in {count} ranges (Old image {old_size:#x} bytes, new image {new_size:#x} bytes)",
+            count = mismatches.len(),
+        );
+    }
+
+    Ok(())
+}
@@ -1,8 +1,9 @@
 #![allow(unused)]
 
-use anyhow::bail;
+use anyhow::{bail, Context};
 use std::{
-    io::{self, Read, Seek},
+    fmt,
+    io::{self, BufReader, Read, Seek},
     mem::size_of,
 };
 use zerocopy::*;
@@ -83,6 +84,9 @@ impl Ident {
     pub const EI_NIDENT: usize = 16;
 
     pub fn validate(&self) -> anyhow::Result<()> {
+        if !Mag(self.magic).valid() {
+            bail!("Not an ELF file")
+        }
         if !self.class.valid() {
             bail!("Invalid class")
         }
@@ -158,14 +162,12 @@ impl<O: ByteOrder, UN: FromBytes + Into<u64>> EhdrN<O, UN> {
             _ => panic!("Invalid ELF header slipped through"),
         };
 
-        if usize::from(self.e_phentsize.get()) != phentsize {
+        // Relocatable objects have no program headers, and e_phentsize may be
+        // zero
+        if self.e_phnum.get() != 0 && usize::from(self.e_phentsize.get()) != phentsize {
             bail!("Invalid e_phentsize, file is probably not an executable")
         }
 
-        if self.e_phnum.get() == u16::MAX {
-            bail!("Too many segments, unimplemented PN_XNUM")
-        }
-
         Ok(())
     }
 
@@ -188,29 +190,61 @@ pub type Ehdr32<O> = EhdrN<O, U32<O>>;
 pub type Ehdr64<O> = EhdrN<O, U64<O>>;
 
 #[derive(Debug, Clone)]
-pub struct Ehdr(pub EhdrN<NativeEndian, U64<NativeEndian>>);
+pub struct Ehdr(
+    pub EhdrN<NativeEndian, U64<NativeEndian>>,
+    /// Section header 0, if needed for counts that don't fit in the ELF header
+    pub Option<Shdr>,
+);
 
 impl Ehdr {
+    /// `e_phnum` value meaning the real count is in `sh_info` of section 0
+    pub const PN_XNUM: u16 = 0xffff;
+    /// `e_shstrndx` value meaning the real index is in `sh_link` of section 0
+    pub const SHN_XINDEX: u16 = 0xffff;
+    /// Most program headers read, so that a bogus count with `PN_XNUM` cannot
+    /// use up memory
+    pub const MAX_PH_NUM: usize = 1 << 20;
+    /// Most section headers read, for the same reason with `e_shnum` zero
+    pub const MAX_SH_NUM: usize = 1 << 20;
+
     pub fn read(mut r: impl Read + Seek) -> anyhow::Result<Self> {
         let pos = r.stream_position()?;
         let ident = Ident::read(&mut r)?;
+        ident.validate()?;
         r.seek(io::SeekFrom::Start(pos))?;
         let res = match (ident.class, ident.data) {
-            (Class::ELFCLASS32, Data::ELFDATA2LSB) => {
-                <Ehdr32<LittleEndian>>::read(r)?.pipe_validate()?.wrap()
-            }
-            (Class::ELFCLASS64, Data::ELFDATA2LSB) => {
-                <Ehdr64<LittleEndian>>::read(r)?.pipe_validate()?.wrap()
-            }
+            (Class::ELFCLASS32, Data::ELFDATA2LSB) => <Ehdr32<LittleEndian>>::read(&mut r)?
+                .pipe_validate()?
+                .wrap(),
+            (Class::ELFCLASS64, Data::ELFDATA2LSB) => <Ehdr64<LittleEndian>>::read(&mut r)?
+                .pipe_validate()?
+                .wrap(),
             (Class::ELFCLASS32, Data::ELFDATA2MSB) => {
-                <Ehdr32<BigEndian>>::read(r)?.pipe_validate()?.wrap()
+                <Ehdr32<BigEndian>>::read(&mut r)?.pipe_validate()?.wrap()
             }
             (Class::ELFCLASS64, Data::ELFDATA2MSB) => {
-                <Ehdr64<BigEndian>>::read(r)?.pipe_validate()?.wrap()
+                <Ehdr64<BigEndian>>::read(&mut r)?.pipe_validate()?.wrap()
             }
             _ => panic!("Invalid ELF header slipped through"),
         };
-        Ok(Self(res))
+        let mut res = Self(res, None);
+
+        let extended = res.0.e_phnum.get() == Self::PN_XNUM
+            || (res.0.e_shnum.get() == 0 && res.sh_offset() != 0)
+            || res.0.e_shstrndx.get() == Self::SHN_XINDEX;
+
+        if extended {
+            if res.sh_offset() == 0 {
+                bail!("Extended program header or section numbering without section headers")
+            }
+            res.check_sh_entry_size()?;
+            let mut buf = vec![0; res.sh_entry_size()];
+            r.seek(io::SeekFrom::Start(pos + res.sh_offset()))?;
+            r.read_exact(&mut buf)?;
+            res.1 = Some(Shdr::from_bytes(&buf, &res));
+        }
+
+        Ok(res)
     }
 
     pub fn ph_offset(&self) -> u64 {
@@ -221,9 +255,390 @@ impl Ehdr {
         usize::from(self.0.e_phentsize.get())
     }
 
+    pub fn ph_num(&self) -> usize {
+        match &self.1 {
+            Some(shdr0) if self.0.e_phnum.get() == Self::PN_XNUM => shdr0.info() as usize,
+            _ => usize::from(self.0.e_phnum.get()),
+        }
+    }
+
     pub fn ph_size(&self) -> usize {
-        self.ph_entry_size() * usize::from(self.0.e_phnum.get())
+        self.ph_entry_size() * self.ph_num()
+    }
+
+    pub fn sh_offset(&self) -> u64 {
+        self.0.e_shoff.get()
+    }
+
+    pub fn sh_entry_size(&self) -> usize {
+        usize::from(self.0.e_shentsize.get())
+    }
+
+    pub fn sh_num(&self) -> usize {
+        match &self.1 {
+            Some(shdr0) if self.0.e_shnum.get() == 0 => shdr0.size() as usize,
+            _ => usize::from(self.0.e_shnum.get()),
+        }
+    }
+
+    pub fn sh_str_index(&self) -> usize {
+        match &self.1 {
+            Some(shdr0) if self.0.e_shstrndx.get() == Self::SHN_XINDEX => shdr0.link() as usize,
+            _ => usize::from(self.0.e_shstrndx.get()),
+        }
+    }
+
+    pub fn class(&self) -> Class {
+        self.0.e_ident.class
+    }
+
+    pub fn little_endian(&self) -> bool {
+        self.0.e_ident.data == Data::ELFDATA2LSB
+    }
+
+    pub fn file_type(&self) -> FileType {
+        FileType(self.0.e_type.get())
+    }
+
+    pub fn machine(&self) -> Machine {
+        Machine(self.0.e_machine.get())
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.0.e_entry.get()
+    }
+
+    pub fn read_phdrs(&self, mut r: impl Read + Seek) -> anyhow::Result<Vec<Phdr>> {
+        if self.ph_num() == 0 {
+            return Ok(Vec::new());
+        }
+
+        let offset = self.ph_offset();
+        let size = self.ph_size() as u64;
+        let file_size = r.seek(io::SeekFrom::End(0))?;
+        if offset >= file_size {
+            bail!(
+                "Program header table at offset {offset:#x} is past the end of the file, which is {file_size:#x} bytes"
+            )
+        }
+        if offset.checked_add(size).is_none_or(|end| end > file_size) {
+            bail!(
+                "Program header table at offsets {offset:#x}..{end:#x} ({count} entries of {entry_size:#x} bytes) goes past the end of the file, which is {file_size:#x} bytes (The file may be truncated)",
+                end = offset.saturating_add(size),
+                count = self.ph_num(),
+                entry_size = self.ph_entry_size(),
+            )
+        }
+
+        if self.ph_num() > Self::MAX_PH_NUM {
+            bail!(
+                "Too many program headers ({count}, at most {max} are supported)",
+                count = self.ph_num(),
+                max = Self::MAX_PH_NUM,
+            )
+        }
+
+        // Read one entry at a time instead of the whole table at once
+        r.seek(io::SeekFrom::Start(offset))?;
+        let mut r = BufReader::new(r.take(size));
+        let mut entry = vec![0; self.ph_entry_size()];
+        let mut phdrs = Vec::new();
+        for _ in 0..self.ph_num() {
+            r.read_exact(&mut entry)?;
+            phdrs.push(Phdr::from_bytes(&entry, self));
+        }
+
+        Ok(phdrs)
+    }
+
+    fn check_sh_entry_size(&self) -> anyhow::Result<()> {
+        let shentsize = match self.class() {
+            Class::ELFCLASS32 => size_of::<Shdr32<NativeEndian>>(),
+            Class::ELFCLASS64 => size_of::<Shdr64<NativeEndian>>(),
+            _ => panic!("Invalid ELF header slipped through"),
+        };
+
+        if self.sh_entry_size() != shentsize {
+            bail!("Invalid e_shentsize")
+        }
+
+        Ok(())
+    }
+
+    /// Read section headers, along with their names
+    ///
+    /// Returns an empty list if there are no section headers.
+    pub fn read_shdrs(&self, mut r: impl Read + Seek) -> anyhow::Result<Vec<Shdr>> {
+        if self.sh_offset() == 0 || self.sh_num() == 0 {
+            return Ok(Vec::new());
+        }
+
+        self.check_sh_entry_size()?;
+        if self.sh_num() > Self::MAX_SH_NUM {
+            bail!(
+                "Too many section headers: {} (At most {} are supported)",
+                self.sh_num(),
+                Self::MAX_SH_NUM
+            )
+        }
+
+        let size = (self.sh_entry_size().checked_mul(self.sh_num()))
+            .context("Section header table size overflows")?;
+        let shdr_bytes = read_range(
+            &mut r,
+            self.sh_offset(),
+            size as u64,
+            "Section header table",
+        )?;
+
+        let mut shdrs: Vec<Shdr> = shdr_bytes
+            .chunks_exact(self.sh_entry_size())
+            .map(|b| Shdr::from_bytes(b, self))
+            .collect();
+
+        if let Some(strtab) = shdrs.get(self.sh_str_index()) {
+            let names = read_section(&mut r, strtab)?;
+            for shdr in &mut shdrs {
+                shdr.1 = string_at(&names, shdr.0.sh_name.get());
+            }
+        }
+
+        Ok(shdrs)
+    }
+
+    /// Read symbols from all symbol tables
+    pub fn read_symbols(
+        &self,
+        mut r: impl Read + Seek,
+        shdrs: &[Shdr],
+    ) -> anyhow::Result<Vec<Sym>> {
+        let mut syms = Vec::new();
+
+        for (i, symtab) in shdrs.iter().enumerate() {
+            if symtab.to_type() == SectionType::SHT_SYMTAB {
+                syms.extend(self.read_symbol_table(&mut r, shdrs, i)?);
+            }
+        }
+
+        Ok(syms)
+    }
+
+    /// Read symbols from the symbol table in section `index`
+    pub fn read_symbol_table(
+        &self,
+        mut r: impl Read + Seek,
+        shdrs: &[Shdr],
+        index: usize,
+    ) -> anyhow::Result<Vec<Sym>> {
+        let symentsize = match self.class() {
+            Class::ELFCLASS32 => size_of::<Sym32<NativeEndian>>(),
+            Class::ELFCLASS64 => size_of::<Sym64<NativeEndian>>(),
+            _ => panic!("Invalid ELF header slipped through"),
+        };
+
+        let Some(symtab) = shdrs.get(index) else {
+            bail!("No symbol table at section index {index}")
+        };
+
+        let data = read_section(&mut r, symtab)?;
+        let names = match shdrs.get(symtab.link() as usize) {
+            Some(strtab) => read_section(&mut r, strtab)?,
+            None => Vec::new(),
+        };
+
+        Ok(data
+            .chunks_exact(symentsize)
+            .map(|b| {
+                let mut sym = Sym::from_bytes(b, self);
+                sym.1 = string_at(&names, sym.0.st_name.get());
+                sym
+            })
+            .collect())
+    }
+}
+
+/// Read the contents of a section from the file
+pub fn read_section(mut r: impl Read + Seek, shdr: &Shdr) -> anyhow::Result<Vec<u8>> {
+    if shdr.to_type() == SectionType::SHT_NOBITS {
+        return Ok(Vec::new());
+    }
+
+    read_range(r, shdr.file_offset(), shdr.size(), "Section")
+}
+
+/// Read `size` bytes at `offset`, checking first that they are all in the
+/// file, so that a bogus size cannot use up memory
+pub fn read_range(
+    mut r: impl Read + Seek,
+    offset: u64,
+    size: u64,
+    what: &str,
+) -> anyhow::Result<Vec<u8>> {
+    let file_size = r.seek(io::SeekFrom::End(0))?;
+    if offset.checked_add(size).is_none_or(|end| end > file_size) {
+        bail!(
+            "{what} at offsets {offset:#x}..{end:#x} goes past the end of the file, which is {file_size:#x} bytes (The file may be truncated)",
+            end = offset.saturating_add(size),
+        )
+    }
+
+    let mut data = vec![0; usize::try_from(size)?];
+    r.seek(io::SeekFrom::Start(offset))?;
+    r.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Note in a `PT_NOTE` segment or `SHT_NOTE` section
+#[derive(Debug, Clone)]
+pub struct Note {
+    pub name: Vec<u8>,
+    pub note_type: u32,
+    pub desc: Vec<u8>,
+}
+
+impl Note {
+    /// `n_type` of a GNU build ID note
+    pub const NT_GNU_BUILD_ID: u32 = 3;
+    /// `n_type` of a GNU property note, as in `.note.gnu.property`
+    pub const NT_GNU_PROPERTY_TYPE_0: u32 = 5;
+
+    /// Parse a list of notes, with names and descriptors padded to `align`
+    /// bytes
+    pub fn parse_all(data: &[u8], ehdr: &Ehdr, align: u64) -> Vec<Self> {
+        let align = match align {
+            8 => 8,
+            _ => 4,
+        };
+        let word = |b: &[u8]| match ehdr.little_endian() {
+            true => u32::from_le_bytes(b.try_into().unwrap()),
+            false => u32::from_be_bytes(b.try_into().unwrap()),
+        };
+
+        let mut res = Vec::new();
+        let mut rest = data;
+        while rest.len() >= 12 {
+            let name_size = word(&rest[0..4]) as usize;
+            let desc_size = word(&rest[4..8]) as usize;
+            let note_type = word(&rest[8..12]);
+
+            let name_start = 12;
+            let desc_start = (name_start + name_size).next_multiple_of(align);
+            let end = (desc_start + desc_size).next_multiple_of(align);
+            let (Some(name), Some(desc)) = (
+                rest.get(name_start..name_start + name_size),
+                rest.get(desc_start..desc_start + desc_size),
+            ) else {
+                break;
+            };
+
+            res.push(Note {
+                name: name.strip_suffix(&[0]).unwrap_or(name).to_vec(),
+                note_type,
+                desc: desc.to_vec(),
+            });
+            rest = rest.get(end..).unwrap_or_default();
+        }
+        res
+    }
+}
+
+/// Read the notes of an ELF file
+///
+/// Notes are found with program headers, or section headers if there are no
+/// program headers, as in relocatable objects.
+fn read_notes(mut r: impl Read + Seek, ehdr: &Ehdr) -> anyhow::Result<Vec<Note>> {
+    let mut regions = Vec::new();
+    if ehdr.ph_num() > 0 {
+        for p in ehdr.read_phdrs(&mut r)? {
+            if p.to_type() == Type::PT_NOTE {
+                regions.push((p.file_offset(), p.file_size(), p.0.p_align.get()));
+            }
+        }
+    } else {
+        for s in ehdr.read_shdrs(&mut r)? {
+            if s.to_type() == SectionType::SHT_NOTE {
+                regions.push((s.file_offset(), s.size(), s.alignment()));
+            }
+        }
     }
+
+    let mut res = Vec::new();
+    for (offset, size, align) in regions {
+        let data = read_range(&mut r, offset, size, "Notes")?;
+        res.extend(Note::parse_all(&data, ehdr, align));
+    }
+    Ok(res)
+}
+
+/// Read the GNU build ID of an ELF file, from its notes
+pub fn read_build_id(mut r: impl Read + Seek) -> anyhow::Result<Option<Vec<u8>>> {
+    r.rewind()?;
+    let ehdr = Ehdr::read(&mut r)?;
+    let build_id = (read_notes(r, &ehdr)?.into_iter())
+        .find(|n| n.name == b"GNU" && n.note_type == Note::NT_GNU_BUILD_ID);
+    Ok(build_id.map(|note| note.desc))
+}
+
+/// `pr_type` of the AArch64 feature bits, and their names
+const AARCH64_FEATURE_1_AND: (u32, &[(u32, &str)]) =
+    (0xc0000000, &[(1, "BTI"), (2, "PAC"), (4, "GCS")]);
+/// `pr_type` of the x86 feature bits, and their names
+const X86_FEATURE_1_AND: (u32, &[(u32, &str)]) = (0xc0000002, &[(1, "IBT"), (2, "SHSTK")]);
+
+/// Read the CPU security features an ELF file is marked as built with, from
+/// its GNU property notes, such as BTI and PAC on AArch64 or IBT and SHSTK
+/// (CET) on x86
+pub fn read_security_features(mut r: impl Read + Seek) -> anyhow::Result<Vec<&'static str>> {
+    r.rewind()?;
+    let ehdr = Ehdr::read(&mut r)?;
+    let (feature_type, names) = match ehdr.machine() {
+        Machine::EM_AARCH64 => AARCH64_FEATURE_1_AND,
+        Machine::EM_386 | Machine::EM_X86_64 => X86_FEATURE_1_AND,
+        _ => return Ok(Vec::new()),
+    };
+    let word = |b: &[u8]| match ehdr.little_endian() {
+        true => u32::from_le_bytes(b.try_into().unwrap()),
+        false => u32::from_be_bytes(b.try_into().unwrap()),
+    };
+    // Properties are padded to the word size of the file
+    let align = match ehdr.class() {
+        Class::ELFCLASS32 => 4,
+        _ => 8,
+    };
+
+    let mut bits = 0;
+    let notes = read_notes(r, &ehdr)?.into_iter();
+    for note in notes.filter(|n| n.name == b"GNU" && n.note_type == Note::NT_GNU_PROPERTY_TYPE_0) {
+        let mut rest = &note.desc[..];
+        while rest.len() >= 8 {
+            let pr_type = word(&rest[0..4]);
+            let size = word(&rest[4..8]) as usize;
+            let Some(data) = rest.get(8..8 + size) else {
+                break;
+            };
+            if pr_type == feature_type && size == 4 {
+                bits |= word(data);
+            }
+            rest = rest
+                .get((8 + size).next_multiple_of(align)..)
+                .unwrap_or_default();
+        }
+    }
+
+    Ok((names.iter())
+        .filter(|(bit, _)| bits & bit != 0)
+        .map(|&(_, name)| name)
+        .collect())
+}
+
+/// Read a null-terminated string from a string table
+fn string_at(strtab: &[u8], offset: u32) -> String {
+    let start = (offset as usize).min(strtab.len());
+    let name = strtab[start..]
+        .split(|&b| b == 0)
+        .next()
+        .unwrap_or_default();
+    String::from_utf8_lossy(name).into_owned()
 }
 
 #[derive(Debug, Clone, FromZeroes, FromBytes)]
@@ -288,7 +703,111 @@ impl<O: ByteOrder> Phdr64<O> {
 pub struct Type(pub u32);
 
 impl Type {
+    pub const PT_NULL: Self = Self(0);
     pub const PT_LOAD: Self = Self(1);
+    pub const PT_DYNAMIC: Self = Self(2);
+    pub const PT_INTERP: Self = Self(3);
+    pub const PT_NOTE: Self = Self(4);
+    pub const PT_PHDR: Self = Self(6);
+    pub const PT_TLS: Self = Self(7);
+    pub const PT_GNU_EH_FRAME: Self = Self(0x6474e550);
+    pub const PT_GNU_STACK: Self = Self(0x6474e551);
+    pub const PT_GNU_RELRO: Self = Self(0x6474e552);
+    pub const PT_GNU_PROPERTY: Self = Self(0x6474e553);
+
+    pub fn name(self) -> Option<&'static str> {
+        let res = match self {
+            Self::PT_NULL => "PT_NULL",
+            Self::PT_LOAD => "PT_LOAD",
+            Self::PT_DYNAMIC => "PT_DYNAMIC",
+            Self::PT_INTERP => "PT_INTERP",
+            Self::PT_NOTE => "PT_NOTE",
+            Self::PT_PHDR => "PT_PHDR",
+            Self::PT_TLS => "PT_TLS",
+            Self::PT_GNU_EH_FRAME => "PT_GNU_EH_FRAME",
+            Self::PT_GNU_STACK => "PT_GNU_STACK",
+            Self::PT_GNU_RELRO => "PT_GNU_RELRO",
+            Self::PT_GNU_PROPERTY => "PT_GNU_PROPERTY",
+            _ => return None,
+        };
+        Some(res)
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "p_type {:#x}", self.0),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, FromZeroes, FromBytes)]
+#[repr(transparent)]
+pub struct FileType(pub u16);
+
+impl FileType {
+    pub const ET_NONE: Self = Self(0);
+    pub const ET_REL: Self = Self(1);
+    pub const ET_EXEC: Self = Self(2);
+    pub const ET_DYN: Self = Self(3);
+    pub const ET_CORE: Self = Self(4);
+}
+
+impl fmt::Display for FileType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::ET_NONE => f.write_str("no file type"),
+            Self::ET_REL => f.write_str("relocatable"),
+            Self::ET_EXEC => f.write_str("executable"),
+            Self::ET_DYN => f.write_str("shared object"),
+            Self::ET_CORE => f.write_str("core"),
+            _ => write!(f, "e_type {:#x}", self.0),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, FromZeroes, FromBytes)]
+#[repr(transparent)]
+pub struct Machine(pub u16);
+
+impl Machine {
+    pub const EM_NONE: Self = Self(0);
+    pub const EM_386: Self = Self(3);
+    pub const EM_ARM: Self = Self(40);
+    pub const EM_X86_64: Self = Self(62);
+    pub const EM_MIPS: Self = Self(8);
+    pub const EM_PPC: Self = Self(20);
+    pub const EM_PPC64: Self = Self(21);
+    pub const EM_AARCH64: Self = Self(183);
+    pub const EM_RISCV: Self = Self(243);
+    pub const EM_LOONGARCH: Self = Self(258);
+
+    pub fn name(self) -> Option<&'static str> {
+        let res = match self {
+            Self::EM_386 => "i386",
+            Self::EM_MIPS => "MIPS",
+            Self::EM_PPC => "PowerPC",
+            Self::EM_PPC64 => "64-bit PowerPC",
+            Self::EM_ARM => "Arm",
+            Self::EM_X86_64 => "x86-64",
+            Self::EM_AARCH64 => "AArch64",
+            Self::EM_RISCV => "RISC-V",
+            Self::EM_LOONGARCH => "LoongArch",
+            _ => return None,
+        };
+        Some(res)
+    }
+}
+
+impl fmt::Display for Machine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "e_machine {}", self.0),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, FromZeroes, FromBytes)]
@@ -311,10 +830,40 @@ impl Flags {
     }
 }
 
+impl fmt::Display for Flags {
+    /// Flags as in "r-x"
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let r = if self.readable() { 'r' } else { '-' };
+        let w = if self.writable() { 'w' } else { '-' };
+        let x = if self.executable() { 'x' } else { '-' };
+        write!(f, "{r}{w}{x}")
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Phdr(pub Phdr64<NativeEndian>);
 
 impl Phdr {
+    /// A loadable segment that does not come from a program header
+    pub fn synthetic(
+        flags: Flags,
+        offset: u64,
+        address: u64,
+        file_size: u64,
+        memory_size: u64,
+    ) -> Self {
+        Self(Phdr64 {
+            p_type: Type::PT_LOAD.0.into(),
+            p_flags: flags.0.into(),
+            p_offset: offset.into(),
+            p_vaddr: address.into(),
+            p_paddr: address.into(),
+            p_filesz: file_size.into(),
+            p_memsz: memory_size.into(),
+            p_align: 1.into(),
+        })
+    }
+
     pub fn from_bytes(data: &[u8], ehdr: &Ehdr) -> Self {
         let res = match (ehdr.0.e_ident.class, ehdr.0.e_ident.data) {
             (Class::ELFCLASS32, Data::ELFDATA2LSB) => {
@@ -349,6 +898,10 @@ impl Phdr {
         self.0.p_vaddr.get()
     }
 
+    pub fn physical_address(&self) -> u64 {
+        self.0.p_paddr.get()
+    }
+
     pub fn file_offset(&self) -> u64 {
         self.0.p_offset.get()
     }
@@ -360,4 +913,524 @@ impl Phdr {
     pub fn memory_size(&self) -> u64 {
         self.0.p_memsz.get()
     }
+
+    pub fn alignment(&self) -> u64 {
+        self.0.p_align.get()
+    }
+
+    /// This segment, with its virtual address replaced by its physical address
+    pub fn at_physical_address(&self) -> Self {
+        let mut res = self.clone();
+        res.0.p_vaddr = res.0.p_paddr;
+        res
+    }
+
+    /// This segment, moved by `bias` in memory and to `offset` in the file
+    pub fn moved(&self, bias: u64, offset: u64) -> Self {
+        let mut res = self.clone();
+        res.0.p_vaddr = self.address().wrapping_add(bias).into();
+        res.0.p_paddr = self.physical_address().wrapping_add(bias).into();
+        res.0.p_offset = offset.into();
+        res
+    }
+
+    /// The part of this segment in memory within `start..end`, if any
+    pub fn clipped(&self, start: u64, end: u64) -> Option<Self> {
+        let start = start.max(self.address());
+        let end = end.min(self.address().saturating_add(self.memory_size()));
+
+        if start >= end {
+            return None;
+        }
+
+        let skip = start - self.address();
+        let mut res = self.clone();
+        res.0.p_vaddr = start.into();
+        res.0.p_paddr = self.physical_address().wrapping_add(skip).into();
+        res.0.p_offset = self.file_offset().wrapping_add(skip).into();
+        res.0.p_filesz = self
+            .file_size()
+            .saturating_sub(skip)
+            .min(end - start)
+            .into();
+        res.0.p_memsz = (end - start).into();
+        Some(res)
+    }
+}
+
+#[derive(Debug, Clone, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct ShdrN<O: ByteOrder, UN> {
+    sh_name: U32<O>,
+    sh_type: U32<O>,
+    sh_flags: UN,
+    sh_addr: UN,
+    sh_offset: UN,
+    sh_size: UN,
+    sh_link: U32<O>,
+    sh_info: U32<O>,
+    sh_addralign: UN,
+    sh_entsize: UN,
+}
+
+impl<O: ByteOrder, UN: FromBytes + Into<u64>> ShdrN<O, UN> {
+    pub fn wrap<O1: ByteOrder, UN1>(self) -> ShdrN<O1, UN1>
+    where
+        U32<O1>: From<u32>,
+        UN1: From<u64>,
+    {
+        ShdrN {
+            sh_name: self.sh_name.get().into(),
+            sh_type: self.sh_type.get().into(),
+            sh_flags: self.sh_flags.into().into(),
+            sh_addr: self.sh_addr.into().into(),
+            sh_offset: self.sh_offset.into().into(),
+            sh_size: self.sh_size.into().into(),
+            sh_link: self.sh_link.get().into(),
+            sh_info: self.sh_info.get().into(),
+            sh_addralign: self.sh_addralign.into().into(),
+            sh_entsize: self.sh_entsize.into().into(),
+        }
+    }
+}
+
+pub type Shdr32<O> = ShdrN<O, U32<O>>;
+pub type Shdr64<O> = ShdrN<O, U64<O>>;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, FromZeroes, FromBytes)]
+#[repr(transparent)]
+pub struct SectionType(pub u32);
+
+impl SectionType {
+    pub const SHT_NULL: Self = Self(0);
+    pub const SHT_PROGBITS: Self = Self(1);
+    pub const SHT_SYMTAB: Self = Self(2);
+    pub const SHT_STRTAB: Self = Self(3);
+    pub const SHT_RELA: Self = Self(4);
+    pub const SHT_NOTE: Self = Self(7);
+    pub const SHT_NOBITS: Self = Self(8);
+    pub const SHT_REL: Self = Self(9);
+
+    pub fn name(self) -> Option<&'static str> {
+        let res = match self {
+            Self::SHT_NULL => "NULL",
+            Self::SHT_PROGBITS => "PROGBITS",
+            Self::SHT_SYMTAB => "SYMTAB",
+            Self::SHT_STRTAB => "STRTAB",
+            Self::SHT_RELA => "RELA",
+            Self::SHT_NOTE => "NOTE",
+            Self::SHT_NOBITS => "NOBITS",
+            Self::SHT_REL => "REL",
+            _ => return None,
+        };
+        Some(res)
+    }
+}
+
+impl fmt::Display for SectionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[repr(transparent)]
+pub struct SectionFlags(pub u64);
+
+impl SectionFlags {
+    pub const SHF_WRITE: Self = Self(1 << 0);
+    pub const SHF_ALLOC: Self = Self(1 << 1);
+    pub const SHF_EXECINSTR: Self = Self(1 << 2);
+
+    pub fn alloc(self) -> bool {
+        self.0 & Self::SHF_ALLOC.0 != 0
+    }
+
+    pub fn write(self) -> bool {
+        self.0 & Self::SHF_WRITE.0 != 0
+    }
+
+    pub fn execinstr(self) -> bool {
+        self.0 & Self::SHF_EXECINSTR.0 != 0
+    }
+}
+
+impl fmt::Display for SectionFlags {
+    /// Flags as in "WAX", like readelf
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let w = if self.write() { "W" } else { "" };
+        let a = if self.alloc() { "A" } else { "" };
+        let x = if self.execinstr() { "X" } else { "" };
+        write!(f, "{w}{a}{x}")
+    }
+}
+
+/// Section header, along with its name
+#[derive(Debug, Clone)]
+pub struct Shdr(pub ShdrN<NativeEndian, U64<NativeEndian>>, pub String);
+
+impl Shdr {
+    /// An allocated section that does not come from a section header
+    pub fn synthetic(name: String, flags: SectionFlags, address: u64, size: u64) -> Self {
+        Self(
+            Shdr64 {
+                sh_name: 0.into(),
+                sh_type: SectionType::SHT_PROGBITS.0.into(),
+                sh_flags: (flags.0 | SectionFlags::SHF_ALLOC.0).into(),
+                sh_addr: address.into(),
+                sh_offset: 0.into(),
+                sh_size: size.into(),
+                sh_link: 0.into(),
+                sh_info: 0.into(),
+                sh_addralign: 1.into(),
+                sh_entsize: 0.into(),
+            },
+            name,
+        )
+    }
+
+    pub fn from_bytes(data: &[u8], ehdr: &Ehdr) -> Self {
+        let res = match (ehdr.0.e_ident.class, ehdr.0.e_ident.data) {
+            (Class::ELFCLASS32, Data::ELFDATA2LSB) => {
+                <Shdr32<LittleEndian>>::read_from(data).map(|x| x.wrap())
+            }
+            (Class::ELFCLASS64, Data::ELFDATA2LSB) => {
+                <Shdr64<LittleEndian>>::read_from(data).map(|x| x.wrap())
+            }
+            (Class::ELFCLASS32, Data::ELFDATA2MSB) => {
+                <Shdr32<BigEndian>>::read_from(data).map(|x| x.wrap())
+            }
+            (Class::ELFCLASS64, Data::ELFDATA2MSB) => {
+                <Shdr64<BigEndian>>::read_from(data).map(|x| x.wrap())
+            }
+            _ => None,
+        };
+
+        let res = res.expect("Invalid ELF header slipped through");
+
+        Self(res, String::new())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.1
+    }
+
+    pub fn to_type(&self) -> SectionType {
+        SectionType(self.0.sh_type.get())
+    }
+
+    pub fn flags(&self) -> SectionFlags {
+        SectionFlags(self.0.sh_flags.get())
+    }
+
+    pub fn address(&self) -> u64 {
+        self.0.sh_addr.get()
+    }
+
+    pub fn file_offset(&self) -> u64 {
+        self.0.sh_offset.get()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.0.sh_size.get()
+    }
+
+    pub fn set_address(&mut self, address: u64) {
+        self.0.sh_addr = address.into();
+    }
+
+    pub fn alignment(&self) -> u64 {
+        self.0.sh_addralign.get()
+    }
+
+    pub fn link(&self) -> u32 {
+        self.0.sh_link.get()
+    }
+
+    pub fn info(&self) -> u32 {
+        self.0.sh_info.get()
+    }
+
+    pub fn entry_size(&self) -> u64 {
+        self.0.sh_entsize.get()
+    }
+}
+
+#[derive(Debug, Clone, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct Sym32<O: ByteOrder> {
+    st_name: U32<O>,
+    st_value: U32<O>,
+    st_size: U32<O>,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: U16<O>,
+}
+
+impl<O: ByteOrder> Sym32<O> {
+    pub fn wrap<O1: ByteOrder>(self) -> Sym64<O1> {
+        let up = |x: u32| -> u64 { x.into() };
+        Sym64 {
+            st_name: self.st_name.get().into(),
+            st_info: self.st_info,
+            st_other: self.st_other,
+            st_shndx: self.st_shndx.get().into(),
+            st_value: up(self.st_value.get()).into(),
+            st_size: up(self.st_size.get()).into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct Sym64<O: ByteOrder> {
+    st_name: U32<O>,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: U16<O>,
+    st_value: U64<O>,
+    st_size: U64<O>,
+}
+
+impl<O: ByteOrder> Sym64<O> {
+    pub fn wrap<O1: ByteOrder>(self) -> Sym64<O1> {
+        Sym64 {
+            st_name: self.st_name.get().into(),
+            st_info: self.st_info,
+            st_other: self.st_other,
+            st_shndx: self.st_shndx.get().into(),
+            st_value: self.st_value.get().into(),
+            st_size: self.st_size.get().into(),
+        }
+    }
+}
+
+/// Symbol, along with its name
+#[derive(Debug, Clone)]
+pub struct Sym(pub Sym64<NativeEndian>, pub String);
+
+impl Sym {
+    pub const SHN_UNDEF: u16 = 0;
+    pub const SHN_LORESERVE: u16 = 0xff00;
+    pub const SHN_ABS: u16 = 0xfff1;
+    pub const SHN_COMMON: u16 = 0xfff2;
+
+    pub const STB_WEAK: u8 = 2;
+
+    pub fn from_bytes(data: &[u8], ehdr: &Ehdr) -> Self {
+        let res = match (ehdr.0.e_ident.class, ehdr.0.e_ident.data) {
+            (Class::ELFCLASS32, Data::ELFDATA2LSB) => {
+                <Sym32<LittleEndian>>::read_from(data).map(|x| x.wrap())
+            }
+            (Class::ELFCLASS64, Data::ELFDATA2LSB) => {
+                <Sym64<LittleEndian>>::read_from(data).map(|x| x.wrap())
+            }
+            (Class::ELFCLASS32, Data::ELFDATA2MSB) => {
+                <Sym32<BigEndian>>::read_from(data).map(|x| x.wrap())
+            }
+            (Class::ELFCLASS64, Data::ELFDATA2MSB) => {
+                <Sym64<BigEndian>>::read_from(data).map(|x| x.wrap())
+            }
+            _ => None,
+        };
+
+        let res = res.expect("Invalid ELF header slipped through");
+
+        Self(res, String::new())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.1
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0.st_value.get()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.0.st_size.get()
+    }
+
+    pub fn section_index(&self) -> u16 {
+        self.0.st_shndx.get()
+    }
+
+    pub fn defined(&self) -> bool {
+        self.section_index() != Self::SHN_UNDEF
+    }
+
+    pub fn binding(&self) -> u8 {
+        self.0.st_info >> 4
+    }
+
+    pub fn set_value(&mut self, value: u64) {
+        self.0.st_value = value.into();
+    }
+}
+
+#[derive(Debug, Clone, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct DynN<UN> {
+    d_tag: UN,
+    d_val: UN,
+}
+
+impl<UN: FromBytes + Into<u64>> DynN<UN> {
+    pub fn wrap<O1: ByteOrder>(self) -> DynN<U64<O1>> {
+        DynN {
+            d_tag: self.d_tag.into().into(),
+            d_val: self.d_val.into().into(),
+        }
+    }
+}
+
+pub type Dyn32<O> = DynN<U32<O>>;
+pub type Dyn64<O> = DynN<U64<O>>;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct DynTag(pub u64);
+
+impl DynTag {
+    pub const DT_NULL: Self = Self(0);
+    pub const DT_PLTRELSZ: Self = Self(2);
+    pub const DT_RELA: Self = Self(7);
+    pub const DT_RELASZ: Self = Self(8);
+    pub const DT_REL: Self = Self(17);
+    pub const DT_RELSZ: Self = Self(18);
+    pub const DT_PLTREL: Self = Self(20);
+    pub const DT_JMPREL: Self = Self(23);
+    pub const DT_RELRSZ: Self = Self(35);
+    pub const DT_RELR: Self = Self(36);
+}
+
+/// Entry in the dynamic section
+#[derive(Debug, Clone)]
+pub struct Dyn {
+    pub tag: DynTag,
+    pub value: u64,
+}
+
+impl Dyn {
+    /// Parse the dynamic section, up to the `DT_NULL` entry
+    pub fn parse_table(data: &[u8], ehdr: &Ehdr) -> Vec<Self> {
+        let entsize = match ehdr.class() {
+            Class::ELFCLASS32 => size_of::<Dyn32<NativeEndian>>(),
+            Class::ELFCLASS64 => size_of::<Dyn64<NativeEndian>>(),
+            _ => panic!("Invalid ELF header slipped through"),
+        };
+
+        data.chunks_exact(entsize)
+            .map(|b| {
+                let raw: Dyn64<NativeEndian> = match (ehdr.class(), ehdr.0.e_ident.data) {
+                    (Class::ELFCLASS32, Data::ELFDATA2LSB) => {
+                        <Dyn32<LittleEndian>>::read_from(b).map(|x| x.wrap())
+                    }
+                    (Class::ELFCLASS64, Data::ELFDATA2LSB) => {
+                        <Dyn64<LittleEndian>>::read_from(b).map(|x| x.wrap())
+                    }
+                    (Class::ELFCLASS32, Data::ELFDATA2MSB) => {
+                        <Dyn32<BigEndian>>::read_from(b).map(|x| x.wrap())
+                    }
+                    (Class::ELFCLASS64, Data::ELFDATA2MSB) => {
+                        <Dyn64<BigEndian>>::read_from(b).map(|x| x.wrap())
+                    }
+                    _ => None,
+                }
+                .unwrap();
+
+                Dyn {
+                    tag: DynTag(raw.d_tag.get()),
+                    value: raw.d_val.get(),
+                }
+            })
+            .take_while(|d| d.tag != DynTag::DT_NULL)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct RelaN<UN> {
+    r_offset: UN,
+    r_info: UN,
+    r_addend: UN,
+}
+
+impl<UN: FromBytes + Into<u64>> RelaN<UN> {
+    pub fn wrap<O1: ByteOrder>(self) -> RelaN<U64<O1>> {
+        RelaN {
+            r_offset: self.r_offset.into().into(),
+            r_info: self.r_info.into().into(),
+            r_addend: self.r_addend.into().into(),
+        }
+    }
+}
+
+pub type Rela32<O> = RelaN<U32<O>>;
+pub type Rela64<O> = RelaN<U64<O>>;
+
+/// Relocation entry, from either `SHT_REL` or `SHT_RELA`
+#[derive(Debug, Clone)]
+pub struct Rela {
+    pub offset: u64,
+    pub sym: u32,
+    pub rtype: u32,
+    /// `None` for `SHT_REL`, where the addend is stored at the location
+    pub addend: Option<i64>,
+}
+
+impl Rela {
+    /// Parse a table of `SHT_REL` or `SHT_RELA` entries
+    pub fn parse_table(data: &[u8], ehdr: &Ehdr, has_addend: bool) -> Vec<Self> {
+        let word = match ehdr.class() {
+            Class::ELFCLASS32 => 4,
+            Class::ELFCLASS64 => 8,
+            _ => panic!("Invalid ELF header slipped through"),
+        };
+        let entsize = if has_addend { word * 3 } else { word * 2 };
+
+        data.chunks_exact(entsize)
+            .map(|b| {
+                // Pad SHT_REL entries with a zero addend to share the same layout
+                let mut buf = [0; size_of::<Rela64<NativeEndian>>()];
+                buf[..entsize].copy_from_slice(b);
+                let raw: Rela64<NativeEndian> = match (ehdr.class(), ehdr.0.e_ident.data) {
+                    (Class::ELFCLASS32, Data::ELFDATA2LSB) => {
+                        <Rela32<LittleEndian>>::read_from_prefix(&buf[..]).map(|x| x.wrap())
+                    }
+                    (Class::ELFCLASS64, Data::ELFDATA2LSB) => {
+                        <Rela64<LittleEndian>>::read_from(&buf[..]).map(|x| x.wrap())
+                    }
+                    (Class::ELFCLASS32, Data::ELFDATA2MSB) => {
+                        <Rela32<BigEndian>>::read_from_prefix(&buf[..]).map(|x| x.wrap())
+                    }
+                    (Class::ELFCLASS64, Data::ELFDATA2MSB) => {
+                        <Rela64<BigEndian>>::read_from(&buf[..]).map(|x| x.wrap())
+                    }
+                    _ => None,
+                }
+                .unwrap();
+
+                let info = raw.r_info.get();
+                let (sym, rtype) = match ehdr.class() {
+                    Class::ELFCLASS32 => ((info >> 8) as u32, (info & 0xff) as u32),
+                    _ => ((info >> 32) as u32, info as u32),
+                };
+                let addend = match ehdr.class() {
+                    Class::ELFCLASS32 => raw.r_addend.get() as u32 as i32 as i64,
+                    _ => raw.r_addend.get() as i64,
+                };
+
+                Rela {
+                    offset: raw.r_offset.get(),
+                    sym,
+                    rtype,
+                    addend: has_addend.then_some(addend),
+                }
+            })
+            .collect()
+    }
 }
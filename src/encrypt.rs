@@ -0,0 +1,117 @@
+use std::{ffi::OsStr, fs};
+
+use aes_gcm::{
+    aead::{AeadInPlace, OsRng},
+    AeadCore, Aes128Gcm, Aes256Gcm, KeyInit,
+};
+use anyhow::{bail, Context};
+use zerocopy::{AsBytes, FromBytes, FromZeroes, LittleEndian, U16, U64};
+
+/// Encrypted payload header, followed by the ciphertext
+///
+/// All fields are little endian.
+#[derive(Debug, Clone, FromZeroes, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct Header {
+    magic: [u8; 4],
+    version: U16<LittleEndian>,
+    /// 128 or 256
+    key_bits: U16<LittleEndian>,
+    nonce: [u8; 12],
+    tag: [u8; 16],
+    size: U64<LittleEndian>,
+}
+
+impl Header {
+    pub const MAGIC: [u8; 4] = *b"ECFE";
+    pub const VERSION: u16 = 1;
+}
+
+/// Read an AES key, either as raw bytes or as hexadecimal text
+pub fn read_key(path: &OsStr) -> anyhow::Result<Vec<u8>> {
+    let data = fs::read(path).with_context(|| format!("Cannot read key file {path:?}"))?;
+    match parse_key(&data) {
+        Some(key) => Ok(key),
+        None => bail!(
+            "Key file {path:?} should contain a 128 or 256 bit AES key, as raw bytes or hexadecimal"
+        ),
+    }
+}
+
+/// An AES key from the contents of a key file
+///
+/// Hexadecimal text is tried first, with or without surrounding whitespace,
+/// so that 32 hex digits are a 128 bit key even without a newline. Anything
+/// else of 16 or 32 bytes is a raw key.
+fn parse_key(data: &[u8]) -> Option<Vec<u8>> {
+    let text = std::str::from_utf8(data).unwrap_or_default().trim();
+    if matches!(text.len(), 32 | 64) && text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        let key = (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect();
+        return Some(key);
+    }
+
+    matches!(data.len(), 16 | 32).then(|| data.to_vec())
+}
+
+/// Encrypt `data` with AES-GCM using a random nonce, returning the header
+/// followed by the ciphertext
+pub fn encrypt(key: &[u8], mut data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let (nonce, tag) = match key.len() {
+        16 => {
+            let cipher = Aes128Gcm::new_from_slice(key).unwrap();
+            let nonce = Aes128Gcm::generate_nonce(&mut OsRng);
+            let tag = cipher
+                .encrypt_in_place_detached(&nonce, b"", &mut data)
+                .map_err(|_| anyhow::anyhow!("Encryption failed"))?;
+            (nonce, tag)
+        }
+        32 => {
+            let cipher = Aes256Gcm::new_from_slice(key).unwrap();
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let tag = cipher
+                .encrypt_in_place_detached(&nonce, b"", &mut data)
+                .map_err(|_| anyhow::anyhow!("Encryption failed"))?;
+            (nonce, tag)
+        }
+        _ => panic!("Invalid key length slipped through"),
+    };
+
+    let header = Header {
+        magic: Header::MAGIC,
+        version: Header::VERSION.into(),
+        key_bits: (key.len() as u16 * 8).into(),
+        nonce: nonce.into(),
+        tag: tag.into(),
+        size: (data.len() as u64).into(),
+    };
+
+    let mut res = header.as_bytes().to_vec();
+    res.extend_from_slice(&data);
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_key() {
+        let hex = b"000102030405060708090a0b0c0d0e0f";
+        let key: Vec<u8> = (0..16).collect();
+        assert_eq!(parse_key(hex), Some(key.clone()));
+        assert_eq!(parse_key(&[&hex[..], b"\n"].concat()), Some(key.clone()));
+        assert_eq!(parse_key(&[&hex[..], &hex[..]].concat()).unwrap().len(), 32);
+    }
+
+    #[test]
+    fn raw_key() {
+        let key: Vec<u8> = (0..32).map(|i| i * 7).collect();
+        assert_eq!(parse_key(&key), Some(key.clone()));
+        assert_eq!(parse_key(&key[..16]), Some(key[..16].to_vec()));
+        assert_eq!(parse_key(&key[..20]), None);
+        assert_eq!(parse_key(b"not a key\n"), None);
+    }
+}
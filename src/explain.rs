@@ -0,0 +1,47 @@
+use std::ffi::OsString;
+
+use clap::Args;
+
+use crate::layout::{self, LayoutArgs};
+
+#[derive(Debug, Args)]
+pub struct ExplainArgs {
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// Input ELF, PE or Mach-O file
+    input: OsString,
+}
+
+pub fn run(args: ExplainArgs) -> anyhow::Result<()> {
+    for e in layout::explain(&args.input, &args.layout)? {
+        let p = &e.phdr;
+        println!(
+            "#{index} {t} {flags} {offset:#x} + {filesz:#x} bytes in file, {addr:#x} + {memsz:#x} bytes in memory",
+            index = e.index,
+            t = p.to_type(),
+            flags = p.flags(),
+            offset = p.file_offset(),
+            filesz = p.file_size(),
+            addr = p.address(),
+            memsz = p.memory_size(),
+        );
+
+        match e.verdict {
+            Ok(ranges) => {
+                let whole =
+                    ranges.len() == 1 && ranges[0] == (p.address()..p.address() + p.memory_size());
+                if whole {
+                    println!("  Copied");
+                } else {
+                    for range in ranges {
+                        println!("  Copied {:#x}..{:#x}", range.start, range.end);
+                    }
+                }
+            }
+            Err(reason) => println!("  Left out: {reason}"),
+        }
+    }
+
+    Ok(())
+}
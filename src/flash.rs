@@ -0,0 +1,134 @@
+//! Flashing an image to a target through a debug probe, with probe-rs
+//!
+//! The probe-rs library is not a dependency, so this runs the probe-rs command
+//! line tool, handing it the segments in an Intel HEX file.
+
+use std::{
+    env,
+    ffi::OsString,
+    io::{BufWriter, Write},
+    path::Path,
+    process::Command,
+};
+
+use anyhow::{bail, Context};
+use clap::Args;
+
+use crate::{
+    ihex,
+    layout::{self, LayoutArgs},
+    log, tempfile,
+};
+
+#[derive(Debug, Args)]
+pub struct FlashArgs {
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// Target chip, as known to probe-rs
+    #[arg(long, env = "PROBE_RS_CHIP")]
+    chip: String,
+
+    /// Debug probe to use, as VID:PID or VID:PID:SERIAL, if there is more than
+    /// one
+    #[arg(long, value_name = "SELECTOR")]
+    probe: Option<String>,
+
+    /// Protocol to talk to the target with, such as swd or jtag
+    #[arg(long)]
+    protocol: Option<String>,
+
+    /// Speed of the protocol in kHz
+    #[arg(long, value_name = "KHZ")]
+    speed: Option<u32>,
+
+    /// Erase the whole chip instead of only the sectors written
+    #[arg(long)]
+    chip_erase: bool,
+
+    /// Read back the flash after writing to check it
+    #[arg(long)]
+    verify: bool,
+
+    /// Reset the target after flashing, so that it runs the new image
+    #[arg(long)]
+    reset: bool,
+
+    /// Input ELF, PE or Mach-O files
+    #[arg(required = true)]
+    input: Vec<OsString>,
+}
+
+/// The probe-rs command line tool, from `PROBE_RS` or `PATH`
+fn probe_rs() -> Command {
+    Command::new(env::var_os("PROBE_RS").unwrap_or_else(|| "probe-rs".into()))
+}
+
+impl FlashArgs {
+    /// Options for probe-rs picking the probe and target
+    fn target_args(&self) -> Vec<String> {
+        let mut res = vec!["--chip".to_owned(), self.chip.clone()];
+        if let Some(probe) = &self.probe {
+            res.extend(["--probe".to_owned(), probe.clone()]);
+        }
+        if let Some(protocol) = &self.protocol {
+            res.extend(["--protocol".to_owned(), protocol.clone()]);
+        }
+        if let Some(speed) = self.speed {
+            res.extend(["--speed".to_owned(), speed.to_string()]);
+        }
+        res
+    }
+}
+
+fn run_probe_rs(command: &mut Command) -> anyhow::Result<()> {
+    let status = command
+        .status()
+        .context("Cannot run probe-rs (Install it, or set PROBE_RS to where it is)")?;
+    if !status.success() {
+        bail!("probe-rs failed")
+    }
+    Ok(())
+}
+
+/// Lay out the input files and download the segments to the target at their
+/// addresses, with gaps left alone
+///
+/// The segments are handed to probe-rs as Intel HEX, which has an address for
+/// each record, so nothing is written between them.
+pub fn run(args: FlashArgs) -> anyhow::Result<()> {
+    let mut layout = layout::layout(&args.input, &args.layout)?;
+
+    let hex = tempfile::Named::new(".hex")?;
+    let mut w = BufWriter::new(hex.file());
+    ihex::write(&mut layout.image, layout.base, layout.entry, &mut w)?;
+    w.flush()?;
+    drop(w);
+    download(&args, &layout, hex.path())?;
+
+    if args.reset {
+        run_probe_rs(probe_rs().arg("reset").args(args.target_args()))?;
+    }
+    Ok(())
+}
+
+fn download(args: &FlashArgs, layout: &layout::Layout, path: &Path) -> anyhow::Result<()> {
+    log::info!(
+        "Flashing {size:#x} bytes at {base:#x} to {chip}",
+        size = layout.image.size(),
+        base = layout.base,
+        chip = args.chip,
+    );
+
+    let mut command = probe_rs();
+    command
+        .args(["download", "--binary-format", "hex"])
+        .args(args.target_args());
+    if args.chip_erase {
+        command.arg("--chip-erase");
+    }
+    if args.verify {
+        command.arg("--verify");
+    }
+    run_probe_rs(command.arg(path))
+}
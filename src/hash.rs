@@ -0,0 +1,53 @@
+use std::io::{self, Read, Write};
+
+use sha2::{Digest, Sha256};
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+pub fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// SHA-256 of everything read from `r`, in hexadecimal
+pub fn sha256(mut r: impl Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut r, &mut hasher)?;
+    Ok(hex(&hasher.finalize()))
+}
+
+/// Writer that passes everything through, keeping its SHA-256
+pub struct Sha256Writer<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Sha256Writer<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// The inner writer, and the SHA-256 of everything written, in
+    /// hexadecimal
+    pub fn finish(self) -> (W, String) {
+        (self.inner, hex(&self.hasher.finalize()))
+    }
+}
+
+impl<W: Write> Write for Sha256Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
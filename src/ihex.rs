@@ -0,0 +1,135 @@
+//! Intel HEX, which gives the address of each record, so that gaps between
+//! chunks take no space
+
+use std::io::Write;
+
+use anyhow::bail;
+
+use crate::image::Image;
+
+/// Data bytes in each record
+const RECORD_SIZE: usize = 16;
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+fn record(w: &mut dyn Write, kind: u8, address: u16, data: &[u8]) -> anyhow::Result<()> {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend(address.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend(data);
+    let checksum = bytes
+        .iter()
+        .fold(0u8, |sum, b| sum.wrapping_add(*b))
+        .wrapping_neg();
+    bytes.push(checksum);
+
+    write!(w, ":")?;
+    for b in bytes {
+        write!(w, "{b:02X}")?;
+    }
+    writeln!(w)?;
+    Ok(())
+}
+
+/// Write the chunks of an image starting at address `base`, and the start
+/// address, which some programmers set the PC to, if there is one
+pub fn write(
+    image: &mut Image,
+    base: u64,
+    start: Option<u64>,
+    w: &mut dyn Write,
+) -> anyhow::Result<()> {
+    let mut upper = 0;
+    for chunk in image.resolved() {
+        let start = base + chunk.offset;
+        if start
+            .checked_add(chunk.size)
+            .is_none_or(|end| end > 1 << 32)
+        {
+            bail!("Intel HEX only has 32-bit addresses, but contents are at {start:#x}")
+        }
+
+        let mut data = Vec::new();
+        image.copy_chunk(&chunk, &mut data)?;
+        let mut address = start;
+        for piece in data.chunks(RECORD_SIZE) {
+            // Records do not cross 64K, where the upper address changes
+            let room = (0x10000 - (address & 0xffff)) as usize;
+            let (first, rest) = piece.split_at(piece.len().min(room));
+            for part in [first, rest] {
+                if part.is_empty() {
+                    continue;
+                }
+                if address >> 16 != upper {
+                    upper = address >> 16;
+                    record(w, EXTENDED_LINEAR_ADDRESS, 0, &(upper as u16).to_be_bytes())?;
+                }
+                record(w, DATA, address as u16, part)?;
+                address += part.len() as u64;
+            }
+        }
+    }
+    if let Some(start) = start {
+        let Ok(start) = u32::try_from(start) else {
+            bail!("Intel HEX only has 32-bit addresses, but the start address is {start:#x}")
+        };
+        record(w, START_LINEAR_ADDRESS, 0, &start.to_be_bytes())?;
+    }
+    record(w, END_OF_FILE, 0, &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(f: impl FnOnce(&mut Vec<u8>) -> anyhow::Result<()>) -> Vec<String> {
+        let mut out = Vec::new();
+        f(&mut out).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    #[test]
+    fn record_checksum() {
+        let data = [
+            0x21, 0x46, 0x01, 0x36, 0x01, 0x21, 0x47, 0x01, 0x36, 0x00, 0x7e, 0xfe, 0x09, 0xd2,
+            0x19, 0x01,
+        ];
+        assert_eq!(
+            lines(|w| record(w, DATA, 0x0100, &data)),
+            [":10010000214601360121470136007EFE09D2190140"],
+        );
+        assert_eq!(lines(|w| record(w, END_OF_FILE, 0, &[])), [":00000001FF"]);
+    }
+
+    #[test]
+    fn upper_address_and_start() {
+        let mut image = Image::default();
+        image.push_bytes(0, vec![0xaa; 4]);
+        let out = lines(|w| write(&mut image, 0x1_fffe, Some(0x1234_5678), w));
+        assert_eq!(
+            out,
+            [
+                ":020000040001F9",
+                ":02FFFE00AAAAAD",
+                ":020000040002F8",
+                ":02000000AAAAAA",
+                ":0400000512345678E3",
+                ":00000001FF",
+            ],
+        );
+    }
+
+    #[test]
+    fn address_too_large() {
+        let mut image = Image::default();
+        image.push_bytes(0, vec![0; 4]);
+        assert!(write(&mut image, 0xffff_fffe, None, &mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,501 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    rc::Rc,
+    time::Instant,
+};
+
+use crate::{log, mmap::Mmap, progress::Progress, timings::ChunkTime};
+
+/// Where the contents of a chunk come from
+#[derive(Debug, Clone)]
+pub enum Data {
+    /// Bytes from `files[file]`, starting at `offset`
+    File { file: usize, offset: u64 },
+    /// Bytes from `bytes`, starting at `offset`
+    Bytes { bytes: Rc<[u8]>, offset: u64 },
+    /// The same byte repeated
+    Fill { byte: u8 },
+}
+
+/// A range of bytes in the output image
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    /// Offset in the output image
+    pub offset: u64,
+    pub size: u64,
+    pub data: Data,
+}
+
+impl Chunk {
+    pub fn end(&self) -> u64 {
+        self.offset + self.size
+    }
+
+    /// The part of this chunk in `start..end`, if any
+    pub fn slice(&self, start: u64, end: u64) -> Option<Chunk> {
+        let start = start.max(self.offset);
+        let end = end.min(self.end());
+
+        if start >= end {
+            return None;
+        }
+
+        let skip = start - self.offset;
+        let data = match &self.data {
+            &Data::File { file, offset } => Data::File {
+                file,
+                offset: offset + skip,
+            },
+            Data::Bytes { bytes, offset } => Data::Bytes {
+                bytes: bytes.clone(),
+                offset: offset + skip,
+            },
+            &Data::Fill { byte } => Data::Fill { byte },
+        };
+
+        Some(Chunk {
+            offset: start,
+            size: end - start,
+            data,
+        })
+    }
+}
+
+/// Size of output buffers, unless given otherwise
+pub const DEFAULT_BUFFER_SIZE: usize = 64 << 10;
+
+/// A flat image, described as chunks of data from files
+///
+/// Parts of the image not covered by any chunk are zero. If chunks overlap,
+/// later ones take precedence.
+#[derive(Debug, Default)]
+pub struct Image {
+    pub files: Vec<File>,
+    /// Memory maps of `files`, where mapping worked, to copy from directly
+    maps: Vec<Option<Mmap>>,
+    pub chunks: Vec<Chunk>,
+    /// The image is at least this large, even if chunks end earlier
+    pub min_size: u64,
+    /// Progress of copying chunks, if shown
+    pub progress: Option<Progress>,
+    /// How long copying each chunk took, if measured
+    pub chunk_times: Option<Vec<ChunkTime>>,
+    /// Cloning failed before, so don't try again
+    no_reflink: bool,
+}
+
+impl Image {
+    /// Block size for cloning, which is the block size of most file systems
+    /// that support it
+    const CLONE_BLOCK_SIZE: u64 = 4096;
+
+    pub fn add_file(&mut self, file: File) -> usize {
+        self.maps.push(Mmap::new(&file).ok());
+        self.files.push(file);
+        self.files.len() - 1
+    }
+
+    pub fn push(&mut self, chunk: Chunk) {
+        self.chunks.push(chunk);
+    }
+
+    /// Add a chunk that all existing chunks take precedence over
+    pub fn push_under(&mut self, chunk: Chunk) {
+        self.chunks.insert(0, chunk);
+    }
+
+    /// Add a chunk with the given contents
+    pub fn push_bytes(&mut self, offset: u64, bytes: impl Into<Rc<[u8]>>) {
+        let bytes = bytes.into();
+        self.push(Chunk {
+            offset,
+            size: bytes.len() as u64,
+            data: Data::Bytes { bytes, offset: 0 },
+        });
+    }
+
+    /// Write the image sequentially into a buffer
+    pub fn read_to_vec(&mut self) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.write_stream(&mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.chunks
+            .iter()
+            .map(|c| c.end())
+            .fold(self.min_size, u64::max)
+    }
+
+    /// Chunks sorted by offset and trimmed so that they don't overlap, with
+    /// later chunks taking precedence
+    pub fn resolved(&self) -> Vec<Chunk> {
+        let mut res: Vec<Chunk> = Vec::new();
+
+        for chunk in &self.chunks {
+            res = res
+                .iter()
+                .flat_map(|c| {
+                    [c.slice(0, chunk.offset), c.slice(chunk.end(), u64::MAX)]
+                        .into_iter()
+                        .flatten()
+                })
+                .collect();
+            res.push(chunk.clone());
+        }
+
+        res.sort_by_key(|c| c.offset);
+        res
+    }
+
+    /// Read the contents of the image in `range`
+    pub fn read_range(&mut self, range: Range<u64>) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; (range.end - range.start) as usize];
+        for chunk in self.resolved() {
+            let Some(part) = chunk.slice(range.start, range.end) else {
+                continue;
+            };
+            let mut data = Vec::new();
+            copy_data(&mut self.files, &self.maps, &part, &mut data)?;
+            let start = (part.offset - range.start) as usize;
+            buf[start..start + data.len()].copy_from_slice(&data);
+        }
+        Ok(buf)
+    }
+
+    /// Bytes of the image with contents, not counting fills, and the largest
+    /// range without contents
+    pub fn stats(&self) -> (u64, Option<Range<u64>>) {
+        let mut payload = 0;
+        let mut largest_gap: Option<Range<u64>> = None;
+        let mut pos = 0;
+
+        let mut check_gap = |start: u64, end: u64| {
+            if end > start
+                && largest_gap
+                    .as_ref()
+                    .is_none_or(|g| end - start > g.end - g.start)
+            {
+                largest_gap = Some(start..end);
+            }
+        };
+
+        for chunk in self.resolved() {
+            if let Data::Fill { .. } = chunk.data {
+                continue;
+            }
+            check_gap(pos, chunk.offset);
+            payload += chunk.size;
+            pos = chunk.end();
+        }
+        check_gap(pos, self.size());
+
+        (payload, largest_gap)
+    }
+
+    /// Set the size of the output, and reserve space on disk for the chunks
+    /// and also the gaps if `gaps`, so that a full disk fails right away
+    /// instead of leaving a partial image
+    ///
+    /// Reserving space is skipped where the filesystem does not support it.
+    pub fn preallocate(&self, output: &File, gaps: bool) -> io::Result<()> {
+        output.set_len(self.size())?;
+
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+        if gaps {
+            ranges.push(0..self.size());
+        } else {
+            for chunk in self.resolved() {
+                match ranges.last_mut() {
+                    Some(last) if last.end == chunk.offset => last.end = chunk.end(),
+                    _ => ranges.push(chunk.offset..chunk.end()),
+                }
+            }
+        }
+
+        for range in ranges {
+            allocate(output, range)?;
+        }
+        Ok(())
+    }
+
+    /// Show a progress bar while writing the image, if it is large enough
+    pub fn show_progress(&mut self) {
+        let total: u64 = self.resolved().iter().map(|c| c.size).sum();
+        if total >= Progress::THRESHOLD {
+            self.progress = Some(Progress::new(total));
+        }
+    }
+
+    /// Copy the contents of a chunk, returning how many bytes were copied
+    pub fn copy_chunk(&mut self, chunk: &Chunk, w: &mut dyn Write) -> io::Result<u64> {
+        let start = Instant::now();
+        let size = match &mut self.progress {
+            Some(progress) => {
+                progress.start_chunk(chunk.size);
+                copy_data(&mut self.files, &self.maps, chunk, &mut progress.writer(w))
+            }
+            None => copy_data(&mut self.files, &self.maps, chunk, w),
+        }?;
+        if let Some(times) = &mut self.chunk_times {
+            times.push(ChunkTime {
+                offset: chunk.offset,
+                size,
+                time: start.elapsed(),
+            });
+        }
+        Ok(size)
+    }
+
+    /// Write the image by seeking, leaving gaps unwritten, buffering up to
+    /// `buffer_size` bytes
+    pub fn write_seek(&mut self, output: &mut File, buffer_size: usize) -> io::Result<()> {
+        let mut w = BufWriter::with_capacity(buffer_size, &mut *output);
+        for chunk in self.chunks.clone() {
+            let parts = match self.reflink(&chunk, &mut w)? {
+                Some(cloned) => vec![
+                    chunk.slice(chunk.offset, cloned.start),
+                    chunk.slice(cloned.end, chunk.end()),
+                ],
+                None => vec![Some(chunk)],
+            };
+            for part in parts.into_iter().flatten() {
+                w.seek(SeekFrom::Start(part.offset))?;
+                self.copy_chunk(&part, &mut w)?;
+            }
+        }
+        w.flush()?;
+        drop(w);
+        output.set_len(self.size())
+    }
+
+    /// Share the blocks of a chunk from a file with the output instead of
+    /// copying them, where the file system supports it, returning the range
+    /// of the image that was cloned
+    ///
+    /// Only whole blocks at the same offset within a block in the input and
+    /// output can be cloned, so the start and end of the chunk may be left.
+    fn reflink(
+        &mut self,
+        chunk: &Chunk,
+        output: &mut BufWriter<&mut File>,
+    ) -> io::Result<Option<Range<u64>>> {
+        let &Data::File { file, offset } = &chunk.data else {
+            return Ok(None);
+        };
+        if self.no_reflink {
+            return Ok(None);
+        }
+
+        let block_size = Self::CLONE_BLOCK_SIZE;
+        let skip = (block_size - chunk.offset % block_size) % block_size;
+        if !(offset + skip).is_multiple_of(block_size) {
+            return Ok(None);
+        }
+
+        // Past the end of the input file, the chunk is zeros, which can't be
+        // cloned
+        let file_size = self.files[file].metadata()?.len();
+        let size = chunk.size.min(file_size.saturating_sub(offset));
+        let size = size.saturating_sub(skip) / block_size * block_size;
+        if size == 0 {
+            return Ok(None);
+        }
+
+        output.flush()?;
+        let start = chunk.offset + skip;
+        if clone_range(
+            &self.files[file],
+            output.get_ref(),
+            offset + skip,
+            start,
+            size,
+        )
+        .is_err()
+        {
+            log::debug!("Cannot clone ranges of input files, copying instead");
+            self.no_reflink = true;
+            return Ok(None);
+        }
+
+        if let Some(progress) = &mut self.progress {
+            progress.start_chunk(chunk.size);
+            progress.add(size);
+        }
+        Ok(Some(start..start + size))
+    }
+
+    /// Write the image by seeking, leaving gaps and blocks of zeros within
+    /// chunks unwritten, buffering up to `buffer_size` bytes
+    ///
+    /// The output needs to start out empty, since skipped blocks are not
+    /// cleared.
+    pub fn write_sparse(&mut self, output: &mut File, buffer_size: usize) -> io::Result<()> {
+        let mut w = SparseWriter {
+            file: BufWriter::with_capacity(buffer_size, &mut *output),
+            pos: 0,
+            file_pos: 0,
+        };
+        for chunk in self.resolved() {
+            w.pos = chunk.offset;
+            self.copy_chunk(&chunk, &mut w)?;
+        }
+        w.flush()?;
+        drop(w);
+        output.set_len(self.size())
+    }
+
+    /// Write the contents of the image in `range`, writing out zeros for gaps
+    pub fn write_range(&mut self, range: Range<u64>, w: &mut dyn Write) -> io::Result<()> {
+        let mut pos = range.start;
+
+        for chunk in self.resolved() {
+            let Some(part) = chunk.slice(range.start, range.end) else {
+                continue;
+            };
+            io::copy(&mut io::repeat(0).take(part.offset - pos), w)?;
+            pos = part.offset + copy_data(&mut self.files, &self.maps, &part, w)?;
+        }
+
+        io::copy(&mut io::repeat(0).take(range.end - pos), w)?;
+        Ok(())
+    }
+
+    /// Write the image sequentially, writing out zeros for gaps
+    pub fn write_stream(&mut self, w: &mut dyn Write) -> io::Result<()> {
+        let mut pos = 0;
+
+        for chunk in self.resolved() {
+            io::copy(&mut io::repeat(0).take(chunk.offset - pos), w)?;
+            pos = chunk.offset + self.copy_chunk(&chunk, w)?;
+        }
+
+        io::copy(&mut io::repeat(0).take(self.size() - pos), w)?;
+        Ok(())
+    }
+}
+
+fn copy_data(
+    files: &mut [File],
+    maps: &[Option<Mmap>],
+    chunk: &Chunk,
+    w: &mut dyn Write,
+) -> io::Result<u64> {
+    match &chunk.data {
+        &Data::File { file, offset } => {
+            if let Some(Some(map)) = maps.get(file) {
+                let start = (offset as usize).min(map.len());
+                return io::copy(&mut (&map[start..]).take(chunk.size), w);
+            }
+            let file = &mut files[file];
+            file.seek(SeekFrom::Start(offset))?;
+            io::copy(&mut file.take(chunk.size), w)
+        }
+        Data::Bytes { bytes, offset } => {
+            let start = (*offset as usize).min(bytes.len());
+            io::copy(&mut (&bytes[start..]).take(chunk.size), w)
+        }
+        &Data::Fill { byte } => io::copy(&mut io::repeat(byte).take(chunk.size), w),
+    }
+}
+
+/// Share `size` bytes of `src` at `src_offset` with `dst` at `dst_offset`
+#[cfg(target_os = "linux")]
+fn clone_range(
+    src: &File,
+    dst: &File,
+    src_offset: u64,
+    dst_offset: u64,
+    size: u64,
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    /// `struct file_clone_range`
+    #[repr(C)]
+    struct FileCloneRange {
+        src_fd: i64,
+        src_offset: u64,
+        src_length: u64,
+        dest_offset: u64,
+    }
+    const FICLONERANGE: u64 = 0x4020940d;
+
+    let range = FileCloneRange {
+        src_fd: src.as_raw_fd().into(),
+        src_offset,
+        src_length: size,
+        dest_offset: dst_offset,
+    };
+    // SAFETY: Passing a valid `struct file_clone_range`
+    if unsafe { libc::ioctl(dst.as_raw_fd(), FICLONERANGE as _, &range) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn clone_range(_: &File, _: &File, _: u64, _: u64, _: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(target_os = "linux")]
+fn allocate(file: &File, range: Range<u64>) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let (Ok(offset), Ok(len)) = (
+        libc::off_t::try_from(range.start),
+        libc::off_t::try_from(range.end - range.start),
+    ) else {
+        return Ok(());
+    };
+    // SAFETY: Only changes the allocation of the file
+    if unsafe { libc::fallocate(file.as_raw_fd(), 0, offset, len) } == 0 {
+        return Ok(());
+    }
+    match io::Error::last_os_error() {
+        e if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+        e => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allocate(_file: &File, _range: Range<u64>) -> io::Result<()> {
+    Ok(())
+}
+
+/// Writes to a file, seeking over blocks of zeros instead of writing them
+struct SparseWriter<'a> {
+    file: BufWriter<&'a mut File>,
+    pos: u64,
+    /// Position of `file`, to only seek after skipping, which flushes
+    file_pos: u64,
+}
+
+impl SparseWriter<'_> {
+    const BLOCK_SIZE: u64 = 4096;
+}
+
+impl Write for SparseWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Up to the next block boundary, so that whole blocks are skipped
+        let size = (Self::BLOCK_SIZE - self.pos % Self::BLOCK_SIZE).min(buf.len() as u64);
+        let buf = &buf[..size as usize];
+
+        if buf.iter().any(|&b| b != 0) {
+            if self.file_pos != self.pos {
+                self.file.seek(SeekFrom::Start(self.pos))?;
+            }
+            self.file.write_all(buf)?;
+            self.file_pos = self.pos + size;
+        }
+        self.pos += size;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
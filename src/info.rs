@@ -0,0 +1,129 @@
+use std::ffi::OsString;
+
+use clap::Args;
+
+use crate::{
+    elf::{self, Class, Ehdr},
+    input,
+};
+
+#[derive(Debug, Args)]
+pub struct InfoArgs {
+    /// Input ELF file
+    input: OsString,
+}
+
+/// Print rows with columns aligned, numbers to the right
+pub fn print_table(header: &[&str], rows: &[Vec<String>]) {
+    let is_number = |cell: &str| {
+        cell.starts_with("0x") || cell.bytes().all(|b| b.is_ascii_digit() || b == b'.')
+    };
+
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    let mut numeric = vec![true; header.len()];
+    for row in rows {
+        for (i, cell) in row.iter().enumerate() {
+            widths[i] = widths[i].max(cell.len());
+            numeric[i] &= is_number(cell);
+        }
+    }
+
+    let print_row = |cells: &mut dyn Iterator<Item = &str>| {
+        let mut line = String::from(" ");
+        for (i, cell) in cells.enumerate() {
+            let width = widths[i];
+            match numeric[i] {
+                true => line.push_str(&format!(" {cell:>width$}")),
+                false => line.push_str(&format!(" {cell:<width$}")),
+            }
+        }
+        println!("{}", line.trim_end());
+    };
+
+    print_row(&mut header.iter().copied());
+    for row in rows {
+        print_row(&mut row.iter().map(String::as_str));
+    }
+}
+
+pub fn run(args: InfoArgs) -> anyhow::Result<()> {
+    let mut file = input::open(&args.input)?;
+    let ehdr = Ehdr::read(&mut file)?;
+
+    let bits = match ehdr.class() {
+        Class::ELFCLASS32 => "32",
+        _ => "64",
+    };
+    let endian = match ehdr.little_endian() {
+        true => "little endian",
+        false => "big endian",
+    };
+    println!(
+        "ELF{bits} {endian} {machine} {file_type}, entry point {entry:#x}",
+        machine = ehdr.machine(),
+        file_type = ehdr.file_type(),
+        entry = ehdr.entry(),
+    );
+
+    let features = elf::read_security_features(&mut file)?;
+    println!(
+        "Security features: {}",
+        match features.is_empty() {
+            true => "none marked".to_owned(),
+            false => features.join(", "),
+        }
+    );
+
+    let phdrs = ehdr.read_phdrs(&mut file)?;
+    if !phdrs.is_empty() {
+        println!();
+        println!("Program headers:");
+        let rows: Vec<Vec<String>> = (phdrs.iter().enumerate())
+            .map(|(i, p)| {
+                vec![
+                    i.to_string(),
+                    p.to_type().to_string(),
+                    p.flags().to_string(),
+                    format!("{:#x}", p.file_offset()),
+                    format!("{:#x}", p.file_size()),
+                    format!("{:#x}", p.address()),
+                    format!("{:#x}", p.physical_address()),
+                    format!("{:#x}", p.memory_size()),
+                    format!("{:#x}", p.alignment()),
+                ]
+            })
+            .collect();
+        print_table(
+            &[
+                "#", "Type", "Flags", "Offset", "FileSize", "VirtAddr", "PhysAddr", "MemSize",
+                "Align",
+            ],
+            &rows,
+        );
+    }
+
+    let shdrs = ehdr.read_shdrs(&mut file)?;
+    if !shdrs.is_empty() {
+        println!();
+        println!("Sections:");
+        let rows: Vec<Vec<String>> = (shdrs.iter().enumerate())
+            .map(|(i, s)| {
+                vec![
+                    i.to_string(),
+                    s.name().to_owned(),
+                    s.to_type().to_string(),
+                    s.flags().to_string(),
+                    format!("{:#x}", s.address()),
+                    format!("{:#x}", s.file_offset()),
+                    format!("{:#x}", s.size()),
+                ]
+            })
+            .collect();
+        print_table(
+            &["#", "Name", "Type", "Flags", "Address", "Offset", "Size"],
+            &rows,
+        );
+    }
+
+    Ok(())
+}
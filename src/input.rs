@@ -0,0 +1,156 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+use flate2::bufread::MultiGzDecoder;
+
+use crate::tempfile;
+
+/// Open an input file
+///
+/// Inputs need to be seekable, so for `-`, standard input is first copied to
+/// an anonymous temporary file.
+///
+/// A member of an `ar` archive can be given as `ARCHIVE:MEMBER`, e.g.
+/// `libfoo.a:foo.o`.
+///
+/// Inputs compressed with gzip or Zstandard are decompressed, also into an
+/// anonymous temporary file.
+pub fn open(path: &OsStr) -> anyhow::Result<File> {
+    let member = match Path::new(path).exists() {
+        true => None,
+        false => split_member(path),
+    };
+
+    let file = if path == "-" {
+        spool(io::stdin().lock())?
+    } else if let Some((archive, member)) = member {
+        open_member(&archive, &member)?
+    } else {
+        File::open(path)?
+    };
+
+    decompress(file).with_context(|| format!("Cannot decompress {path:?}"))
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Decompress `file` if it is compressed, detected by its magic number
+fn decompress(mut file: File) -> io::Result<File> {
+    let mut magic = Vec::new();
+    (&mut file).take(4).read_to_end(&mut magic)?;
+    file.rewind()?;
+
+    if magic.starts_with(GZIP_MAGIC) {
+        spool(MultiGzDecoder::new(BufReader::new(file)))
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        spool(zstd::Decoder::new(file)?)
+    } else {
+        Ok(file)
+    }
+}
+
+/// Copy `r` to an anonymous temporary file
+fn spool(mut r: impl Read) -> io::Result<File> {
+    let mut file = tempfile::anonymous()?;
+    io::copy(&mut r, &mut file)?;
+    file.rewind()?;
+    Ok(file)
+}
+
+/// Split `ARCHIVE:MEMBER` at the last colon
+fn split_member(path: &OsStr) -> Option<(OsString, String)> {
+    let path = path.to_str()?;
+    let (archive, member) = path.rsplit_once(':')?;
+    if archive.is_empty() || member.is_empty() {
+        return None;
+    }
+    Some((archive.into(), member.into()))
+}
+
+const AR_MAGIC: &[u8; 8] = b"!<arch>\n";
+const AR_HEADER_SIZE: u64 = 60;
+
+/// Find a member of an `ar` archive by name, and copy it out
+fn open_member(archive: &OsStr, member: &str) -> anyhow::Result<File> {
+    let mut file = BufReader::new(
+        File::open(archive).with_context(|| format!("Cannot open archive {archive:?}"))?,
+    );
+
+    let mut magic = [0; 8];
+    file.read_exact(&mut magic)?;
+    if &magic != AR_MAGIC {
+        bail!("{archive:?} is not an ar archive")
+    }
+
+    // GNU archives keep long names in a member called "//"
+    let mut long_names = Vec::new();
+    let mut offset = AR_MAGIC.len() as u64;
+
+    loop {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut header = [0; AR_HEADER_SIZE as usize];
+        match file.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+
+        if &header[58..60] != b"`\n" {
+            bail!("Invalid member header at offset {offset:#x} in archive {archive:?}")
+        }
+
+        let field = |range| {
+            String::from_utf8_lossy(&header[range])
+                .trim_end()
+                .to_owned()
+        };
+        let raw_name = field(0..16);
+        let Ok(mut size) = field(48..58).parse::<u64>() else {
+            bail!("Invalid member size at offset {offset:#x} in archive {archive:?}")
+        };
+        let mut data_offset = offset + AR_HEADER_SIZE;
+        let next = data_offset + size + size % 2;
+
+        let name = if raw_name == "//" {
+            long_names = vec![0; usize::try_from(size)?];
+            file.read_exact(&mut long_names)?;
+            None
+        } else if raw_name == "/" || raw_name == "/SYM64/" || raw_name.starts_with("__.SYMDEF") {
+            None
+        } else if let Some(len) = raw_name.strip_prefix("#1/") {
+            // BSD archives put long names right before the contents
+            let len: u64 = len.parse()?;
+            let mut name = vec![0; usize::try_from(len)?];
+            file.read_exact(&mut name)?;
+            data_offset += len;
+            size = size.saturating_sub(len);
+            let name = String::from_utf8_lossy(&name);
+            Some(name.trim_end_matches('\0').to_owned())
+        } else if let Some(index) = raw_name.strip_prefix('/') {
+            let index: usize = index.parse()?;
+            let Some(rest) = long_names.get(index..) else {
+                bail!("Invalid long name offset in archive {archive:?}")
+            };
+            let end = rest.iter().position(|&b| b == b'\n').unwrap_or(rest.len());
+            let name = String::from_utf8_lossy(&rest[..end]);
+            Some(name.trim_end_matches('/').to_owned())
+        } else {
+            Some(raw_name.trim_end_matches('/').to_owned())
+        };
+
+        if name.as_deref() == Some(member) {
+            file.seek(SeekFrom::Start(data_offset))?;
+            return Ok(spool(file.take(size))?);
+        }
+
+        offset = next;
+    }
+
+    bail!("No member named {member:?} in archive {archive:?}")
+}
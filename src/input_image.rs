@@ -0,0 +1,247 @@
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{Read, Seek},
+};
+
+use crate::{
+    elf::{self, FileType, Phdr},
+    layout::{self, LayoutArgs, Placement, Segment},
+    macho, pe, reloc,
+};
+
+/// An input file, read for layout
+pub struct Parsed {
+    pub file_type: FileType,
+    /// Entry point, moved along with the segments
+    pub entry: Option<u64>,
+    pub placement: Placement,
+    /// Candidate segments, including those of types that are not copied
+    pub segments: Vec<Segment>,
+    /// Section headers, with addresses where they are loaded, if needed
+    pub shdrs: Vec<elf::Shdr>,
+    /// Symbols, with addresses where they are loaded, if needed
+    pub syms: Vec<elf::Sym>,
+}
+
+/// An input file of some format, with its headers read
+///
+/// Each format converts what it has to ELF segments and sections, so that the
+/// rest of the layout only deals with ELF.
+pub trait InputImage {
+    /// Machine and class, checked against --expect-machine
+    fn machine(&self) -> (elf::Machine, elf::Class);
+
+    /// File type, checked against --allow-type
+    fn file_type(&self) -> FileType;
+
+    /// Read the segments, and the sections and symbols if needed, where they
+    /// are loaded
+    fn read(
+        &self,
+        input: usize,
+        path: &OsStr,
+        file: &mut File,
+        args: &LayoutArgs,
+    ) -> anyhow::Result<Parsed>;
+}
+
+impl InputImage for elf::Ehdr {
+    fn machine(&self) -> (elf::Machine, elf::Class) {
+        (elf::Ehdr::machine(self), self.class())
+    }
+
+    fn file_type(&self) -> FileType {
+        elf::Ehdr::file_type(self)
+    }
+
+    fn read(
+        &self,
+        input: usize,
+        path: &OsStr,
+        file: &mut File,
+        args: &LayoutArgs,
+    ) -> anyhow::Result<Parsed> {
+        let ehdr = self;
+
+        // Relocatable objects have no program headers, so their allocated
+        // sections are placed and relocated here, and each taken as a segment
+        if ehdr.file_type() == FileType::ET_REL {
+            let relocated = reloc::relocate_object(file, ehdr, args.base.unwrap_or(0))?;
+            let placed = (relocated.sections.iter())
+                .map(|s| (relocated.shdrs[s.index].name().to_owned(), s.phdr.address()))
+                .collect();
+            let segments = (relocated.sections.iter())
+                .map(|s| Segment {
+                    input,
+                    index: s.index,
+                    phdr: s.phdr.clone(),
+                    contents: Some(s.contents.clone()),
+                })
+                .collect();
+            return Ok(Parsed {
+                file_type: ehdr.file_type(),
+                entry: None,
+                placement: Placement::Sections(placed),
+                segments,
+                shdrs: relocated.shdrs,
+                syms: relocated.syms,
+            });
+        }
+
+        let phdrs = ehdr.read_phdrs(&mut *file)?;
+
+        // Position-independent executables are moved to start at the base
+        // address, if given, with relative relocations applied. They are
+        // relocated even where they are, as the linker may not have written
+        // the addends in place, and other relocations cannot be done here.
+        let mut bias = 0;
+        let contents = match ehdr.file_type() {
+            FileType::ET_DYN => {
+                if let Some(base) = args.base {
+                    let lowest = (phdrs.iter())
+                        .filter(|p| p.to_type() == elf::Type::PT_LOAD)
+                        .map(|p| p.address())
+                        .min()
+                        .unwrap_or(0);
+                    bias = base.wrapping_sub(lowest);
+                }
+                reloc::relocate_dynamic(file, ehdr, &phdrs, bias, |file, index, phdr| {
+                    layout::read_segment(file, index, phdr, path, args)
+                })?
+            }
+            _ => vec![None; phdrs.len()],
+        };
+
+        let segments = (phdrs.into_iter().zip(contents).enumerate())
+            .map(|(index, (phdr, contents))| Segment {
+                input,
+                index,
+                phdr: match contents {
+                    Some(_) => phdr.moved(bias, 0),
+                    None => phdr.moved(bias, phdr.file_offset()),
+                },
+                contents,
+            })
+            .collect();
+
+        let use_sections = !args.only_section.is_empty() || !args.remove_section.is_empty();
+        let use_symbols = args.start_symbol.is_some() || args.end_symbol.is_some();
+
+        let shdrs: Vec<elf::Shdr> = match use_sections || use_symbols {
+            true => (ehdr.read_shdrs(&mut *file)?.into_iter())
+                .map(|mut s| {
+                    if s.flags().alloc() {
+                        s.set_address(s.address().wrapping_add(bias));
+                    }
+                    s
+                })
+                .collect(),
+            false => Vec::new(),
+        };
+
+        let syms = match use_symbols {
+            true => (ehdr.read_symbols(&mut *file, &shdrs)?.into_iter())
+                .map(|mut s| {
+                    if s.defined() && s.section_index() != elf::Sym::SHN_ABS {
+                        s.set_value(s.value().wrapping_add(bias));
+                    }
+                    s
+                })
+                .collect(),
+            false => Vec::new(),
+        };
+
+        Ok(Parsed {
+            file_type: ehdr.file_type(),
+            // Core dumps have no entry point
+            entry: (ehdr.file_type() != FileType::ET_CORE).then(|| ehdr.entry().wrapping_add(bias)),
+            placement: Placement::Moved(bias),
+            segments,
+            shdrs,
+            syms,
+        })
+    }
+}
+
+impl InputImage for pe::Image {
+    fn machine(&self) -> (elf::Machine, elf::Class) {
+        (self.machine, self.class)
+    }
+
+    fn file_type(&self) -> FileType {
+        FileType::ET_EXEC
+    }
+
+    fn read(
+        &self,
+        input: usize,
+        _path: &OsStr,
+        _file: &mut File,
+        _args: &LayoutArgs,
+    ) -> anyhow::Result<Parsed> {
+        Ok(parse_synthetic(input, self.entry, &self.phdrs, &self.shdrs))
+    }
+}
+
+impl InputImage for macho::Image {
+    fn machine(&self) -> (elf::Machine, elf::Class) {
+        (self.machine, self.class)
+    }
+
+    fn file_type(&self) -> FileType {
+        FileType::ET_EXEC
+    }
+
+    fn read(
+        &self,
+        input: usize,
+        _path: &OsStr,
+        _file: &mut File,
+        _args: &LayoutArgs,
+    ) -> anyhow::Result<Parsed> {
+        Ok(parse_synthetic(input, self.entry, &self.phdrs, &self.shdrs))
+    }
+}
+
+/// Segments and sections from other formats, converted to ELF
+fn parse_synthetic(
+    input: usize,
+    entry: Option<u64>,
+    phdrs: &[(usize, Phdr)],
+    shdrs: &[elf::Shdr],
+) -> Parsed {
+    let segments = (phdrs.iter())
+        .map(|(index, phdr)| Segment {
+            input,
+            index: *index,
+            phdr: phdr.clone(),
+            contents: None,
+        })
+        .collect();
+
+    Parsed {
+        file_type: FileType::ET_EXEC,
+        entry,
+        placement: Placement::Moved(0),
+        segments,
+        shdrs: shdrs.to_vec(),
+        syms: Vec::new(),
+    }
+}
+
+/// Read the headers of an input file of any supported format, detected by its
+/// magic number
+pub fn open(file: &mut File) -> anyhow::Result<Box<dyn InputImage>> {
+    let mut magic = Vec::new();
+    (&mut *file).take(4).read_to_end(&mut magic)?;
+    file.rewind()?;
+
+    if magic.starts_with(pe::DOS_MAGIC) {
+        Ok(Box::new(pe::read(file)?))
+    } else if magic == macho::MH_MAGIC_64 {
+        Ok(Box::new(macho::read(file)?))
+    } else {
+        Ok(Box::new(elf::Ehdr::read(&mut *file)?))
+    }
+}
@@ -0,0 +1,1580 @@
+use std::{
+    cmp::Reverse,
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    ops::{Range, RangeInclusive},
+    rc::Rc,
+};
+
+use anyhow::{bail, Context};
+use clap::{Args, ValueEnum};
+use clap_num::maybe_hex;
+
+use crate::{
+    diag::{self, DiagArgs, Warning},
+    elf::{self, FileType, Phdr},
+    image::{Chunk, Data, Image},
+    input,
+    input_image::{self, Parsed},
+    log, select,
+};
+
+/// Options for selecting segments and laying them out
+#[derive(Debug, Clone, Args)]
+pub struct LayoutArgs {
+    /// Only copy segments with these flags (among "rwx")
+    #[arg(long, value_name = "FLAGS", value_parser=parse_flags)]
+    pub if_: Option<u32>,
+
+    /// Only copy segments without these flags (among "rwx")
+    #[arg(long, value_name = "FLAGS", value_parser=parse_flags)]
+    pub if_not: Option<u32>,
+
+    /// Only copy segments whose flags match this expression, e.g. "r & !w | x"
+    /// (Flags "rwx", with "!", "&", "|" and parentheses)
+    #[arg(long, value_name = "EXPR", value_parser = select::Expr::parse)]
+    pub select: Option<select::Expr>,
+
+    /// Address to start flat binary at (Defaults to lowest address among
+    /// segments). Position-independent executables are relocated to load here
+    #[arg(long, value_name = "ADDRESS", value_parser=maybe_hex::<u64>, env = "ELFCOPYFLAT_BASE")]
+    pub base: Option<u64>,
+
+    /// Only accept input files of these types, given as a comma-separated list
+    /// (Defaults to accepting all types)
+    #[arg(long, value_name = "TYPES", value_delimiter = ',')]
+    pub allow_type: Vec<InputType>,
+
+    /// Fail unless input files are for this architecture
+    #[arg(long, value_name = "ARCH", env = "ELFCOPYFLAT_EXPECT_MACHINE")]
+    pub expect_machine: Option<Arch>,
+
+    /// Also copy the TLS initialization image from PT_TLS
+    #[arg(long)]
+    pub include_tls: bool,
+
+    /// Put the TLS initialization image at this address instead of its own
+    #[arg(long, value_name = "ADDRESS", value_parser = maybe_hex::<u64>, requires = "include_tls")]
+    pub tls_address: Option<u64>,
+
+    /// Lay out segments by their physical (load) addresses instead of their
+    /// virtual addresses, as for XIP firmware copied to RAM at startup
+    #[arg(long, visible_alias = "lma")]
+    pub use_paddr: bool,
+
+    /// Fill memory left out of core dumps with this byte, instead of zeros
+    #[arg(long, value_name = "BYTE", value_parser = maybe_hex::<u8>)]
+    pub core_fill: Option<u8>,
+
+    /// Fill the parts of segments past the end of a truncated input file with
+    /// zeros, and cut off file contents larger than the memory size of their
+    /// segment, instead of failing
+    #[arg(long)]
+    pub lenient: bool,
+
+    /// Allow empty output file
+    #[arg(long)]
+    pub allow_empty: bool,
+
+    /// Allow overlapping segments from the same file with different contents
+    /// in the overlapping part
+    #[arg(long)]
+    pub allow_overlaps: bool,
+
+    /// How to handle overlapping segments: "merge-if-equal" allows them if
+    /// their contents agree, "first" or "last" uses the segment that comes
+    /// first or last by input file and program header index, and "error"
+    /// fails on any overlap
+    #[arg(long, value_name = "POLICY", default_value = "merge-if-equal")]
+    pub overlap_policy: OverlapPolicy,
+
+    #[command(flatten)]
+    pub diag: DiagArgs,
+
+    /// Fail instead of warning about segments that are both writable and
+    /// executable, or an executable stack
+    #[arg(long)]
+    pub deny_wx: bool,
+
+    /// Warn about segments whose file offset and address disagree modulo
+    /// their alignment, or which the base address leaves unaligned in the
+    /// output
+    #[arg(long)]
+    pub check_align: bool,
+
+    /// Fail if the base address is more than this below the first segment
+    /// (e.g. "0x1000" or "64K")
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub max_leading_pad: Option<u64>,
+
+    /// Fail if there is a gap larger than this between consecutive segments
+    /// (e.g. "0x10000" or "1M")
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, env = "ELFCOPYFLAT_MAX_GAP")]
+    pub max_gap: Option<u64>,
+
+    /// Only copy the segment with this program header index
+    #[arg(long, value_name = "N")]
+    pub segment: Option<usize>,
+
+    /// Only copy segments with these program header indices, given as a
+    /// comma-separated list of indices and ranges (e.g. "0,2-4")
+    #[arg(
+        long,
+        value_name = "LIST",
+        value_parser = parse_index_range,
+        value_delimiter = ',',
+        conflicts_with = "segment"
+    )]
+    pub segments: Vec<RangeInclusive<usize>>,
+
+    /// What to do with segments with no file contents or no memory, which
+    /// otherwise still count for the base address and overlaps
+    #[arg(long, value_name = "POLICY", default_value = "include")]
+    pub empty_segments: EmptyPolicy,
+
+    /// What to do with segments loaded from the same bytes of a file at
+    /// different addresses
+    #[arg(long, value_name = "POLICY", default_value = "keep")]
+    pub shared_file_ranges: SharedPolicy,
+
+    /// Only copy the parts of segments within this address range, given as
+    /// START..END (Can be given multiple times)
+    #[arg(long, value_name = "RANGE", value_parser = parse_range)]
+    pub only_range: Vec<Range<u64>>,
+
+    /// Leave out the parts of segments within this address range, given as
+    /// START..END, or with ":FILL", overwrite them with the byte FILL instead
+    /// (Can be given multiple times)
+    #[arg(long, value_name = "RANGE[:FILL]", value_parser = parse_removed_range)]
+    pub remove_range: Vec<RemovedRange>,
+
+    /// Only copy the parts of segments holding this section (Can be given
+    /// multiple times)
+    #[arg(short = 'j', long, value_name = "NAME")]
+    pub only_section: Vec<String>,
+
+    /// Do not copy the parts of segments holding this section (Can be given
+    /// multiple times)
+    #[arg(short = 'R', long, value_name = "NAME")]
+    pub remove_section: Vec<String>,
+
+    /// Only copy from the address of this symbol onwards
+    #[arg(long, value_name = "SYMBOL")]
+    pub start_symbol: Option<String>,
+
+    /// Only copy up to the address of this symbol
+    #[arg(long, value_name = "SYMBOL")]
+    pub end_symbol: Option<String>,
+}
+
+/// Policies for `--empty-segments`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EmptyPolicy {
+    /// Leave them out
+    Skip,
+    /// Leave them out, with a warning
+    Warn,
+    /// Keep them for layout
+    Include,
+}
+
+/// Zeros before the first segment that are warned about
+const LEADING_PAD_WARNING: u64 = 1 << 20;
+
+/// Policies for `--overlap-policy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OverlapPolicy {
+    MergeIfEqual,
+    First,
+    Last,
+    Error,
+}
+
+/// Policies for `--shared-file-ranges`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SharedPolicy {
+    /// Copy the shared bytes to each address
+    Keep,
+    /// Only copy the shared bytes to the lowest address
+    Dedupe,
+    /// Fail
+    Error,
+}
+
+/// ELF file types for `--allow-type`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum InputType {
+    /// Executables (ET_EXEC), and PE and Mach-O images
+    Exec,
+    /// Position-independent executables and shared objects (ET_DYN)
+    Dyn,
+    /// Relocatable objects (ET_REL)
+    Rel,
+    /// Core dumps (ET_CORE)
+    Core,
+}
+
+impl InputType {
+    fn file_type(self) -> FileType {
+        match self {
+            InputType::Exec => FileType::ET_EXEC,
+            InputType::Dyn => FileType::ET_DYN,
+            InputType::Rel => FileType::ET_REL,
+            InputType::Core => FileType::ET_CORE,
+        }
+    }
+}
+
+/// Architectures for `--expect-machine`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Arch {
+    #[value(alias = "x86")]
+    I386,
+    #[value(name = "x86_64", alias = "x86-64", alias = "amd64")]
+    X86_64,
+    Arm,
+    #[value(alias = "arm64")]
+    Aarch64,
+    Riscv32,
+    Riscv64,
+    Mips,
+    Powerpc,
+    Powerpc64,
+    Loongarch64,
+}
+
+impl Arch {
+    /// The `e_machine` value, the class, and whether it is little endian, for
+    /// writing ELF files
+    ///
+    /// For architectures with both endiannesses, this is the usual one for
+    /// embedded targets.
+    pub fn elf_defaults(self) -> (elf::Machine, elf::Class, bool) {
+        use elf::Class;
+
+        let (machine, class) = self.machine();
+        let class = class.unwrap_or(match self {
+            Arch::I386 | Arch::Arm | Arch::Mips | Arch::Powerpc => Class::ELFCLASS32,
+            _ => Class::ELFCLASS64,
+        });
+        let little_endian = !matches!(self, Arch::Mips | Arch::Powerpc | Arch::Powerpc64);
+        (machine, class, little_endian)
+    }
+
+    /// The `e_machine` value, and the class if it tells apart variants
+    fn machine(self) -> (elf::Machine, Option<elf::Class>) {
+        use elf::{Class, Machine};
+
+        match self {
+            Arch::I386 => (Machine::EM_386, None),
+            Arch::X86_64 => (Machine::EM_X86_64, None),
+            Arch::Arm => (Machine::EM_ARM, None),
+            Arch::Aarch64 => (Machine::EM_AARCH64, None),
+            Arch::Riscv32 => (Machine::EM_RISCV, Some(Class::ELFCLASS32)),
+            Arch::Riscv64 => (Machine::EM_RISCV, Some(Class::ELFCLASS64)),
+            Arch::Mips => (Machine::EM_MIPS, None),
+            Arch::Powerpc => (Machine::EM_PPC, None),
+            Arch::Powerpc64 => (Machine::EM_PPC64, None),
+            Arch::Loongarch64 => (Machine::EM_LOONGARCH, Some(Class::ELFCLASS64)),
+        }
+    }
+}
+
+pub fn parse_range(s: &str) -> Result<Range<u64>, String> {
+    let Some((start, end)) = s.split_once("..") else {
+        return Err("Range should be given as START..END".to_owned());
+    };
+    let start = maybe_hex::<u64>(start)?;
+    let end = maybe_hex::<u64>(end)?;
+
+    if start > end {
+        return Err(format!("Range start {start:#x} is after end {end:#x}"));
+    }
+
+    Ok(start..end)
+}
+
+/// An address range to blank out of the image
+#[derive(Debug, Clone)]
+pub struct RemovedRange {
+    pub range: Range<u64>,
+    /// Byte to overwrite the range with, instead of leaving it out
+    pub fill: Option<u8>,
+}
+
+fn parse_removed_range(s: &str) -> Result<RemovedRange, String> {
+    let (range, fill) = match s.split_once(':') {
+        Some((range, fill)) => (range, Some(maybe_hex::<u8>(fill)?)),
+        None => (s, None),
+    };
+    Ok(RemovedRange {
+        range: parse_range(range)?,
+        fill,
+    })
+}
+
+/// Parse a size, which may have a suffix `K`, `M` or `G` for a multiple of
+/// 1024, 1024^2 or 1024^3
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, shift) = match s.strip_suffix(['K', 'k']) {
+        Some(digits) => (digits, 10),
+        None => match s.strip_suffix(['M', 'm']) {
+            Some(digits) => (digits, 20),
+            None => match s.strip_suffix(['G', 'g']) {
+                Some(digits) => (digits, 30),
+                None => (s, 0),
+            },
+        },
+    };
+
+    let n = maybe_hex::<u64>(digits)?;
+    n.checked_mul(1 << shift)
+        .ok_or_else(|| format!("Size {s} is too large"))
+}
+
+fn parse_index_range(s: &str) -> Result<RangeInclusive<usize>, String> {
+    let parse = |n: &str| {
+        n.trim()
+            .parse::<usize>()
+            .map_err(|_| format!("Invalid index {n:?}"))
+    };
+
+    let range = match s.split_once('-') {
+        Some((start, end)) => parse(start)?..=parse(end)?,
+        None => parse(s)?..=parse(s)?,
+    };
+
+    if range.is_empty() {
+        return Err(format!(
+            "Range start {} is after end {}",
+            range.start(),
+            range.end()
+        ));
+    }
+
+    Ok(range)
+}
+
+pub fn parse_flags(s: &str) -> Result<u32, String> {
+    let mut flags = 0;
+    for c in s.chars() {
+        let val = match c {
+            'r' | 'R' => elf::Flags::PF_R.0,
+            'w' | 'W' => elf::Flags::PF_W.0,
+            'x' | 'X' => elf::Flags::PF_X.0,
+            _ => return Err(format!("Unknown flag '{c}'")),
+        };
+
+        if flags & val != 0 {
+            return Err(format!("Duplicate flag '{}'", c.to_ascii_lowercase()));
+        } else {
+            flags |= val;
+        }
+    }
+    Ok(flags)
+}
+
+/// A selected segment
+#[derive(Debug, Clone)]
+pub struct Segment {
+    /// Index of the input file, which is also its index in `Image::files`
+    pub input: usize,
+    /// Index of the program header in the input file, or of the section for
+    /// relocatable objects
+    pub index: usize,
+    pub phdr: Phdr,
+    /// Contents of the segment, if they are not taken from the input file as
+    /// is, in which case the file offset in `phdr` is relative to this
+    pub contents: Option<Rc<[u8]>>,
+}
+
+/// Selected segments of ELF files, laid out as a flat image
+#[derive(Debug)]
+pub struct Layout {
+    /// Selected segments, sorted by address
+    pub segments: Vec<Segment>,
+    pub base: u64,
+    /// Bytes left for a header at the start of the image, before the contents
+    /// at `base`
+    pub header: u64,
+    /// Entry point of the first input file, if it has one
+    pub entry: Option<u64>,
+    /// Where each input file ended up, by index
+    pub placements: Vec<Placement>,
+    pub image: Image,
+}
+
+/// Where an input file ended up in memory, for debuggers to load its symbols
+#[derive(Debug, Clone)]
+pub enum Placement {
+    /// Everything is where the file says, moved by this much
+    Moved(u64),
+    /// Sections of a relocatable object, by name, placed at these addresses
+    Sections(Vec<(String, u64)>),
+}
+
+impl Layout {
+    /// Offset in the image of an address at or above the base address
+    pub fn offset(&self, address: u64) -> u64 {
+        address - self.base + self.header
+    }
+
+    /// The chunk of the image holding the file contents of a segment
+    pub fn chunk(&self, s: &Segment) -> Chunk {
+        Chunk {
+            offset: self.offset(s.phdr.address()),
+            size: s.phdr.file_size(),
+            data: match &s.contents {
+                Some(bytes) => Data::Bytes {
+                    bytes: bytes.clone(),
+                    offset: s.phdr.file_offset(),
+                },
+                None => Data::File {
+                    file: s.input,
+                    offset: s.phdr.file_offset(),
+                },
+            },
+        }
+    }
+
+    /// Move everything forward by `size` bytes, and fill the space left at
+    /// the start with `byte`
+    ///
+    /// The base address stays where it is, at offset `size` in the image.
+    pub fn reserve_header(&mut self, size: u64, byte: u8) {
+        self.header += size;
+        for chunk in &mut self.image.chunks {
+            chunk.offset += size;
+        }
+        if self.image.min_size > 0 {
+            self.image.min_size += size;
+        }
+        self.image.push(Chunk {
+            offset: 0,
+            size,
+            data: Data::Fill { byte },
+        });
+    }
+
+    /// Read the file contents of a segment
+    pub fn read_segment(&mut self, s: &Segment) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        let chunk = self.chunk(s);
+        self.image.copy_chunk(&chunk, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Read the contents of a segment in memory within `range`, which are zero
+    /// past the file contents
+    pub fn read_memory(&mut self, s: &Segment, range: Range<u64>) -> io::Result<Vec<u8>> {
+        let Some(phdr) = s.phdr.clipped(range.start, range.end) else {
+            return Ok(Vec::new());
+        };
+        let size = phdr.memory_size() as usize;
+        let mut buf = self.read_segment(&Segment { phdr, ..s.clone() })?;
+        buf.resize(size, 0);
+        Ok(buf)
+    }
+}
+
+fn check_machine(
+    args: &LayoutArgs,
+    path: &OsStr,
+    actual: elf::Machine,
+    class: elf::Class,
+) -> anyhow::Result<()> {
+    let Some(arch) = args.expect_machine else {
+        return Ok(());
+    };
+
+    let (machine, expected_class) = arch.machine();
+    if actual != machine || expected_class.is_some_and(|c| c != class) {
+        let bits = match class {
+            elf::Class::ELFCLASS32 => 32,
+            _ => 64,
+        };
+        bail!(
+            "{path:?} is a {bits}-bit {actual} file, expected {expected}",
+            expected = arch.to_possible_value().unwrap().get_name(),
+        )
+    }
+
+    Ok(())
+}
+
+fn check_type(args: &LayoutArgs, path: &OsStr, actual: FileType) -> anyhow::Result<()> {
+    if args.allow_type.is_empty() || args.allow_type.iter().any(|t| t.file_type() == actual) {
+        return Ok(());
+    }
+
+    match actual {
+        FileType::ET_EXEC => bail!("{path:?} is an executable (Use --allow-type exec to copy it)"),
+        FileType::ET_DYN => bail!(
+            "{path:?} is a position-independent executable or shared object (Use --allow-type dyn \
+            to copy it, and --base to relocate it to its load address)"
+        ),
+        FileType::ET_REL => bail!(
+            "{path:?} is a relocatable object (Use --allow-type rel to place its sections \
+            starting at --base)"
+        ),
+        FileType::ET_CORE => bail!("{path:?} is a core dump (Use --allow-type core to copy it)"),
+        _ => bail!("{path:?} has unknown ELF file type {}", actual.0),
+    }
+}
+
+/// Parse an input file of any supported format, checking that it is what is
+/// expected
+fn parse(input: usize, path: &OsStr, file: &mut File, args: &LayoutArgs) -> anyhow::Result<Parsed> {
+    let image = input_image::open(file)?;
+    let (machine, class) = image.machine();
+    check_machine(args, path, machine, class)?;
+    check_type(args, path, image.file_type())?;
+    image.read(input, path, file, args)
+}
+
+/// Whether segments of this type are copied
+///
+/// The TLS initialization image is the file contents of PT_TLS. The rest is
+/// zeroed per thread, not at its address.
+fn copied_type(t: elf::Type, args: &LayoutArgs) -> bool {
+    t == elf::Type::PT_LOAD || (args.include_tls && t == elf::Type::PT_TLS)
+}
+
+/// Why a segment is not selected by type, flags or index, if it is not
+fn exclusion_reason(s: &Segment, args: &LayoutArgs) -> Option<String> {
+    let t = s.phdr.to_type();
+    let flags = s.phdr.flags();
+
+    if t == elf::Type::PT_TLS && !args.include_tls {
+        Some("PT_TLS is only copied with --include-tls".to_owned())
+    } else if !copied_type(t, args) {
+        Some(format!("{t} segments are not copied"))
+    } else if flags.0 & args.if_.unwrap_or(!0) == 0 {
+        Some("Flags do not match --if".to_owned())
+    } else if flags.0 & args.if_not.unwrap_or(0) != 0 {
+        Some("Flags match --if-not".to_owned())
+    } else if !args.select.as_ref().is_none_or(|e| e.matches(flags)) {
+        Some("Flags do not match --select".to_owned())
+    } else if args.segment.is_some_and(|n| n != s.index) {
+        Some("Not the segment given by --segment".to_owned())
+    } else if !(args.segments.is_empty() || args.segments.iter().any(|r| r.contains(&s.index))) {
+        Some("Not among the segments given by --segments".to_owned())
+    } else {
+        None
+    }
+}
+
+/// An input file, with the segments selected from it
+struct Selected {
+    file_type: FileType,
+    entry: Option<u64>,
+    placement: Placement,
+    segments: Vec<Segment>,
+}
+
+fn read_segments(
+    input: usize,
+    path: &OsStr,
+    file: &mut File,
+    args: &LayoutArgs,
+) -> anyhow::Result<Selected> {
+    let parsed = parse(input, path, file, args)?;
+    let candidates = parsed.segments;
+
+    let copied_type = |t| copied_type(t, args);
+    let candidates = candidates.into_iter().filter_map(|s| {
+        if s.phdr.to_type() != elf::Type::PT_TLS {
+            return Some(s);
+        }
+        let p = &s.phdr;
+        let phdr = match args.tls_address {
+            Some(address) => p.moved(address.wrapping_sub(p.address()), p.file_offset()),
+            None => p.clone(),
+        };
+        let phdr = phdr.clipped(
+            phdr.address(),
+            phdr.address().saturating_add(phdr.file_size()),
+        )?;
+        Some(Segment { phdr, ..s })
+    });
+    let candidates: Vec<Segment> = candidates.collect();
+
+    for s in candidates.iter().filter(|s| copied_type(s.phdr.to_type())) {
+        let p = &s.phdr;
+        if p.address().checked_add(p.memory_size()).is_none() {
+            bail!(
+                "Segment #{index} in {path:?} at {addr:#x} with size {size:#x} goes past the end of the address space",
+                index = s.index,
+                addr = p.address(),
+                size = p.memory_size(),
+            )
+        }
+        // Checked here too, as segments are moved to it after selecting by
+        // sections and symbols
+        if args.use_paddr && p.physical_address().checked_add(p.memory_size()).is_none() {
+            bail!(
+                "Segment #{index} in {path:?} at physical address {addr:#x} with size {size:#x} goes past the end of the address space",
+                index = s.index,
+                addr = p.physical_address(),
+                size = p.memory_size(),
+            )
+        }
+        if p.file_offset().checked_add(p.file_size()).is_none() {
+            bail!(
+                "Segment #{index} in {path:?} at file offset {offset:#x} with size {size:#x} goes past the end of any file",
+                index = s.index,
+                offset = p.file_offset(),
+                size = p.file_size(),
+            )
+        }
+    }
+
+    let loadable: Vec<usize> = (candidates.iter())
+        .filter(|s| copied_type(s.phdr.to_type()))
+        .map(|s| s.index)
+        .collect();
+
+    let exec_stack = (candidates.iter())
+        .any(|s| s.phdr.to_type() == elf::Type::PT_GNU_STACK && s.phdr.flags().executable());
+
+    let mut segments: Vec<Segment> = (candidates.into_iter())
+        .filter(|s| exclusion_reason(s, args).is_none())
+        .collect();
+
+    for range in &args.segments {
+        if !loadable.iter().any(|i| range.contains(i)) {
+            match range.start() == range.end() {
+                true => bail!(
+                    "No loadable segment with index {} in {path:?}",
+                    range.start()
+                ),
+                false => bail!(
+                    "No loadable segments with indices {}-{} in {path:?}",
+                    range.start(),
+                    range.end()
+                ),
+            }
+        }
+    }
+
+    check_wx(&segments, exec_stack, path, args)?;
+
+    if args.empty_segments != EmptyPolicy::Include {
+        let mut kept = Vec::new();
+        for s in segments {
+            let p = &s.phdr;
+            if p.file_size() != 0 && p.memory_size() != 0 {
+                kept.push(s);
+                continue;
+            }
+            if args.empty_segments == EmptyPolicy::Warn {
+                let what = match p.memory_size() {
+                    0 => "no memory",
+                    _ => "no file contents",
+                };
+                diag::warn(
+                    &args.diag,
+                    Warning::EmptySegment,
+                    format_args!(
+                        "Segment #{index} in {path:?} at {addr:#x} has {what}, leaving it out",
+                        index = s.index,
+                        addr = p.address(),
+                    ),
+                )?;
+            }
+        }
+        segments = kept;
+    }
+
+    let mut segments = (segments.into_iter())
+        .filter_map(|s| check_memory_size(s, path, args).transpose())
+        .map(|s| check_file_range(s?, file, path, args))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let use_sections = !args.only_section.is_empty() || !args.remove_section.is_empty();
+    let use_symbols = args.start_symbol.is_some() || args.end_symbol.is_some();
+    let shdrs = parsed.shdrs;
+
+    if use_sections {
+        let find = |name: &String| -> anyhow::Result<Range<u64>> {
+            let Some(shdr) = shdrs.iter().find(|s| s.name() == name) else {
+                bail!("No section named {name:?}")
+            };
+            if !shdr.flags().alloc() {
+                bail!("Section {name:?} is not loaded into memory")
+            }
+            Ok(shdr.address()..shdr.address() + shdr.size())
+        };
+
+        if !args.only_section.is_empty() {
+            let ranges =
+                (args.only_section.iter().map(find)).collect::<anyhow::Result<Vec<_>>>()?;
+            segments = clip(&segments, &ranges);
+        }
+
+        for range in args.remove_section.iter().map(find) {
+            let range = range?;
+            segments = clip(&segments, &[0..range.start, range.end..u64::MAX]);
+        }
+    }
+
+    if use_symbols {
+        let syms = parsed.syms;
+        let find = |name: &String| -> anyhow::Result<u64> {
+            match syms.iter().find(|s| s.name() == name && s.defined()) {
+                Some(sym) => Ok(sym.value()),
+                None => bail!("No symbol named {name:?}"),
+            }
+        };
+
+        let start = args.start_symbol.as_ref().map(find).transpose()?;
+        let end = args.end_symbol.as_ref().map(find).transpose()?;
+        let range = start.unwrap_or(0)..end.unwrap_or(u64::MAX);
+
+        if range.start > range.end {
+            bail!(
+                "Start symbol at {start:#x} is after end symbol at {end:#x}",
+                start = range.start,
+                end = range.end,
+            )
+        }
+
+        segments = clip(&segments, &[range]);
+    }
+
+    // Sections and symbols are at virtual addresses, so this is done after
+    // selecting by them
+    if args.use_paddr {
+        segments = (segments.into_iter())
+            .map(|s| Segment {
+                phdr: s.phdr.at_physical_address(),
+                ..s
+            })
+            .collect();
+    }
+
+    if !args.only_range.is_empty() {
+        segments = clip(&segments, &args.only_range);
+    }
+
+    for r in args.remove_range.iter().filter(|r| r.fill.is_none()) {
+        segments = clip(&segments, &[0..r.range.start, r.range.end..u64::MAX]);
+    }
+
+    segments = handle_shared_file_ranges(segments, path, args)?;
+
+    Ok(Selected {
+        file_type: parsed.file_type,
+        entry: parsed.entry,
+        placement: parsed.placement,
+        segments,
+    })
+}
+
+/// The parts of segments within any of `ranges` of addresses
+fn clip(segments: &[Segment], ranges: &[Range<u64>]) -> Vec<Segment> {
+    segments
+        .iter()
+        .flat_map(|s| {
+            ranges.iter().filter_map(|r| {
+                Some(Segment {
+                    phdr: s.phdr.clipped(r.start, r.end)?,
+                    ..s.clone()
+                })
+            })
+        })
+        .collect()
+}
+
+/// Warn about writable and executable memory, which is a security risk
+fn check_wx(
+    segments: &[Segment],
+    exec_stack: bool,
+    path: &OsStr,
+    args: &LayoutArgs,
+) -> anyhow::Result<()> {
+    let mut messages = Vec::new();
+    for s in segments {
+        let flags = s.phdr.flags();
+        if flags.writable() && flags.executable() {
+            messages.push((
+                Warning::WritableExecutable,
+                format!(
+                    "Segment #{index} in {path:?} at {addr:#x} is both writable and executable",
+                    index = s.index,
+                    addr = s.phdr.address(),
+                ),
+            ));
+        }
+    }
+    if exec_stack {
+        messages.push((
+            Warning::ExecutableStack,
+            format!("{path:?} asks for an executable stack with PT_GNU_STACK"),
+        ));
+    }
+
+    for (warning, m) in &messages {
+        match args.deny_wx {
+            true => log::error!("{m}"),
+            false => diag::warn(&args.diag, *warning, m)?,
+        }
+    }
+    if args.deny_wx && !messages.is_empty() {
+        bail!("Writable and executable memory in {path:?} (Remove --deny-wx to allow it)")
+    }
+    Ok(())
+}
+
+/// Warn if a segment is not aligned as its program header asks
+fn check_align(s: &Segment, base: u64, describe: &str, diag_args: &DiagArgs) -> anyhow::Result<()> {
+    let p = &s.phdr;
+    let align = p.alignment();
+    if align <= 1 {
+        return Ok(());
+    }
+
+    if s.contents.is_none() && p.file_offset() % align != p.address() % align {
+        diag::warn(
+            diag_args,
+            Warning::OffsetMisaligned,
+            format_args!(
+            "Segment {describe} has file offset {offset:#x} and address {addr:#x}, which are not congruent modulo its alignment {align:#x}",
+            offset = p.file_offset(),
+            addr = p.address(),
+            ),
+        )?;
+    }
+
+    if !base.is_multiple_of(align) {
+        diag::warn(
+            diag_args,
+            Warning::BaseMisaligned,
+            format_args!(
+            "Segment {describe} at {addr:#x} is aligned to {align:#x}, but base {base:#x} is not, so it is misaligned in the output",
+            addr = p.address(),
+            ),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Check that the file contents of a segment fit in its memory
+///
+/// With `--lenient`, the file contents are cut off at the memory size
+/// instead, which leaves nothing of a segment with no memory.
+fn check_memory_size(
+    s: Segment,
+    path: &OsStr,
+    args: &LayoutArgs,
+) -> anyhow::Result<Option<Segment>> {
+    let p = &s.phdr;
+    if p.file_size() <= p.memory_size() {
+        return Ok(Some(s));
+    }
+
+    let message = format!(
+        "Segment #{index} in {path:?} has file size {filesz:#x}, larger than its memory size {memsz:#x}",
+        index = s.index,
+        filesz = p.file_size(),
+        memsz = p.memory_size(),
+    );
+    if !args.lenient {
+        bail!("{message}. The file may be corrupted (Use --lenient to copy only the memory size)")
+    }
+    diag::warn(
+        &args.diag,
+        Warning::FileSizeOverMemorySize,
+        format_args!("{message}, copying only the memory size"),
+    )?;
+
+    let phdr = p.clipped(p.address(), p.address() + p.memory_size());
+    Ok(phdr.map(|phdr| Segment { phdr, ..s }))
+}
+
+/// Check that the file contents of a segment are all in the file
+///
+/// With `--lenient`, the missing part is filled with zeros instead.
+fn check_file_range(
+    s: Segment,
+    file: &mut File,
+    path: &OsStr,
+    args: &LayoutArgs,
+) -> anyhow::Result<Segment> {
+    let p = &s.phdr;
+    let file_size = file.metadata()?.len();
+    let end = p.file_offset() + p.file_size();
+    if s.contents.is_some() || end <= file_size {
+        return Ok(s);
+    }
+
+    let contents = read_segment(file, s.index, p, path, args)?;
+    Ok(Segment {
+        phdr: p.moved(0, 0),
+        contents: Some(contents.into()),
+        ..s
+    })
+}
+
+/// Read the file contents of segment #`index` in `path`
+///
+/// With `--lenient`, the part past the end of the file is filled with zeros
+/// instead.
+pub fn read_segment(
+    file: &mut File,
+    index: usize,
+    p: &Phdr,
+    path: &OsStr,
+    args: &LayoutArgs,
+) -> anyhow::Result<Vec<u8>> {
+    let file_size = file.metadata()?.len();
+    let end = p.file_offset().saturating_add(p.file_size());
+    if end <= file_size {
+        return elf::read_range(&mut *file, p.file_offset(), p.file_size(), "Segment");
+    }
+
+    let message = format!(
+        "Segment #{index} in {path:?} is at file offsets {start:#x}..{end:#x}, past the end of the file at {file_size:#x}",
+        start = p.file_offset(),
+    );
+    if !args.lenient {
+        bail!("{message}. The file may be truncated (Use --lenient to fill the rest with zeros)")
+    }
+    diag::warn(
+        &args.diag,
+        Warning::TruncatedFile,
+        format_args!("{message}, filling the rest with zeros"),
+    )?;
+
+    let size = usize::try_from(p.file_size())?;
+    let mut contents = Vec::new();
+    contents
+        .try_reserve_exact(size)
+        .with_context(|| format!("Segment #{index} in {path:?} is too large to fill with zeros"))?;
+    file.seek(SeekFrom::Start(p.file_offset()))?;
+    (&mut *file)
+        .take(p.file_size())
+        .read_to_end(&mut contents)?;
+    contents.resize(size, 0);
+    Ok(contents)
+}
+
+/// The file offsets that both `a` and `b` are loaded from, if any
+fn shared_file_range(a: &Segment, b: &Segment) -> Option<Range<u64>> {
+    if a.contents.is_some() || b.contents.is_some() {
+        return None;
+    }
+    let (pa, pb) = (&a.phdr, &b.phdr);
+    let start = pa.file_offset().max(pb.file_offset());
+    let end = (pa.file_offset() + pa.file_size()).min(pb.file_offset() + pb.file_size());
+    (start < end).then_some(start..end)
+}
+
+/// Check for segments loaded from the same bytes of the file, which some
+/// linkers emit at different addresses
+fn handle_shared_file_ranges(
+    mut segments: Vec<Segment>,
+    path: &OsStr,
+    args: &LayoutArgs,
+) -> anyhow::Result<Vec<Segment>> {
+    let policy = args.shared_file_ranges;
+    segments.sort_by_key(|s| s.phdr.address());
+    let mut res: Vec<Segment> = Vec::new();
+    let mut shared = 0;
+
+    for s in segments {
+        let mut parts = vec![s];
+        for kept in &res {
+            let mut rest = Vec::new();
+            for p in parts {
+                let Some(range) = shared_file_range(kept, &p) else {
+                    rest.push(p);
+                    continue;
+                };
+
+                shared += 1;
+                let m = format!(
+                    "Segment #{a} at {addr_a:#x} and segment #{b} at {addr_b:#x} are both loaded from file offsets {start:#x}..{end:#x}",
+                    a = kept.index,
+                    addr_a = kept.phdr.address(),
+                    b = p.index,
+                    addr_b = p.phdr.address(),
+                    start = range.start,
+                    end = range.end,
+                );
+                match policy {
+                    SharedPolicy::Keep => (),
+                    SharedPolicy::Error => log::error!("{m}"),
+                    SharedPolicy::Dedupe => diag::warn(&args.diag, Warning::SharedFileRange, m)?,
+                }
+
+                match policy {
+                    SharedPolicy::Dedupe => {
+                        let start = p.phdr.address() + (range.start - p.phdr.file_offset());
+                        let end = start + (range.end - range.start);
+                        rest.extend(clip(std::slice::from_ref(&p), &[0..start, end..u64::MAX]));
+                    }
+                    _ => rest.push(p),
+                }
+            }
+            parts = rest;
+        }
+        res.extend(parts);
+    }
+
+    if shared > 0 && policy == SharedPolicy::Error {
+        bail!("Segments in {path:?} share file contents (Use --shared-file-ranges keep or dedupe)")
+    }
+
+    Ok(res)
+}
+
+/// Which of two overlapping segments takes precedence, if `policy` picks one
+fn precedence<'a>(policy: OverlapPolicy, a: &'a Segment, b: &'a Segment) -> Option<&'a Segment> {
+    let (first, last) = match (a.input, a.index) < (b.input, b.index) {
+        true => (a, b),
+        false => (b, a),
+    };
+    match policy {
+        OverlapPolicy::First => Some(first),
+        OverlapPolicy::Last => Some(last),
+        OverlapPolicy::MergeIfEqual | OverlapPolicy::Error => None,
+    }
+}
+
+/// Address ranges where `a` and `b`, both starting at `start`, differ
+fn differing_ranges(a: &[u8], b: &[u8], start: u64) -> Vec<Range<u64>> {
+    let mut res: Vec<Range<u64>> = Vec::new();
+    for (i, (x, y)) in a.iter().zip(b).enumerate() {
+        if x == y {
+            continue;
+        }
+        let address = start + i as u64;
+        match res.last_mut() {
+            Some(r) if r.end == address => r.end += 1,
+            _ => res.push(address..address + 1),
+        }
+    }
+    res
+}
+
+/// Take the file contents of one program header of any type as the image,
+/// without any further layout
+///
+/// With `extend`, the image is padded with zeros to the memory size.
+pub fn raw_segment(
+    input: &OsString,
+    index: usize,
+    extend: bool,
+    args: &LayoutArgs,
+) -> anyhow::Result<Layout> {
+    let mut file = input::open(input)?;
+    let ehdr = elf::Ehdr::read(&mut file)?;
+    let phdrs = ehdr.read_phdrs(&mut file)?;
+
+    let Some(phdr) = phdrs.get(index).cloned() else {
+        bail!(
+            "No program header with index {index}, file has {count}",
+            count = phdrs.len()
+        )
+    };
+
+    let segment = Segment {
+        input: 0,
+        index,
+        phdr,
+        contents: None,
+    };
+    let segment = check_file_range(segment, &mut file, input, args)?;
+
+    let mut layout = Layout {
+        segments: vec![segment],
+        base: 0,
+        header: 0,
+        entry: Some(ehdr.entry()),
+        placements: vec![Placement::Moved(0)],
+        image: Image::default(),
+    };
+
+    layout.base = layout.segments[0].phdr.address();
+    layout.image.add_file(file);
+    let chunk = layout.chunk(&layout.segments[0]);
+    layout.image.push(chunk);
+
+    if extend {
+        layout.image.min_size = layout.segments[0].phdr.memory_size();
+    }
+
+    Ok(layout)
+}
+
+/// What happened to a program header of an input file, for `explain`
+pub struct Explanation {
+    pub index: usize,
+    /// The program header as in the file
+    pub phdr: Phdr,
+    /// Address ranges copied, or why nothing is
+    pub verdict: Result<Vec<Range<u64>>, String>,
+}
+
+/// Explain which program headers of an input file are copied, and why not
+pub fn explain(path: &OsStr, args: &LayoutArgs) -> anyhow::Result<Vec<Explanation>> {
+    let mut file = input::open(path)?;
+    let candidates = parse(0, path, &mut file, args)?.segments;
+    file.rewind()?;
+    let segments = read_segments(0, path, &mut file, args)?.segments;
+
+    let res = (candidates.into_iter())
+        .map(|c| {
+            let verdict = match exclusion_reason(&c, args) {
+                Some(reason) => Err(reason),
+                None => {
+                    let ranges: Vec<Range<u64>> = (segments.iter())
+                        .filter(|s| s.index == c.index)
+                        .map(|s| s.phdr.address()..s.phdr.address() + s.phdr.memory_size())
+                        .collect();
+                    let empty = c.phdr.file_size() == 0 || c.phdr.memory_size() == 0;
+                    match ranges.is_empty() {
+                        false => Ok(ranges),
+                        true if empty && args.empty_segments != EmptyPolicy::Include => {
+                            Err("No file contents or memory (--empty-segments)".to_owned())
+                        }
+                        true => {
+                            Err("Clipped away by address, section or symbol options".to_owned())
+                        }
+                    }
+                }
+            };
+            Explanation {
+                index: c.index,
+                phdr: c.phdr,
+                verdict,
+            }
+        })
+        .collect();
+
+    Ok(res)
+}
+
+pub fn layout(inputs: &[OsString], args: &LayoutArgs) -> anyhow::Result<Layout> {
+    let files = (inputs.iter())
+        .map(|path| input::open(path))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    layout_files(inputs, files, args)
+}
+
+/// Lay out already opened input files, with `inputs` being their paths
+pub fn layout_files(
+    inputs: &[OsString],
+    files: Vec<File>,
+    args: &LayoutArgs,
+) -> anyhow::Result<Layout> {
+    let mut image = Image::default();
+    let mut segments = Vec::new();
+    let mut core_segments = Vec::new();
+    let mut entry = None;
+    let mut placements = Vec::new();
+
+    for (path, mut file) in inputs.iter().zip(files) {
+        let input = image.files.len();
+        let selected = read_segments(input, path, &mut file, args)?;
+        let mut input_segments = selected.segments;
+        if input == 0 {
+            entry = selected.entry;
+        }
+        placements.push(selected.placement);
+        image.add_file(file);
+
+        if selected.file_type == FileType::ET_CORE {
+            core_segments.extend(input_segments.iter().cloned());
+        }
+
+        if inputs.len() > 1 {
+            log::info!("Segments in {path:?} to copy:");
+        } else {
+            log::info!("Segments in file to copy:");
+        }
+        for s in &input_segments {
+            let p = &s.phdr;
+            log::info!(
+                "  #{index} {flags} {offset:#x} + {filesz:#x} bytes in file, {addr:#x} + {memsz:#x} bytes in memory",
+                index = s.index,
+                flags = p.flags(),
+                offset = p.file_offset(),
+                filesz = p.file_size(),
+                addr = p.address(),
+                memsz = p.memory_size(),
+            );
+        }
+
+        segments.append(&mut input_segments);
+    }
+
+    // Segments at the same address stay in the order of the input files
+    segments.sort_by_key(|s| (s.phdr.address(), s.input, s.index));
+
+    let describe = |s: &Segment| match inputs.len() {
+        1 => format!("#{}", s.index),
+        _ => format!("#{} in {:?}", s.index, inputs[s.input]),
+    };
+    // Checked when reading each file, but kept from overflowing anyway
+    let end_of = |s: &Segment| -> anyhow::Result<u64> {
+        match s.phdr.address().checked_add(s.phdr.memory_size()) {
+            Some(end) => Ok(end),
+            None => bail!(
+                "Segment {d} at {addr:#x} with size {size:#x} goes past the end of the address space",
+                d = describe(s),
+                addr = s.phdr.address(),
+                size = s.phdr.memory_size(),
+            ),
+        }
+    };
+
+    let is_empty = segments.iter().all(|s| s.phdr.file_size() == 0);
+
+    if is_empty && !args.allow_empty {
+        bail!("Empty output file (Use --allow-empty to create one anyway)")
+    }
+
+    let min_addr = segments.iter().map(|s| s.phdr.address()).min();
+
+    if let (Some(base), Some(lowest)) = (args.base, segments.first()) {
+        let min_addr = lowest.phdr.address();
+        if base > min_addr {
+            bail!(
+                "Segment {d} starts at {min_addr:#x}, which is less than specified base {base:#x} (Use --base {min_addr:#x} or lower)",
+                d = describe(lowest),
+            )
+        }
+    }
+
+    if let Some(max_gap) = args.max_gap {
+        let mut prev: Option<&Segment> = None;
+        for s in &segments {
+            if let Some(p) = prev {
+                let end = end_of(p)?;
+                let gap = s.phdr.address().saturating_sub(end);
+                if gap > max_gap {
+                    bail!(
+                        "Gap of {gap:#x} bytes between segment {a} ending at {end:#x} and segment {b} at {addr:#x} is larger than the maximum {max_gap:#x} (Use --only-range or --segments to leave one out)",
+                        a = describe(p),
+                        b = describe(s),
+                        addr = s.phdr.address(),
+                    )
+                }
+            }
+            let ends_later = match prev {
+                Some(p) => end_of(s)? > end_of(p)?,
+                None => true,
+            };
+            if ends_later {
+                prev = Some(s);
+            }
+        }
+    }
+
+    let base = args.base.or(min_addr).unwrap_or(0);
+
+    // Zeros before the first segment, from a base address below it
+    if let Some(min_addr) = min_addr {
+        let pad = min_addr - base;
+        if args.max_leading_pad.is_some_and(|max| pad > max) {
+            bail!(
+                "Base {base:#x} is {pad:#x} bytes below the first segment at {min_addr:#x}, more than the maximum {max:#x} (Use --base {min_addr:#x} to start at the first segment)",
+                max = args.max_leading_pad.unwrap(),
+            )
+        }
+        if pad >= LEADING_PAD_WARNING {
+            diag::warn(
+                &args.diag,
+                Warning::LeadingPad,
+                format_args!(
+                    "Base {base:#x} is {pad:#x} bytes below the first segment at {min_addr:#x}, so the image starts with that many zeros (Without --base, it would be {min_addr:#x})"
+                ),
+            )?;
+        }
+    }
+
+    log::info!("Base address {base:#x}");
+
+    if args.check_align {
+        for s in &segments {
+            check_align(s, base, &describe(s), &args.diag)?;
+        }
+    }
+
+    let mut layout = Layout {
+        segments,
+        base,
+        header: 0,
+        entry,
+        placements,
+        image,
+    };
+
+    // Overlapping segments are fine if they agree on the contents of the
+    // overlapping part
+    let segments = layout.segments.clone();
+    let mut conflicts = 0;
+    let mut cross_file_conflicts = 0;
+    for (i, sa) in segments.iter().enumerate() {
+        let end_a = end_of(sa)?;
+        for sb in segments[i + 1..].iter() {
+            if sb.phdr.address() >= end_a {
+                break;
+            }
+
+            let end = end_a.min(end_of(sb)?);
+            let range = sb.phdr.address()..end;
+            let a = layout.read_memory(sa, range.clone())?;
+            let b = layout.read_memory(sb, range.clone())?;
+            let differing = differing_ranges(&a, &b, range.start);
+
+            if args.overlap_policy == OverlapPolicy::Error {
+                log::error!(
+                    "Segment {a} and segment {b} overlap at {start:#x}..{end:#x}",
+                    a = describe(sa),
+                    b = describe(sb),
+                    start = range.start,
+                );
+                conflicts += 1;
+                continue;
+            }
+
+            if differing.is_empty() {
+                log::debug!(
+                    "Segment {a} and segment {b} overlap at {start:#x}..{end:#x} with the same contents",
+                    a = describe(sa),
+                    b = describe(sb),
+                    start = range.start,
+                );
+                continue;
+            }
+
+            if let Some(winner) = precedence(args.overlap_policy, sa, sb) {
+                log::info!(
+                    "Segment {a} and segment {b} overlap with different contents at {start:#x}..{end:#x}, using segment {winner}",
+                    a = describe(sa),
+                    b = describe(sb),
+                    start = range.start,
+                    winner = describe(winner),
+                );
+                continue;
+            }
+
+            for r in &differing {
+                log::error!(
+                    "Segment {a} and segment {b} overlap with different contents at {start:#x}..{end:#x}",
+                    a = describe(sa),
+                    b = describe(sb),
+                    start = r.start,
+                    end = r.end,
+                );
+            }
+
+            if sa.input != sb.input {
+                cross_file_conflicts += 1;
+            } else {
+                conflicts += 1;
+            }
+        }
+    }
+
+    if args.overlap_policy == OverlapPolicy::Error && conflicts > 0 {
+        bail!("Overlapping segments (--overlap-policy is error)")
+    }
+
+    if cross_file_conflicts > 0 {
+        bail!("Overlapping segments from different input files with different contents (Use --overlap-policy first or last to pick one)")
+    }
+
+    if conflicts > 0 && !args.allow_overlaps {
+        bail!(
+            "Overlapping segments with different contents (Use --allow-overlaps to use it anyway)"
+        )
+    }
+
+    // Later chunks take precedence
+    let mut in_order = layout.segments.clone();
+    match args.overlap_policy {
+        OverlapPolicy::First => in_order.sort_by_key(|s| Reverse((s.input, s.index))),
+        OverlapPolicy::Last => in_order.sort_by_key(|s| (s.input, s.index)),
+        OverlapPolicy::MergeIfEqual | OverlapPolicy::Error => {}
+    }
+    for s in &in_order {
+        let chunk = layout.chunk(s);
+        layout.image.push(chunk);
+    }
+
+    // Core dumps leave out some memory, such as unchanged file mappings, whose
+    // contents are not known. The image covers all of it anyway, so that it is
+    // a full snapshot of memory.
+    for s in &core_segments {
+        let p = &s.phdr;
+        let end = p.address() + p.memory_size() - base;
+        layout.image.min_size = layout.image.min_size.max(end);
+
+        if let Some(byte) = args.core_fill {
+            if p.memory_size() > p.file_size() {
+                layout.image.push_under(Chunk {
+                    offset: p.address() + p.file_size() - base,
+                    size: p.memory_size() - p.file_size(),
+                    data: Data::Fill { byte },
+                });
+            }
+        }
+    }
+
+    // Removed ranges with a fill byte are overwritten where they are in the
+    // image, which they do not make larger
+    let end = base + layout.image.size();
+    for r in &args.remove_range {
+        let Some(byte) = r.fill else {
+            continue;
+        };
+        let (start, end) = (r.range.start.max(base), r.range.end.min(end));
+        if start < end {
+            layout.image.push(Chunk {
+                offset: start - base,
+                size: end - start,
+                data: Data::Fill { byte },
+            });
+        }
+    }
+
+    Ok(layout)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::tempfile;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        layout: LayoutArgs,
+    }
+
+    fn args(args: &[&str]) -> LayoutArgs {
+        Cli::parse_from(["elfcopyflat"].iter().chain(args)).layout
+    }
+
+    /// A 64-bit little endian RISC-V ELF file of type `e_type`, `size` bytes
+    /// long, with program headers given as type, file offset, virtual address,
+    /// physical address, file size and memory size
+    fn write_elf(e_type: u16, phdrs: &[(u32, u64, u64, u64, u64, u64)], size: usize) -> File {
+        let mut data = Vec::new();
+        data.extend(b"\x7fELF\x02\x01\x01");
+        data.extend([0; 9]);
+        data.extend(e_type.to_le_bytes());
+        data.extend(243u16.to_le_bytes()); // e_machine: EM_RISCV
+        data.extend(1u32.to_le_bytes()); // e_version
+        data.extend(0u64.to_le_bytes()); // e_entry
+        data.extend(0x40u64.to_le_bytes()); // e_phoff
+        data.extend(0u64.to_le_bytes()); // e_shoff
+        data.extend(0u32.to_le_bytes()); // e_flags
+        for value in [0x40, 0x38, phdrs.len() as u16, 0x40, 0, 0] {
+            data.extend(value.to_le_bytes());
+        }
+        for &(p_type, offset, vaddr, paddr, filesz, memsz) in phdrs {
+            data.extend(p_type.to_le_bytes());
+            data.extend(4u32.to_le_bytes()); // p_flags: PF_R
+            for value in [offset, vaddr, paddr, filesz, memsz, 0x1000] {
+                data.extend(value.to_le_bytes());
+            }
+        }
+        data.resize(size, 0);
+
+        let mut file = tempfile::anonymous().unwrap();
+        file.write_all(&data).unwrap();
+        file
+    }
+
+    const PT_LOAD: u32 = 1;
+    const PT_DYNAMIC: u32 = 2;
+    const ET_EXEC: u16 = 2;
+    const ET_DYN: u16 = 3;
+
+    #[test]
+    fn truncated_pie() {
+        // The dynamic table is empty, and the loaded segment goes 0x80 bytes
+        // past the end of the file
+        let phdrs = [
+            (PT_LOAD, 0x1000, 0, 0, 0x100, 0x100),
+            (PT_DYNAMIC, 0x1000, 0, 0, 0x10, 0x10),
+        ];
+        let path = OsStr::new("pie.elf");
+
+        let mut file = write_elf(ET_DYN, &phdrs, 0x1080);
+        let err = read_segments(0, path, &mut file, &args(&[])).err().unwrap();
+        assert!(err.to_string().contains("\"pie.elf\""), "{err}");
+
+        let mut file = write_elf(ET_DYN, &phdrs, 0x1080);
+        let selected = read_segments(0, path, &mut file, &args(&["--lenient"])).unwrap();
+        let s = (selected.segments.iter())
+            .find(|s| s.phdr.to_type() == elf::Type::PT_LOAD)
+            .unwrap();
+        assert_eq!(s.contents.as_deref(), Some(&[0; 0x100][..]));
+    }
+
+    #[test]
+    fn high_physical_address() {
+        let phdrs = [(PT_LOAD, 0x1000, 0x1000, u64::MAX - 0xff, 0x100, 0x200)];
+        let path = OsStr::new("high.elf");
+
+        let mut file = write_elf(ET_EXEC, &phdrs, 0x1100);
+        read_segments(0, path, &mut file, &args(&[])).unwrap();
+
+        let mut file = write_elf(ET_EXEC, &phdrs, 0x1100);
+        let err = read_segments(0, path, &mut file, &args(&["--use-paddr"]))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("physical address"), "{err}");
+    }
+
+    #[test]
+    fn overlapping_high_physical_addresses() {
+        // Two segments agreeing on zeros where they overlap, at the very end
+        // of the physical address space
+        let top = u64::MAX - 0x1ff;
+        let phdrs = [
+            (PT_LOAD, 0x1000, 0x1000, top, 0x80, 0x100),
+            (PT_LOAD, 0x1100, 0x2000, top + 0x80, 0x80, 0x100),
+        ];
+        let inputs = [OsString::from("high.elf")];
+
+        let file = write_elf(ET_EXEC, &phdrs, 0x1180);
+        let layout = layout_files(&inputs, vec![file], &args(&["--use-paddr"])).unwrap();
+        assert_eq!(layout.base, top);
+
+        let phdrs = [(PT_LOAD, 0x1000, 0x1000, top, 0x80, 0x201)];
+        let file = write_elf(ET_EXEC, &phdrs, 0x1080);
+        let err = layout_files(&inputs, vec![file], &args(&["--use-paddr"]))
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string().contains("end of the address space"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn oversized_pie() {
+        let phdrs = [
+            (PT_LOAD, 0x1000, 0, 0, 0x100, 0x100),
+            (PT_DYNAMIC, 0x1000, 0, 0, 1 << 44, 1 << 44),
+        ];
+        let mut file = write_elf(ET_DYN, &phdrs, 0x1100);
+        let err = read_segments(0, OsStr::new("pie.elf"), &mut file, &args(&[]))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("\"pie.elf\""), "{err}");
+    }
+}
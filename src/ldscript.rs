@@ -0,0 +1,57 @@
+//! Linker script fragments describing where things ended up in the image,
+//! for placing other things around it
+
+use std::{ffi::OsString, fmt::Write, fs};
+
+/// Something copied to the image
+pub struct Entry {
+    /// Name of the memory region, which should be a valid symbol
+    pub name: String,
+    pub address: u64,
+    pub size: u64,
+    /// Attributes of the memory region, as in "rx"
+    pub attributes: String,
+}
+
+/// Write a `MEMORY` command with a region for each entry, and a `SECTIONS`
+/// command defining symbols for the bounds and entry point of the image
+pub fn write(
+    path: &OsString,
+    entries: &[Entry],
+    base: u64,
+    entry_point: Option<u64>,
+    image_size: u64,
+) -> anyhow::Result<()> {
+    let mut script = String::new();
+    writeln!(script, "/* Written by elfcopyflat */")?;
+    writeln!(script)?;
+    writeln!(script, "MEMORY")?;
+    writeln!(script, "{{")?;
+    for entry in entries {
+        let attributes = match entry.attributes.as_str() {
+            "" => String::new(),
+            attributes => format!(" ({attributes})"),
+        };
+        writeln!(
+            script,
+            "  {name}{attributes} : ORIGIN = {address:#x}, LENGTH = {size:#x}",
+            name = entry.name,
+            address = entry.address,
+            size = entry.size,
+        )?;
+    }
+    writeln!(script, "}}")?;
+    writeln!(script)?;
+    writeln!(script, "SECTIONS")?;
+    writeln!(script, "{{")?;
+    writeln!(script, "  __image_start = {base:#x};")?;
+    writeln!(script, "  __image_end = {:#x};", base + image_size)?;
+    writeln!(script, "  __image_size = {image_size:#x};")?;
+    if let Some(entry_point) = entry_point {
+        writeln!(script, "  __image_entry = {entry_point:#x};")?;
+    }
+    writeln!(script, "}}")?;
+
+    fs::write(path, script)?;
+    Ok(())
+}
@@ -0,0 +1,6 @@
+//! Helpers for using elfcopyflat from build scripts and runners
+//!
+//! The conversion itself is done by running the `elfcopyflat` program, which
+//! needs to be installed.
+
+pub mod build_support;
@@ -0,0 +1,82 @@
+use std::{
+    env,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+/// How much to print, each level including the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    /// Details of errors, which are always printed
+    Error,
+    /// Warnings, hidden with `-q`
+    Warn,
+    /// What is being copied where, shown with `-v`
+    Info,
+    /// Everything else, shown with `-vv`
+    Debug,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Warn as u8);
+
+impl Level {
+    /// Parse a level as in `RUST_LOG`, e.g. "info"
+    fn parse(s: &str) -> Option<Self> {
+        let res = match s.trim().to_ascii_lowercase().as_str() {
+            "off" | "error" => Level::Error,
+            "warn" => Level::Warn,
+            "info" => Level::Info,
+            "debug" | "trace" => Level::Debug,
+            _ => return None,
+        };
+        Some(res)
+    }
+}
+
+/// Set the level from `-q` and `-v`, or otherwise from the `ELFCOPYFLAT_LOG`
+/// environment variable
+pub fn init(quiet: bool, verbose: u8) {
+    let level = if quiet {
+        Level::Error
+    } else if verbose > 0 {
+        match verbose {
+            1 => Level::Info,
+            _ => Level::Debug,
+        }
+    } else {
+        (env::var("ELFCOPYFLAT_LOG").ok())
+            .and_then(|s| Level::parse(&s))
+            .unwrap_or(Level::Warn)
+    };
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log::enabled($level) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log::log!($crate::log::Level::Error, $($arg)*) };
+}
+
+macro_rules! warning {
+    ($($arg:tt)*) => { $crate::log::log!($crate::log::Level::Warn, $($arg)*) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log::log!($crate::log::Level::Info, $($arg)*) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log::log!($crate::log::Level::Debug, $($arg)*) };
+}
+
+pub(crate) use {debug, error, info, log, warning};
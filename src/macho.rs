@@ -0,0 +1,309 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    mem::size_of,
+};
+
+use anyhow::bail;
+use zerocopy::{FromBytes, FromZeroes, LittleEndian, U32, U64};
+
+use crate::elf::{self, Class, Flags, Machine, Phdr, SectionFlags, Shdr};
+
+/// Header of a 64-bit Mach-O file
+///
+/// All fields are little endian, which is the only byte order supported.
+#[derive(Debug, Clone, FromZeroes, FromBytes)]
+#[repr(C)]
+struct Header64 {
+    magic: U32<LittleEndian>,
+    cputype: U32<LittleEndian>,
+    cpusubtype: U32<LittleEndian>,
+    filetype: U32<LittleEndian>,
+    ncmds: U32<LittleEndian>,
+    sizeofcmds: U32<LittleEndian>,
+    flags: U32<LittleEndian>,
+    reserved: U32<LittleEndian>,
+}
+
+#[derive(Debug, Clone, FromZeroes, FromBytes)]
+#[repr(C)]
+struct LoadCommand {
+    cmd: U32<LittleEndian>,
+    cmdsize: U32<LittleEndian>,
+}
+
+#[derive(Debug, Clone, FromZeroes, FromBytes)]
+#[repr(C)]
+struct SegmentCommand64 {
+    cmd: U32<LittleEndian>,
+    cmdsize: U32<LittleEndian>,
+    segname: [u8; 16],
+    vmaddr: U64<LittleEndian>,
+    vmsize: U64<LittleEndian>,
+    fileoff: U64<LittleEndian>,
+    filesize: U64<LittleEndian>,
+    maxprot: U32<LittleEndian>,
+    initprot: U32<LittleEndian>,
+    nsects: U32<LittleEndian>,
+    flags: U32<LittleEndian>,
+}
+
+/// `LC_MAIN`, with the entry point as a file offset
+#[derive(Debug, Clone, FromZeroes, FromBytes)]
+#[repr(C)]
+struct EntryPointCommand {
+    cmd: U32<LittleEndian>,
+    cmdsize: U32<LittleEndian>,
+    entryoff: U64<LittleEndian>,
+    stacksize: U64<LittleEndian>,
+}
+
+/// Header of each thread state in `LC_UNIXTHREAD`, with `count` in 32-bit
+/// words
+#[derive(Debug, Clone, FromZeroes, FromBytes)]
+#[repr(C)]
+struct ThreadState {
+    flavor: U32<LittleEndian>,
+    count: U32<LittleEndian>,
+}
+
+#[derive(Debug, Clone, FromZeroes, FromBytes)]
+#[repr(C)]
+struct Section64 {
+    sectname: [u8; 16],
+    segname: [u8; 16],
+    addr: U64<LittleEndian>,
+    size: U64<LittleEndian>,
+    offset: U32<LittleEndian>,
+    align: U32<LittleEndian>,
+    reloff: U32<LittleEndian>,
+    nreloc: U32<LittleEndian>,
+    flags: U32<LittleEndian>,
+    reserved1: U32<LittleEndian>,
+    reserved2: U32<LittleEndian>,
+    reserved3: U32<LittleEndian>,
+}
+
+pub const MH_MAGIC_64: [u8; 4] = 0xfeedfacf_u32.to_le_bytes();
+const LC_SEGMENT_64: u32 = 0x19;
+const LC_UNIXTHREAD: u32 = 0x5;
+const LC_MAIN: u32 = 0x80000028;
+
+/// Thread state flavors with the program counter, and its index among the
+/// 64-bit registers
+const X86_THREAD_STATE64: u32 = 4;
+const X86_RIP: usize = 16;
+const ARM_THREAD_STATE64: u32 = 6;
+const ARM_PC: usize = 32;
+
+const VM_PROT_READ: u32 = 1;
+const VM_PROT_WRITE: u32 = 2;
+const VM_PROT_EXECUTE: u32 = 4;
+
+const CPU_ARCH_ABI64: u32 = 0x01000000;
+const CPU_TYPE_X86: u32 = 7;
+const CPU_TYPE_ARM: u32 = 12;
+
+/// Segments of a Mach-O file, in the same form as ELF
+pub struct Image {
+    pub machine: Machine,
+    pub class: Class,
+    /// Entry point, from `LC_MAIN` or `LC_UNIXTHREAD`
+    pub entry: Option<u64>,
+    /// Index of each load command, with a loadable segment for it
+    pub phdrs: Vec<(usize, Phdr)>,
+    /// Sections, named like `__TEXT,__text`
+    pub shdrs: Vec<Shdr>,
+}
+
+fn name(bytes: &[u8; 16]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+/// The program counter in the thread states of `LC_UNIXTHREAD`, if there is
+/// one for `machine`
+fn thread_pc(mut states: &[u8], machine: Machine) -> Option<u64> {
+    while let Some(state) = ThreadState::read_from_prefix(states) {
+        let start = size_of::<ThreadState>();
+        let end = start.checked_add((state.count.get() as usize).checked_mul(4)?)?;
+        let registers = states.get(start..end)?;
+        let pc = match (machine, state.flavor.get()) {
+            (Machine::EM_X86_64, X86_THREAD_STATE64) => Some(X86_RIP),
+            (Machine::EM_AARCH64, ARM_THREAD_STATE64) => Some(ARM_PC),
+            _ => None,
+        };
+        if let Some(pc) = pc {
+            let value = registers.get(pc * 8..pc * 8 + 8)?;
+            return Some(u64::from_le_bytes(value.try_into().unwrap()));
+        }
+        states = &states[end..];
+    }
+    None
+}
+
+/// Read the `LC_SEGMENT_64` load commands of a 64-bit Mach-O file, and the
+/// entry point
+///
+/// Segments with no access, such as `__PAGEZERO`, are left out.
+pub fn read(file: &mut File) -> anyhow::Result<Image> {
+    let mut buf = vec![0; size_of::<Header64>()];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut buf)?;
+    let header = Header64::read_from(&buf[..]).unwrap();
+
+    let commands = elf::read_range(
+        &mut *file,
+        size_of::<Header64>() as u64,
+        header.sizeofcmds.get().into(),
+        "Load commands",
+    )?;
+
+    let cputype = header.cputype.get();
+    let machine = match cputype {
+        CPU_TYPE_X86 => Machine::EM_386,
+        CPU_TYPE_ARM => Machine::EM_ARM,
+        _ if cputype == CPU_TYPE_X86 | CPU_ARCH_ABI64 => Machine::EM_X86_64,
+        _ if cputype == CPU_TYPE_ARM | CPU_ARCH_ABI64 => Machine::EM_AARCH64,
+        _ => Machine::EM_NONE,
+    };
+
+    let mut image = Image {
+        machine,
+        class: Class::ELFCLASS64,
+        entry: None,
+        phdrs: Vec::new(),
+        shdrs: Vec::new(),
+    };
+
+    // LC_MAIN gives a file offset, found in the segments once all are read
+    let mut entry_offset = None;
+
+    let mut rest = &commands[..];
+    for i in 0..usize::try_from(header.ncmds.get())? {
+        let Some(command) = LoadCommand::read_from_prefix(rest) else {
+            bail!("Load command {i} is past the end of load commands")
+        };
+        let size = command.cmdsize.get() as usize;
+        if size < size_of::<LoadCommand>() || size > rest.len() {
+            bail!("Invalid size {size:#x} of load command {i}")
+        }
+        let (data, next) = rest.split_at(size);
+        rest = next;
+
+        match command.cmd.get() {
+            LC_SEGMENT_64 => {}
+            LC_MAIN => {
+                let Some(main) = EntryPointCommand::read_from_prefix(data) else {
+                    bail!("Entry point load command {i} is too small")
+                };
+                entry_offset = Some(main.entryoff.get());
+                continue;
+            }
+            LC_UNIXTHREAD => {
+                let states = &data[size_of::<LoadCommand>()..];
+                image.entry = image.entry.or(thread_pc(states, machine));
+                continue;
+            }
+            _ => continue,
+        }
+
+        let Some(segment) = SegmentCommand64::read_from_prefix(data) else {
+            bail!("Segment load command {i} is too small")
+        };
+
+        let prot = segment.initprot.get();
+        if prot & (VM_PROT_READ | VM_PROT_WRITE | VM_PROT_EXECUTE) == 0 {
+            continue;
+        }
+
+        let mut flags = 0;
+        let mut section_flags = 0;
+        if prot & VM_PROT_READ != 0 {
+            flags |= Flags::PF_R.0;
+        }
+        if prot & VM_PROT_WRITE != 0 {
+            flags |= Flags::PF_W.0;
+            section_flags |= SectionFlags::SHF_WRITE.0;
+        }
+        if prot & VM_PROT_EXECUTE != 0 {
+            flags |= Flags::PF_X.0;
+            section_flags |= SectionFlags::SHF_EXECINSTR.0;
+        }
+
+        let file_size = segment.filesize.get().min(segment.vmsize.get());
+        let phdr = Phdr::synthetic(
+            Flags(flags),
+            segment.fileoff.get(),
+            segment.vmaddr.get(),
+            file_size,
+            segment.vmsize.get(),
+        );
+        image.phdrs.push((i, phdr));
+
+        let sections = &data[size_of::<SegmentCommand64>()..];
+        for s in sections
+            .chunks_exact(size_of::<Section64>())
+            .take(segment.nsects.get() as usize)
+        {
+            let section = Section64::read_from(s).unwrap();
+            image.shdrs.push(Shdr::synthetic(
+                format!("{},{}", name(&section.segname), name(&section.sectname)),
+                SectionFlags(section_flags),
+                section.addr.get(),
+                section.size.get(),
+            ));
+        }
+    }
+
+    if let Some(offset) = entry_offset {
+        let Some(phdr) = (image.phdrs.iter().map(|(_, p)| p)).find(|p| {
+            (p.file_offset()..p.file_offset().saturating_add(p.file_size())).contains(&offset)
+        }) else {
+            bail!("Entry point at file offset {offset:#x} is not in any segment")
+        };
+        image.entry = Some(phdr.address().wrapping_add(offset - phdr.file_offset()));
+    }
+
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn states(states: &[(u32, &[u64])]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for &(flavor, registers) in states {
+            data.extend(flavor.to_le_bytes());
+            data.extend((registers.len() as u32 * 2).to_le_bytes());
+            for r in registers {
+                data.extend(r.to_le_bytes());
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn thread_pc() {
+        let mut x86 = [0; 21];
+        x86[X86_RIP] = 0x100000400;
+        let mut arm = [0; 34];
+        arm[ARM_PC] = 0x100000800;
+        let data = states(&[(ARM_THREAD_STATE64, &arm), (X86_THREAD_STATE64, &x86)]);
+
+        assert_eq!(
+            super::thread_pc(&data, Machine::EM_X86_64),
+            Some(0x100000400)
+        );
+        assert_eq!(
+            super::thread_pc(&data, Machine::EM_AARCH64),
+            Some(0x100000800)
+        );
+        assert_eq!(super::thread_pc(&data, Machine::EM_386), None);
+        assert_eq!(
+            super::thread_pc(&data[..data.len() - 8], Machine::EM_X86_64),
+            None
+        );
+    }
+}
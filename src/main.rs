@@ -1,155 +1,1513 @@
 use std::{
-    ffi::OsString,
-    fs::File,
-    io::{Read, Seek, SeekFrom},
+    env,
+    ffi::{OsStr, OsString},
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, IsTerminal, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
 };
 
-use anyhow::bail;
-use clap::Parser;
+use anyhow::{bail, Context};
+use clap::{Args as _, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use clap_num::maybe_hex;
-use elf::Phdr;
+use hash::Sha256Writer;
+use image::{Chunk, Data, Image};
+use layout::LayoutArgs;
+use output::AtomicFile;
+use timings::Timings;
 
+mod argsfile;
+mod bench;
+mod completions;
+mod compress;
+mod config;
+mod consts;
+mod container;
+mod delta;
+mod devicetree;
+mod diag;
+mod diff;
 mod elf;
+mod encrypt;
+mod explain;
+#[cfg(feature = "flash")]
+mod flash;
+mod hash;
+mod ihex;
+mod image;
+mod info;
+mod input;
+mod input_image;
+mod layout;
+mod ldscript;
+mod log;
+mod macho;
+mod mangen;
+mod manifest;
+mod metadata;
+mod mmap;
+mod output;
+mod patch;
+mod pe;
+mod preview;
+mod progress;
+mod regions;
+mod reloc;
+mod report;
+mod scripts;
+mod select;
+mod serve;
+mod slots;
+mod srec;
+mod tempfile;
+mod template;
+mod timings;
+mod upload;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+mod verify;
+mod wrap;
 
 /// elfcopyflat: Copy loadable segments in an ELF file to a flat binary
 #[derive(Debug, Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// Only copy segments with these flags (among "rwx")
-    #[arg(long, value_name = "FLAGS", value_parser=parse_flags)]
-    if_: Option<u32>,
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    /// Only copy segments without these flags (among "rwx")
-    #[arg(long, value_name = "FLAGS", value_parser=parse_flags)]
-    if_not: Option<u32>,
+    #[command(flatten)]
+    copy: CopyArgs,
 
-    /// Address to start flat binary at (Defaults to lowest address among segments)
-    #[arg(long, value_name = "ADDRESS", value_parser=maybe_hex::<u64>)]
-    base: Option<u64>,
+    /// Print more information (Give twice for even more)
+    #[arg(long, short, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
 
-    /// Allow empty output file
+    /// Only print errors
+    #[arg(long, short, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+}
+
+/// Output formats, named as in objcopy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputTarget {
+    /// Flat binary
+    Binary,
+    /// Intel HEX
+    Ihex,
+    /// Motorola S-records
+    Srec,
+}
+
+/// Ways of writing the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum IoBackend {
+    /// Reads and writes one at a time
+    Std,
+    /// Linux io_uring, with several writes in flight at once (Needs the
+    /// io-uring feature)
+    Uring,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Time converting generated input files with different ways of writing
+    #[command(hide = true)]
+    Bench(bench::BenchArgs),
+    /// Print a shell completion script
+    Completions(completions::CompletionsArgs),
+    /// Copy segments to a flat binary (Same as without a subcommand)
+    Copy(Box<CopyArgs>),
+    /// Generate a block-based patch from an old image to a new one
+    Delta(delta::DeltaArgs),
+    /// Show which bytes of an existing flat image differ from what input
+    /// files would be copied to now
+    Diff(diff::DiffArgs),
+    /// Show which segments of an input file are copied, and why others are
+    /// not
+    Explain(explain::ExplainArgs),
+    /// Lay out input files and download them to a target through a debug
+    /// probe, with probe-rs (Needs the flash feature)
+    #[cfg(feature = "flash")]
+    Flash(flash::FlashArgs),
+    /// Print a man page in roff format, for packaging
+    #[command(hide = true)]
+    Mangen,
+    /// Show the ELF header, program headers and sections of an input file
+    Info(info::InfoArgs),
+    /// Write segments over an existing flat image in place
+    Patch(patch::PatchArgs),
+    /// Serve the image over TFTP or HTTP, converting input files again
+    /// whenever they change
+    Serve(serve::ServeArgs),
+    /// Lay out input files and send the image to a bootloader on a serial
+    /// port with XMODEM or YMODEM
+    Upload(upload::UploadArgs),
+    /// Check that a flat image matches what input files would be copied to
+    Verify(verify::VerifyArgs),
+    /// Wrap a flat binary in an ELF file with one loadable segment
+    Wrap(wrap::WrapArgs),
+}
+
+#[derive(Debug, clap::Args)]
+struct CopyArgs {
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// Write the file contents of the program header selected by --segment,
+    /// of any type, as is without laying out an image
+    #[arg(long, requires = "segment", conflicts_with = "onto")]
+    raw: bool,
+
+    /// With --raw, pad the output with zeros to the memory size of the segment
+    #[arg(long, requires = "raw")]
+    raw_memsz: bool,
+
+    /// Existing flat image to write segments over, keeping its other contents
+    /// (Usually used with --base set to the start address of the image)
+    #[arg(long, value_name = "IMAGE")]
+    onto: Option<OsString>,
+
+    /// Write out gaps in the image, instead of leaving them as holes in a
+    /// sparse file
+    #[arg(long)]
+    dense: bool,
+
+    /// If the output exists, only write the parts of it that changed, in
+    /// place, leaving the rest alone
+    #[arg(long, conflicts_with_all = ["compress", "compress_segments", "encrypt", "onto"])]
+    incremental: bool,
+
+    /// Leave blocks of zeros as holes in a sparse file, even within segments
+    #[arg(long, conflicts_with_all = ["dense", "gap_fill"])]
+    sparse: bool,
+
+    /// Make sure that the output is the same byte for byte on every run and
+    /// platform, failing if an option would make it differ (Implies --dense)
     #[arg(long)]
-    allow_empty: bool,
+    deterministic: bool,
+
+    /// How to write the output
+    #[arg(long, value_name = "BACKEND", default_value = "std")]
+    io_backend: IoBackend,
 
-    /// Allow overlapping segments
+    /// Fill gaps in the image with this byte instead of zeros (Implies
+    /// --dense)
+    #[arg(long, value_name = "BYTE", value_parser = maybe_hex::<u8>, env = "ELFCOPYFLAT_GAP_FILL")]
+    gap_fill: Option<u8>,
+
+    /// Pad the image up to this address, with the --gap-fill byte or zeros
+    #[arg(long, value_name = "ADDRESS", value_parser = maybe_hex::<u64>, conflicts_with = "compress_segments")]
+    pad_to: Option<u64>,
+
+    /// Output format, named as in objcopy
+    #[arg(short = 'O', long, value_name = "BFDNAME", conflicts_with_all = ["compress_segments", "incremental", "sparse"])]
+    output_target: Option<OutputTarget>,
+
+    /// Start address for Intel HEX and S-record output, instead of the entry
+    /// point
+    #[arg(long, value_name = "ADDRESS", value_parser = maybe_hex::<u64>, requires = "output_target")]
+    set_start: Option<u64>,
+
+    /// Ignored, for compatibility with objcopy, which only copies symbols
+    /// and relocations to ELF output
+    #[arg(short = 'S', long)]
+    strip_all: bool,
+
+    /// Leave this many bytes at the start of the image for a header, filled
+    /// like gaps, and move everything else forward
+    #[arg(long, value_name = "SIZE", value_parser = layout::parse_size, conflicts_with_all = ["onto", "compress_segments"])]
+    reserve_header: Option<u64>,
+
+    /// Append a file after the image, optionally aligned (e.g. "board.dtb:align=8")
+    #[arg(long, value_name = "FILE[:align=N]", value_parser = parse_append)]
+    append: Vec<Append>,
+
+    /// Append a trailer with the version of elfcopyflat, the build ID of the
+    /// first input, and a hash of the options, to trace the image back to
+    /// where it came from
+    #[arg(long, conflicts_with = "compress_segments")]
+    embed_metadata: bool,
+
+    /// Also put the time in the trailer, from SOURCE_DATE_EPOCH if set
+    #[arg(long, requires = "embed_metadata")]
+    metadata_timestamp: bool,
+
+    /// Instead of a flat binary, write each segment compressed separately,
+    /// preceded by an index of addresses and sizes
+    #[arg(long, value_name = "ALGORITHM", conflicts_with_all = ["onto", "append", "compress"])]
+    compress_segments: Option<compress::Compression>,
+
+    /// Compress the flat binary
+    #[arg(long, value_name = "ALGORITHM")]
+    compress: Option<compress::Compression>,
+
+    /// Size of the buffer for writing the output, e.g. larger for slow
+    /// network filesystems or SD card readers
+    #[arg(long, value_name = "SIZE", value_parser = layout::parse_size, default_value = "64K")]
+    buffer_size: u64,
+
+    /// Fail if the image, including appended files, is larger than this
+    /// (e.g. "0x40000" or "256K")
+    #[arg(long, value_name = "SIZE", value_parser = layout::parse_size, env = "ELFCOPYFLAT_MAX_SIZE")]
+    max_size: Option<u64>,
+
+    /// Print how much of this memory region the image uses, and fail if it
+    /// overflows, e.g. "FLASH=0x8000000,256K" (Can be given multiple times)
+    #[arg(long, value_name = "NAME=ORIGIN,LENGTH", value_parser = regions::parse_region)]
+    region: Vec<regions::Region>,
+
+    #[command(flatten)]
+    slots: slots::SlotArgs,
+
+    /// Encrypt the output with AES-GCM, using a 128 or 256 bit key from this
+    /// file (Applied after compression)
+    #[arg(long, value_name = "KEYFILE")]
+    encrypt: Option<OsString>,
+
+    /// Read the output back after writing it, and check that it has what was
+    /// written
     #[arg(long)]
-    allow_overlaps: bool,
+    verify: bool,
 
-    /// Print more information
+    /// Print the number of segments, how much of the image is contents and
+    /// how much is padding, and the largest gap
+    #[arg(long)]
+    stats: bool,
+
+    /// Print how long reading the input, checking the layout, copying each
+    /// segment and verifying took, with throughput
+    #[arg(long)]
+    timings: bool,
+
+    /// Print a hexdump of the first and last N bytes of the image, and around
+    /// the start and end of each segment, to standard error
+    #[arg(long, value_name = "N", num_args = 0..=1, require_equals = true, default_missing_value = "64", value_parser = maybe_hex::<u64>)]
+    preview: Option<u64>,
+
+    /// Lay out the image and check it as usual, and print what would be
+    /// written, without writing anything
+    #[arg(long, conflicts_with = "verify")]
+    dry_run: bool,
+
+    /// Overwrite the output file if it exists
     #[arg(long, short)]
-    verbose: bool,
+    force: bool,
+
+    /// Before overwriting the output file, keep a copy of it with ".bak"
+    /// added to its name
+    #[arg(long)]
+    backup: bool,
+
+    /// Write SHA-256 hashes, sizes and addresses of segments and the output
+    /// to this JSON file
+    #[arg(long, value_name = "FILE")]
+    manifest: Option<OsString>,
+
+    /// Write the ranges to be signed, their SHA-256 hashes, version fields and
+    /// empty signature fields to this JSON file, for a signer to fill in
+    #[arg(long, value_name = "FILE")]
+    signing_manifest: Option<OsString>,
+
+    /// Version of the image to put in the signing manifest
+    #[arg(long, requires = "signing_manifest")]
+    image_version: Option<String>,
+
+    /// Anti-rollback counter to put in the signing manifest
+    #[arg(long, value_name = "N", requires = "signing_manifest")]
+    security_version: Option<u32>,
+
+    /// Write where each segment was copied from and to, the base address,
+    /// the image size, and warnings to this JSON file (`-` for standard
+    /// output)
+    #[arg(long, value_name = "FILE")]
+    report_json: Option<OsString>,
+
+    /// Write a linker script with a MEMORY region for each segment and
+    /// appended file, and symbols for the bounds of the image, to this file
+    #[arg(long, value_name = "FILE")]
+    emit_ldscript: Option<OsString>,
+
+    /// Write a device tree overlay with a /reserved-memory node for each
+    /// segment and appended file, to this file
+    #[arg(long, value_name = "FILE")]
+    emit_dt_reserved: Option<OsString>,
+
+    /// Number of cells for addresses and sizes in the device tree overlay, as
+    /// #address-cells and #size-cells of /reserved-memory
+    #[arg(long, value_name = "N", default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..=2), requires = "emit_dt_reserved")]
+    dt_cells: u32,
+
+    /// Mark the reserved memory as no-map in the device tree overlay
+    #[arg(long, requires = "emit_dt_reserved")]
+    dt_no_map: bool,
+
+    /// Write a C header defining the base address, image size, entry point,
+    /// and where each segment is, to this file
+    #[arg(long, value_name = "FILE")]
+    emit_c_header: Option<OsString>,
+
+    /// Write a Rust module with the same constants as `--emit-c-header`, to
+    /// this file
+    #[arg(long, value_name = "FILE")]
+    emit_rust: Option<OsString>,
+
+    /// Also define an array of `#[repr(C)]` segment descriptors in the Rust
+    /// module
+    #[arg(long, requires = "emit_rust")]
+    rust_segment_table: bool,
+
+    /// Write a GDB script restoring the image into the memory of a target at
+    /// its base address, loading symbols from the input files, and setting
+    /// the PC to the entry point, to this file
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["compress", "compress_segments", "encrypt"])]
+    emit_gdb: Option<OsString>,
+
+    /// Print QEMU arguments loading the image at its base address with the
+    /// generic loader, and starting at the entry point
+    #[arg(long, conflicts_with_all = ["compress", "compress_segments", "encrypt"])]
+    emit_qemu_args: bool,
+
+    /// Write an OpenOCD script programming the image into flash at its base
+    /// address, to this file
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["compress", "compress_segments", "encrypt"])]
+    emit_openocd: Option<OsString>,
+
+    /// Write a Renode script loading the image onto the system bus at its
+    /// base address, and setting the PC to the entry point, to this file
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["compress", "compress_segments", "encrypt"])]
+    emit_renode: Option<OsString>,
+
+    /// CPU to set the PC of in the Renode script
+    #[arg(
+        long,
+        value_name = "NAME",
+        default_value = "sysbus.cpu",
+        requires = "emit_renode"
+    )]
+    renode_cpu: String,
+
+    /// Prefix of names in the C header and the --emit-entry file
+    #[arg(long, value_name = "PREFIX", value_parser = parse_prefix, default_value = "IMAGE")]
+    const_prefix: String,
+
+    /// Print the address of the entry point
+    #[arg(long)]
+    print_entry: bool,
+
+    /// Write the address of the entry point, and its offset from the base
+    /// address, as NAME=VALUE lines to this file
+    #[arg(long, value_name = "FILE")]
+    emit_entry: Option<OsString>,
+
+    /// Produce each target described in this config file, instead of one
+    /// output given on the command line (Options for targets are taken from
+    /// the file)
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["input", "output"])]
+    config: Option<OsString>,
+
+    /// Options that affect the image, in a canonical form, for
+    /// `--embed-metadata`
+    #[arg(skip)]
+    image_options: Vec<String>,
+
+    /// Input ELF, PE or Mach-O files, which may be compressed with gzip or
+    /// Zstandard (`-` for standard input, ARCHIVE:MEMBER for a member of an ar
+    /// archive)
+    #[arg(required = true)]
+    input: Vec<OsString>,
+
+    /// Output flat binary (`{buildid}` or e.g. `{buildid8}` is replaced by
+    /// the build ID of the first input file, or its first 8 digits)
+    #[arg(required = true)]
+    output: Option<OsString>,
+}
 
-    /// Input ELF file
-    input: OsString,
+#[derive(Debug, Clone)]
+struct Append {
+    path: OsString,
+    align: u64,
+}
+
+fn parse_append(s: &str) -> Result<Append, String> {
+    let (path, align) = match s.rsplit_once(":align=") {
+        Some((path, align)) => (path, maybe_hex::<u64>(align)?),
+        None => (s, 1),
+    };
 
-    /// Output flat binary
-    output: OsString,
+    if align == 0 {
+        return Err("Alignment must be non-zero".to_owned());
+    }
+
+    Ok(Append {
+        path: path.into(),
+        align,
+    })
 }
 
-fn parse_flags(s: &str) -> Result<u32, String> {
-    let mut flags = 0;
-    for c in s.chars() {
-        let val = match c {
-            'r' | 'R' => elf::Flags::PF_R.0,
-            'w' | 'W' => elf::Flags::PF_W.0,
-            'x' | 'X' => elf::Flags::PF_X.0,
-            _ => return Err(format!("Unknown flag '{c}'")),
+fn parse_prefix(s: &str) -> Result<String, String> {
+    let valid = s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    match valid {
+        true => Ok(s.to_owned()),
+        false => Err("Prefix should be a valid identifier".to_owned()),
+    }
+}
+
+fn same_file(a: &OsString, b: &OsString) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Check that the SHA-256 of a written file is `expected`
+fn check_written(path: &Path, expected: &str) -> anyhow::Result<()> {
+    let actual = hash::sha256(File::open(path)?)?;
+    if actual != expected {
+        bail!("Output file does not have what was written to it (SHA-256 {actual}, expected {expected})")
+    }
+    Ok(())
+}
+
+/// Names, offsets and sizes of segments and appended files in the image
+fn contents(
+    segments: &[layout::Segment],
+    base: u64,
+    args: &CopyArgs,
+    appended: &[(u64, u64)],
+) -> Vec<(String, u64, u64)> {
+    let header = args.reserve_header.unwrap_or(0);
+    let segments = segments.iter().map(|s| {
+        let offset = s.phdr.address() - base + header;
+        let name = match args.input.len() {
+            1 => format!("Segment #{}", s.index),
+            _ => format!("Segment #{} in {:?}", s.index, args.input[s.input]),
         };
+        (name, offset, s.phdr.file_size())
+    });
+    let header = (args.reserve_header).map(|size| ("Reserved header".to_owned(), 0, size));
+    let appended = (args.append.iter().zip(appended))
+        .map(|(a, &(offset, size))| (format!("Appended {:?}", a.path), offset, size));
+    header.into_iter().chain(segments).chain(appended).collect()
+}
 
-        if flags & val != 0 {
-            return Err(format!("Duplicate flag '{}'", c.to_ascii_lowercase()));
-        } else {
-            flags |= val;
+/// Check that nothing else prints to standard output when the report is
+/// written there, so that it stays valid JSON
+fn check_report_stdout(args: &CopyArgs) -> anyhow::Result<()> {
+    if args.report_json.as_deref() != Some(OsStr::new("-")) {
+        return Ok(());
+    }
+    let printing = [
+        (args.stats, "--stats"),
+        (args.print_entry, "--print-entry"),
+        (args.emit_qemu_args, "--emit-qemu-args"),
+        (args.dry_run, "--dry-run"),
+        (args.timings, "--timings"),
+        (!args.region.is_empty(), "--region"),
+    ];
+    if let Some((_, flag)) = printing.iter().find(|(set, _)| *set) {
+        bail!("--report-json - cannot be used with {flag}, which also prints to standard output (Write the report to a file)")
+    }
+    Ok(())
+}
+
+/// Check that no option makes the output differ between runs, for
+/// `--deterministic`
+fn check_deterministic(args: &CopyArgs) -> anyhow::Result<()> {
+    if args.sparse {
+        bail!("--sparse leaves holes depending on the filesystem, which --deterministic does not allow")
+    }
+    if args.encrypt.is_some() {
+        bail!("--encrypt uses a random nonce each time, which --deterministic does not allow")
+    }
+    if args.metadata_timestamp && env::var_os("SOURCE_DATE_EPOCH").is_none() {
+        bail!(
+            "--metadata-timestamp puts the current time in the image, which --deterministic does \
+            not allow (Set SOURCE_DATE_EPOCH to the time to use)"
+        )
+    }
+    Ok(())
+}
+
+/// Write the `--manifest` and `--signing-manifest` files, if asked for
+fn write_manifests(
+    output: &OsStr,
+    base: u64,
+    segments: Vec<manifest::SegmentEntry>,
+    args: &CopyArgs,
+) -> anyhow::Result<()> {
+    if let Some(path) = &args.signing_manifest {
+        let versions = manifest::Versions {
+            version: args.image_version.clone(),
+            security_version: args.security_version,
+        };
+        // The reserved header is left out, for the signature to go in
+        let header = args.reserve_header.unwrap_or(0);
+        manifest::write_signing(path, output, base, header, segments.clone(), versions)?;
+    }
+    if let Some(path) = &args.manifest {
+        manifest::write(path, output, base, segments)?;
+    }
+    Ok(())
+}
+
+/// Offset of the entry point in the image, if it is in the image
+fn entry_offset(entry: Option<u64>, base: u64, args: &CopyArgs) -> Option<u64> {
+    let header = args.reserve_header.unwrap_or(0);
+    entry
+        .and_then(|entry| entry.checked_sub(base))
+        .map(|offset| offset + header)
+}
+
+/// Address of the start of the image, below the base address by the size of
+/// the reserved header, for `what`, which needs the header to have an address
+fn image_address(base: u64, args: &CopyArgs, what: &str) -> anyhow::Result<u64> {
+    let header = args.reserve_header.unwrap_or(0);
+    let Some(address) = base.checked_sub(header) else {
+        bail!("{what} needs an address for the reserved header, but its {header:#x} bytes do not fit below base address {base:#x}")
+    };
+    Ok(address)
+}
+
+/// Print the entry point for `--print-entry`, and write the `--emit-entry`
+/// file, if asked for
+fn write_entry(base: u64, entry: Option<u64>, args: &CopyArgs) -> anyhow::Result<()> {
+    if !args.print_entry && args.emit_entry.is_none() {
+        return Ok(());
+    }
+    let Some(entry) = entry else {
+        bail!("The first input file has no entry point")
+    };
+    if args.print_entry {
+        println!("{entry:#x}");
+    }
+    if let Some(path) = &args.emit_entry {
+        let prefix = &args.const_prefix;
+        let mut text = format!("{prefix}_ENTRY={entry:#x}\n");
+        if let Some(offset) = entry_offset(Some(entry), base, args) {
+            text.push_str(&format!("{prefix}_ENTRY_OFFSET={offset:#x}\n"));
         }
+        fs::write(path, text).with_context(|| format!("Cannot write entry point to {path:?}"))?;
     }
-    Ok(flags)
+    Ok(())
 }
 
-fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
-    let mut input_file = File::open(&args.input)?;
-
-    let ehdr = elf::Ehdr::read(&mut input_file)?;
-    let mut phdr_bytes: Vec<u8> = vec![0; ehdr.ph_size()];
-    input_file.seek(SeekFrom::Start(ehdr.ph_offset()))?;
-    input_file.read_exact(&mut phdr_bytes)?;
-
-    let mut phdrs: Vec<elf::Phdr> = phdr_bytes
-        .chunks_exact(ehdr.ph_entry_size())
-        .map(|b| Phdr::from_bytes(b, &ehdr))
-        .filter(|phdr| {
-            phdr.to_type() == elf::Type::PT_LOAD
-                && phdr.flags().0 & args.if_.unwrap_or(!0) != 0
-                && phdr.flags().0 & args.if_not.unwrap_or(0) == 0
+/// Write the report, linker script, device tree overlay, entry point and
+/// constants, whichever are asked for
+fn write_side_outputs(
+    layout: &layout::Layout,
+    args: &CopyArgs,
+    appended: &[(u64, u64)],
+) -> anyhow::Result<()> {
+    let (segments, base, entry, image) =
+        (&layout.segments, layout.base, layout.entry, &layout.image);
+    write_report(segments, base, entry, image, args, appended)?;
+    write_ldscript(segments, base, entry, image, args, appended)?;
+    write_dt_reserved(segments, base, args, appended)?;
+    write_entry(base, entry, args)?;
+    write_consts(segments, base, entry, image, args)
+}
+
+/// Write the `--report-json` report, if asked for
+fn write_report(
+    segments: &[layout::Segment],
+    base: u64,
+    entry: Option<u64>,
+    image: &Image,
+    args: &CopyArgs,
+    appended: &[(u64, u64)],
+) -> anyhow::Result<()> {
+    let Some(path) = &args.report_json else {
+        return Ok(());
+    };
+    let header = args.reserve_header.unwrap_or(0);
+    let mut report = report::Report::new(&args.input, segments, base, header, image.size());
+    report.appended = (args.append.iter().zip(appended))
+        .map(|(a, &(offset, size))| report::AppendedEntry {
+            path: a.path.to_string_lossy().into_owned(),
+            offset,
+            size,
+        })
+        .collect();
+    report.entry = entry;
+    report.entry_offset = entry_offset(entry, base, args);
+    report.inputs = (args.input.iter().zip(&image.files))
+        .map(|(path, file)| report::InputEntry {
+            path: path.to_string_lossy().into_owned(),
+            // PE and Mach-O files have no GNU property notes
+            security_features: elf::read_security_features(file).unwrap_or_default(),
+        })
+        .collect();
+    report.write(path)
+}
+
+/// Name of a segment for use in symbols, as in `SEGMENT0`
+fn segment_symbol(s: &layout::Segment, args: &CopyArgs) -> String {
+    match args.input.len() {
+        1 => format!("SEGMENT{}", s.index),
+        _ => format!("INPUT{}_SEGMENT{}", s.input, s.index),
+    }
+}
+
+/// Write the `--emit-ldscript` linker script, if asked for
+fn write_ldscript(
+    segments: &[layout::Segment],
+    base: u64,
+    entry: Option<u64>,
+    image: &Image,
+    args: &CopyArgs,
+    appended: &[(u64, u64)],
+) -> anyhow::Result<()> {
+    let Some(path) = &args.emit_ldscript else {
+        return Ok(());
+    };
+    let start = image_address(base, args, "--emit-ldscript")?;
+    let header = (args.reserve_header).map(|size| ldscript::Entry {
+        name: "HEADER".to_owned(),
+        address: start,
+        size,
+        attributes: "r".to_owned(),
+    });
+    let segments = segments.iter().map(|s| ldscript::Entry {
+        name: segment_symbol(s, args),
+        address: s.phdr.address(),
+        size: s.phdr.file_size(),
+        attributes: s.phdr.flags().to_string().replace('-', ""),
+    });
+    let appended = appended
+        .iter()
+        .enumerate()
+        .map(|(i, &(offset, size))| ldscript::Entry {
+            name: format!("APPENDED{i}"),
+            address: start + offset,
+            size,
+            attributes: "r".to_owned(),
+        });
+    let entries: Vec<_> = header.into_iter().chain(segments).chain(appended).collect();
+    ldscript::write(path, &entries, start, entry, image.size())
+        .with_context(|| format!("Cannot write linker script {path:?}"))
+}
+
+/// Write the `--emit-dt-reserved` device tree overlay, if asked for
+fn write_dt_reserved(
+    segments: &[layout::Segment],
+    base: u64,
+    args: &CopyArgs,
+    appended: &[(u64, u64)],
+) -> anyhow::Result<()> {
+    let Some(path) = &args.emit_dt_reserved else {
+        return Ok(());
+    };
+    let start = image_address(base, args, "--emit-dt-reserved")?;
+    let header = (args.reserve_header).map(|size| devicetree::Range {
+        name: "header".to_owned(),
+        address: start,
+        size,
+    });
+    // Memory sizes, to also keep zero-initialized data
+    let segments = segments.iter().map(|s| devicetree::Range {
+        name: segment_symbol(s, args).to_lowercase().replace('_', "-"),
+        address: s.phdr.address(),
+        size: s.phdr.memory_size(),
+    });
+    let appended = appended
+        .iter()
+        .enumerate()
+        .map(|(i, &(offset, size))| devicetree::Range {
+            name: format!("appended{i}"),
+            address: start + offset,
+            size,
+        });
+    let ranges: Vec<_> = header.into_iter().chain(segments).chain(appended).collect();
+    devicetree::write(path, &ranges, args.dt_cells, args.dt_no_map)
+        .with_context(|| format!("Cannot write device tree overlay {path:?}"))
+}
+
+/// Write the `--emit-c-header` header and `--emit-rust` module, if asked for
+fn write_consts(
+    segments: &[layout::Segment],
+    base: u64,
+    entry: Option<u64>,
+    image: &Image,
+    args: &CopyArgs,
+) -> anyhow::Result<()> {
+    if args.emit_c_header.is_none() && args.emit_rust.is_none() {
+        return Ok(());
+    }
+    let header = args.reserve_header.unwrap_or(0);
+    let consts = consts::Constants {
+        prefix: args.const_prefix.clone(),
+        base,
+        header,
+        size: image.size(),
+        entry,
+        segments: (segments.iter())
+            .map(|s| consts::Segment {
+                name: segment_symbol(s, args),
+                address: s.phdr.address(),
+                offset: s.phdr.address() - base + header,
+                size: s.phdr.file_size(),
+                memory_size: s.phdr.memory_size(),
+            })
+            .collect(),
+    };
+    if let Some(path) = &args.emit_c_header {
+        (consts.write_c(path)).with_context(|| format!("Cannot write C header {path:?}"))?;
+    }
+    if let Some(path) = &args.emit_rust {
+        (consts.write_rust(path, args.rust_segment_table))
+            .with_context(|| format!("Cannot write Rust module {path:?}"))?;
+    }
+    Ok(())
+}
+
+/// Write the `--emit-gdb`, `--emit-openocd` and `--emit-renode` scripts and
+/// print `--emit-qemu-args`, if asked for
+fn write_scripts(
+    placements: &[layout::Placement],
+    base: u64,
+    entry: Option<u64>,
+    args: &CopyArgs,
+    output: &OsString,
+) -> anyhow::Result<()> {
+    if args.emit_gdb.is_none()
+        && args.emit_openocd.is_none()
+        && args.emit_renode.is_none()
+        && !args.emit_qemu_args
+    {
+        return Ok(());
+    }
+    let loaded = scripts::Loaded {
+        image: scripts::absolute(output),
+        base: image_address(base, args, "Loading the image in scripts")?,
+        entry,
+        inputs: (args.input.iter())
+            .map(|path| scripts::absolute(path))
+            .zip(placements.iter().cloned())
+            .collect(),
+    };
+    if let Some(path) = &args.emit_gdb {
+        (loaded.write_gdb(path)).with_context(|| format!("Cannot write GDB script {path:?}"))?;
+    }
+    if let Some(path) = &args.emit_openocd {
+        (loaded.write_openocd(path))
+            .with_context(|| format!("Cannot write OpenOCD script {path:?}"))?;
+    }
+    if let Some(path) = &args.emit_renode {
+        (loaded.write_renode(path, &args.renode_cpu))
+            .with_context(|| format!("Cannot write Renode script {path:?}"))?;
+    }
+    if args.emit_qemu_args {
+        println!("{}", loaded.qemu_args());
+    }
+    Ok(())
+}
+
+/// Print the `--stats` summary
+fn print_stats(segments: &[layout::Segment], base: u64, image: &Image) {
+    let (payload, largest_gap) = image.stats();
+    let size = image.size();
+    let padding = size - payload;
+    let percent = match size {
+        0 => 0.0,
+        _ => padding as f64 * 100.0 / size as f64,
+    };
+
+    println!("Segments: {}", segments.len());
+    println!("Contents: {payload:#x} bytes");
+    println!("Padding: {padding:#x} bytes ({percent:.1}%)");
+    match largest_gap {
+        Some(gap) => println!(
+            "Largest gap: {size:#x} bytes at offset {start:#x} (address {addr:#x})",
+            size = gap.end - gap.start,
+            start = gap.start,
+            addr = base.wrapping_add(gap.start),
+        ),
+        None => println!("Largest gap: None"),
+    }
+    println!("Image size: {size:#x} bytes");
+}
+
+/// Write only the parts of the image that differ from what the output at
+/// `path` already has
+fn write_changed(
+    image: &mut Image,
+    path: &Path,
+    output: &mut File,
+    buffer_size: usize,
+) -> anyhow::Result<()> {
+    // Writing a little more beats a write for each changed byte
+    const MERGE_DISTANCE: u64 = 4096;
+
+    let size = image.size();
+    let mut changed: Vec<Range<u64>> = Vec::new();
+    for range in verify::compare(image, File::open(path)?, verify::Gaps::Fill(0))? {
+        let range = range.start.min(size)..range.end.min(size);
+        match changed.last_mut() {
+            _ if range.is_empty() => {}
+            Some(last) if range.start - last.end < MERGE_DISTANCE => last.end = range.end,
+            _ => changed.push(range),
+        }
+    }
+
+    let mut written = 0;
+    for range in &changed {
+        let mut pos = range.start;
+        while pos < range.end {
+            let end = range.end.min(pos.saturating_add(buffer_size.max(1) as u64));
+            let data = image.read_range(pos..end)?;
+            output.seek(SeekFrom::Start(pos))?;
+            output.write_all(&data)?;
+            pos = end;
+        }
+        written += range.end - range.start;
+    }
+    output.set_len(size)?;
+
+    log::info!(
+        "Wrote {count} changed ranges, {written:#x} of {size:#x} bytes",
+        count = changed.len(),
+    );
+    Ok(())
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn write_uring(image: &mut Image, output: &File, buffer_size: usize) -> io::Result<()> {
+    uring::write(image, output, buffer_size)
+}
+
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+fn write_uring(_image: &mut Image, _output: &File, _buffer_size: usize) -> io::Result<()> {
+    unreachable!("io_uring is checked for before writing")
+}
+
+/// Print the `--timings` summary
+fn print_timings(
+    timings: &Timings,
+    segments: &[layout::Segment],
+    base: u64,
+    image: &Image,
+    args: &CopyArgs,
+    appended: &[(u64, u64)],
+) {
+    let chunk_times = image.chunk_times.as_deref().unwrap_or_default();
+    timings.print(chunk_times, &contents(segments, base, args, appended));
+}
+
+/// Print what `--dry-run` would have written
+fn print_plan(
+    segments: &[layout::Segment],
+    base: u64,
+    image: &Image,
+    args: &CopyArgs,
+    appended: &[(u64, u64)],
+    output: &OsString,
+) {
+    println!("Base address {base:#x}");
+    for (name, offset, size) in contents(segments, base, args, appended) {
+        println!(
+            "  {name}: {offset:#x}..{end:#x} ({size:#x} bytes)",
+            end = offset + size,
+        );
+    }
+    println!("Image size {:#x}", image.size());
+    println!("Would write {output:?}");
+}
+
+/// Options that do not change the contents of the image, or only name files,
+/// left out of the hash of the options in `--embed-metadata`
+const NOT_IMAGE_OPTIONS: &[&str] = &[
+    "input",
+    "output",
+    "config",
+    "onto",
+    "encrypt",
+    "verbose",
+    "quiet",
+    "strict",
+    "allow",
+    "deny",
+    "io_backend",
+    "buffer_size",
+    "max_size",
+    "region",
+    "verify",
+    "stats",
+    "timings",
+    "preview",
+    "dry_run",
+    "force",
+    "backup",
+    "manifest",
+    "signing_manifest",
+    "image_version",
+    "security_version",
+    "report_json",
+    "emit_ldscript",
+    "emit_dt_reserved",
+    "dt_cells",
+    "dt_no_map",
+    "emit_c_header",
+    "emit_rust",
+    "rust_segment_table",
+    "emit_gdb",
+    "emit_qemu_args",
+    "emit_openocd",
+    "emit_renode",
+    "renode_cpu",
+    "const_prefix",
+    "print_entry",
+    "emit_entry",
+];
+
+/// Options given on the command line or in the environment that affect the
+/// image, as `name=value`, sorted by name so that their order does not matter
+///
+/// Appended files are given by alignment only, as paths to input files are
+/// left out.
+fn image_options(matches: &clap::ArgMatches) -> Vec<String> {
+    let mut ids: Vec<&str> = (matches.ids())
+        .map(|id| id.as_str())
+        .filter(|id| !NOT_IMAGE_OPTIONS.contains(id))
+        .filter(|id| {
+            matches
+                .value_source(id)
+                .is_some_and(|source| source != clap::parser::ValueSource::DefaultValue)
         })
         .collect();
+    ids.sort_unstable();
+
+    let mut res = Vec::new();
+    for id in ids {
+        if id == "append" {
+            let appended = matches.get_many::<Append>(id).into_iter().flatten();
+            res.extend(appended.map(|a| format!("append=align={:#x}", a.align)));
+        } else if let Ok(Some(values)) = matches.try_get_raw(id) {
+            res.extend(values.map(|v| format!("{id}={}", v.to_string_lossy())));
+        }
+    }
+    res
+}
+
+/// Parse options for copying, without the program name
+fn parse_copy_args(args: &[OsString]) -> Result<CopyArgs, clap::Error> {
+    let cmd = CopyArgs::augment_args(clap::Command::new("elfcopyflat").no_binary_name(true));
+    let matches = cmd.try_get_matches_from(args)?;
+    let mut copy_args = CopyArgs::from_arg_matches(&matches)?;
+    copy_args.image_options = image_options(&matches);
+    Ok(copy_args)
+}
+
+/// Produce each target in a config file
+fn copy_config(path: &OsString) -> anyhow::Result<()> {
+    for target in config::targets(path)? {
+        log::info!("Producing target {:?}", target.name);
+        diag::take_emitted();
+
+        let args = parse_copy_args(&target.args)
+            .with_context(|| format!("Invalid target {:?} in {path:?}", target.name))?;
+        copy(args).with_context(|| format!("Cannot produce target {:?}", target.name))?;
+    }
+    Ok(())
+}
+
+/// Ask whether to overwrite an existing output file, if there is someone to
+/// ask
+fn confirm_overwrite(output: &OsString) -> anyhow::Result<()> {
+    let refuse = || -> anyhow::Result<()> {
+        bail!("Output file {output:?} already exists (Use --force to overwrite it, or --backup to keep a copy)")
+    };
+
+    if !io::stdin().is_terminal() {
+        return refuse();
+    }
+
+    eprint!("Overwrite {output:?}? [y/N] ");
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    match answer.trim() {
+        "y" | "Y" | "yes" => Ok(()),
+        _ => refuse(),
+    }
+}
+
+fn copy(args: CopyArgs) -> anyhow::Result<()> {
+    if let Some(config) = &args.config {
+        return copy_config(config);
+    }
+
+    check_report_stdout(&args)?;
+    if args.deterministic {
+        check_deterministic(&args)?;
+    }
+
+    let mut timings = Timings::new();
+    let output = args.output.clone().expect("Missing positional arguments");
+    let buffer_size = usize::try_from(args.buffer_size)?;
+    let mut layout = if args.raw {
+        if args.input.len() != 1 {
+            bail!("--raw needs exactly one input file")
+        }
+        let index = args.layout.segment.expect("--raw without --segment");
+        layout::raw_segment(&args.input[0], index, args.raw_memsz, &args.layout)?
+    } else {
+        layout::layout(&args.input, &args.layout)?
+    };
+    timings.step("Read input");
+    let output = template::expand(&output, &layout.image.files[0])?;
+
+    if let Some(size) = args.reserve_header {
+        layout.reserve_header(size, args.gap_fill.unwrap_or(0));
+    }
+
+    for path in args.input.iter().chain(args.append.iter().map(|a| &a.path)) {
+        if same_file(path, &output) {
+            bail!("Output file {output:?} is the same as input file {path:?}")
+        }
+    }
+
+    let in_place = args
+        .onto
+        .as_ref()
+        .is_some_and(|onto| same_file(onto, &output));
+
+    // Devices and such are written directly, as there is nothing to rename
+    let is_special = fs::metadata(&output).is_ok_and(|m| !m.is_file());
+    let exists = fs::metadata(&output).is_ok_and(|m| m.is_file());
+    let update = args.incremental && exists;
+    let atomic = match in_place || is_special || update {
+        true => None,
+        false => Some(AtomicFile::new(&output)),
+    };
+    let write_path = match &atomic {
+        Some(atomic) => atomic.temp_path().to_owned(),
+        None => PathBuf::from(&output),
+    };
+
+    if exists && !in_place && !update && !args.dry_run {
+        if args.backup {
+            let mut backup = output.clone();
+            backup.push(".bak");
+            fs::copy(&output, &backup)
+                .with_context(|| format!("Cannot back up {output:?} to {backup:?}"))?;
+            log::info!("Backed up {output:?} to {backup:?}");
+        } else if !args.force {
+            confirm_overwrite(&output)?;
+        }
+    }
+
+    // Intel HEX and S-records are written as text, which cannot be compressed
+    // or encrypted
+    let text_target = args
+        .output_target
+        .filter(|&target| target != OutputTarget::Binary);
+    if text_target.is_some() && (args.compress.is_some() || args.encrypt.is_some()) {
+        bail!("Intel HEX and S-record output cannot be compressed or encrypted")
+    }
+
+    let hash_segments = args.manifest.is_some() || args.signing_manifest.is_some();
+
+    if args.io_backend == IoBackend::Uring {
+        if !cfg!(all(feature = "io-uring", target_os = "linux")) {
+            bail!("This build does not support io_uring (Build with --features io-uring on Linux)")
+        }
+        if args.compress.is_some()
+            || args.compress_segments.is_some()
+            || args.encrypt.is_some()
+            || args.sparse
+            || args.incremental
+            || text_target.is_some()
+        {
+            bail!("--io-backend uring only writes uncompressed flat binaries without --sparse or --incremental")
+        }
+    }
 
-    phdrs.sort_by_key(|p| p.address());
-
-    if args.verbose {
-        eprintln!("Segments in file to copy:");
-        for p in &phdrs {
-            let r = if p.flags().readable() { "r" } else { "-" };
-            let w = if p.flags().writable() { "w" } else { "-" };
-            let x = if p.flags().executable() { "x" } else { "-" };
-            eprintln!(
-                "  {r}{w}{x} {offset:#x} + {filesz:#x} bytes in file, {addr:#x} + {memsz:#x} bytes in memory",
-                offset = p.file_offset(),
-                filesz = p.file_size(),
-                addr = p.address(),
-                memsz = p.memory_size(),
+    if let Some(compression) = args.compress_segments {
+        if args.dry_run {
+            print_plan(
+                &layout.segments,
+                layout.base,
+                &layout.image,
+                &args,
+                &[],
+                &output,
+            );
+            write_side_outputs(&layout, &args, &[])?;
+            return Ok(());
+        }
+        timings.step("Check");
+        if args.timings {
+            layout.image.chunk_times = Some(Vec::new());
+        }
+        if io::stderr().is_terminal() && log::enabled(log::Level::Warn) {
+            layout.image.show_progress();
+        }
+        let mut output_file = Sha256Writer::new(BufWriter::with_capacity(
+            buffer_size,
+            File::create(&write_path)?,
+        ));
+        let segment_hashes = match hash_segments {
+            true => manifest::segments(
+                &layout.segments,
+                layout.base,
+                layout.header,
+                &mut layout.image,
+            )?,
+            false => Vec::new(),
+        };
+        container::write(&mut layout, &mut output_file, compression)?;
+        layout.image.progress = None;
+        let (mut output_file, hash) = output_file.finish();
+        output_file.flush()?;
+        timings.step("Copy");
+        if args.verify {
+            check_written(&write_path, &hash)?;
+            timings.step("Verify");
+        }
+        if let Some(atomic) = atomic {
+            atomic.commit()?;
+        }
+        write_manifests(&output, layout.base, segment_hashes, &args)?;
+        write_side_outputs(&layout, &args, &[])?;
+        timings.step("Finish");
+        if args.timings {
+            print_timings(
+                &timings,
+                &layout.segments,
+                layout.base,
+                &layout.image,
+                &args,
+                &[],
             );
         }
+        return Ok(());
     }
 
-    let is_empty = phdrs.iter().all(|phdr| phdr.file_size() == 0);
+    let base = layout.base;
+    // Address of the start of the image, only for messages, as it wraps around
+    // if the reserved header does not fit below the base address
+    let image_start = base.wrapping_sub(layout.header);
+    let image = &mut layout.image;
 
-    if is_empty && !args.allow_empty {
-        bail!("Empty output file (Use --allow-empty to create one anyway)")
+    if in_place
+        && (args.compress.is_some()
+            || args.slots.ab_slot_size.is_some()
+            || args.encrypt.is_some()
+            || args.gap_fill.is_some()
+            || args.embed_metadata
+            || args.sparse
+            || text_target.is_some())
+    {
+        bail!("Cannot transform the image when writing over it in place (Use another output file)")
     }
 
-    let mut output_file = File::create(&args.output)?;
+    let key = args.encrypt.as_deref().map(encrypt::read_key).transpose()?;
 
-    let overlaps = phdrs
-        .iter()
-        .zip(phdrs.iter().skip(1))
-        .filter(|(pa, pb)| {
-            if pa.address() + pa.memory_size() > pb.address() {
-                eprintln!(
-                    "Segment at {start:#x} has size {size:#x}, which overlaps the next segment at {next:#x}",
-                    start = pa.address(),
-                    size = pa.memory_size(),
-                    next = pb.address(),
+    if let Some(onto) = &args.onto {
+        let onto_file = File::open(onto)?;
+        let size = onto_file.metadata()?.len();
+        if in_place {
+            // Patching in place, the contents are already there
+            image.min_size = size;
+        } else {
+            let file = image.add_file(onto_file);
+            image.push_under(Chunk {
+                offset: 0,
+                size,
+                data: Data::File { file, offset: 0 },
+            });
+        }
+    }
+
+    let mut appended = Vec::new();
+    for a in &args.append {
+        let offset = image.size().next_multiple_of(a.align);
+        let append_file = File::open(&a.path)?;
+        let size = append_file.metadata()?.len();
+        let file = image.add_file(append_file);
+        image.push(Chunk {
+            offset,
+            size,
+            data: Data::File { file, offset: 0 },
+        });
+        appended.push((offset, size));
+
+        log::info!(
+            "Appended {path:?} at offset {offset:#x} (address {addr:#x}), {size:#x} bytes",
+            path = a.path,
+            addr = base + (offset - layout.header),
+        );
+    }
+
+    if let Some(pad_to) = args.pad_to {
+        let Some(end) = pad_to.checked_sub(base) else {
+            bail!("Cannot pad to {pad_to:#x}, which is below the base address {base:#x}")
+        };
+        let end = end + layout.header;
+        image.min_size = image.min_size.max(end);
+    }
+
+    if let Some(byte) = args.gap_fill {
+        image.push_under(Chunk {
+            offset: 0,
+            size: image.size(),
+            data: Data::Fill { byte },
+        });
+    }
+
+    if args.embed_metadata {
+        // PE and Mach-O files have no GNU build ID
+        let build_id = elf::read_build_id(&image.files[0]).ok().flatten();
+        let metadata = metadata::Metadata {
+            build_id,
+            options: args.image_options.clone(),
+            timestamp: args
+                .metadata_timestamp
+                .then(metadata::timestamp)
+                .transpose()?,
+        };
+        let offset = image.size();
+        let trailer = metadata.trailer();
+        log::info!(
+            "Appended metadata at offset {offset:#x}, {size:#x} bytes",
+            size = trailer.len(),
+        );
+        image.push_bytes(offset, trailer);
+    }
+
+    if let Some(max_size) = args.max_size {
+        if image.size() > max_size {
+            log::error!("Contents of the image:");
+            for (name, offset, size) in contents(&layout.segments, base, &args, &appended) {
+                let over = match offset + size > max_size {
+                    true => ", over the limit",
+                    false => "",
+                };
+                log::error!(
+                    "  {name}: {offset:#x}..{end:#x} ({size:#x} bytes){over}",
+                    end = offset + size,
                 );
-                true
-            } else {
-                false
             }
-        })
-        .count();
+            bail!(
+                "Image size {size:#x} is larger than the maximum {max_size:#x}, by {excess:#x} bytes",
+                size = image.size(),
+                excess = image.size() - max_size,
+            )
+        }
+    }
+
+    if !args.region.is_empty() {
+        regions::check(&args.region, image, image_address(base, &args, "--region")?)?;
+    }
 
-    if overlaps > 0 && !args.allow_overlaps {
-        bail!("Overlapping segments (Use --allow-overlaps to use it anyway)")
+    // Once everything is in the image, before it is put in slots
+    let segment_hashes = match hash_segments {
+        true => manifest::segments(&layout.segments, base, layout.header, image)?,
+        false => Vec::new(),
+    };
+
+    if let Some(slot_size) = args.slots.ab_slot_size {
+        let start = image_address(base, &args, "--ab-slot-size")?;
+        *image = slots::ab_image(image, slot_size, &args.slots, start)?;
     }
 
-    let min_addr = phdrs.iter().map(|phdr| phdr.address()).min();
+    timings.step("Check");
 
-    if let Some((base, min_addr)) = args.base.zip(min_addr) {
-        if base > min_addr {
-            bail!("Segments start at {min_addr:#x}, which is less than specified base {base:#x}")
+    if args.dry_run {
+        print_plan(&layout.segments, base, image, &args, &appended, &output);
+        if args.stats {
+            print_stats(&layout.segments, image_start, image);
+        }
+        if let Some(size) = args.preview {
+            preview::print(image, &layout.segments, image_start, size)?;
+        }
+        write_side_outputs(&layout, &args, &appended)?;
+        write_scripts(&layout.placements, base, layout.entry, &args, &output)?;
+        if args.timings {
+            print_timings(
+                &timings,
+                &layout.segments,
+                base,
+                &layout.image,
+                &args,
+                &appended,
+            );
         }
+        return Ok(());
     }
 
-    let base = args.base.or(min_addr).unwrap_or(0);
+    if args.timings {
+        image.chunk_times = Some(Vec::new());
+    }
+    if io::stderr().is_terminal() && log::enabled(log::Level::Warn) {
+        image.show_progress();
+    }
 
-    if args.verbose {
-        eprintln!("Base address {base:#x}")
+    if let Some(key) = key {
+        let data = match args.compress {
+            Some(compression) => {
+                compression.write_stream(Vec::new(), |w| Ok(image.write_stream(w)?))?
+            }
+            None => image.read_to_vec()?,
+        };
+        let data = encrypt::encrypt(&key, data)?;
+        fs::write(&write_path, &data)?;
+        timings.step("Copy");
+        if args.verify {
+            check_written(&write_path, &hash::sha256(&data[..])?)?;
+            timings.step("Verify");
+        }
+    } else if let Some(target) = text_target {
+        let mut output_file = Sha256Writer::new(BufWriter::with_capacity(
+            buffer_size,
+            File::create(&write_path)?,
+        ));
+        let address = image_address(base, &args, "Intel HEX and S-record output")?;
+        let start = args.set_start.or(layout.entry);
+        match target {
+            OutputTarget::Ihex => ihex::write(image, address, start, &mut output_file)?,
+            _ => srec::write(image, address, start, &mut output_file)?,
+        }
+        let (mut output_file, hash) = output_file.finish();
+        output_file.flush()?;
+        timings.step("Copy");
+        if args.verify {
+            check_written(&write_path, &hash)?;
+            timings.step("Verify");
+        }
+    } else if let Some(compression) = args.compress {
+        let output_file = Sha256Writer::new(BufWriter::with_capacity(
+            buffer_size,
+            File::create(&write_path)?,
+        ));
+        let output_file = compression.write_stream(output_file, |w| Ok(image.write_stream(w)?))?;
+        let (mut output_file, hash) = output_file.finish();
+        output_file.flush()?;
+        timings.step("Copy");
+        if args.verify {
+            check_written(&write_path, &hash)?;
+            timings.step("Verify");
+        }
+    } else {
+        let mut output_file = match in_place || update {
+            true => OpenOptions::new().write(true).open(&write_path)?,
+            false => File::create(&write_path)?,
+        };
+        let dense = (args.dense || args.deterministic || args.gap_fill.is_some()) && !in_place;
+        if !in_place && !is_special && !update {
+            match args.sparse {
+                true => output_file.set_len(image.size()),
+                false => image.preallocate(&output_file, dense),
+            }
+            .with_context(|| format!("Cannot allocate {:#x} bytes for {output:?}", image.size()))?;
+        }
+        if update {
+            write_changed(image, &write_path, &mut output_file, buffer_size)?;
+        } else if args.io_backend == IoBackend::Uring {
+            if dense && args.gap_fill.is_none() {
+                image.push_under(Chunk {
+                    offset: 0,
+                    size: image.size(),
+                    data: Data::Fill { byte: 0 },
+                });
+            }
+            write_uring(image, &output_file, buffer_size)?;
+        } else if dense {
+            let mut output_file = BufWriter::with_capacity(buffer_size, output_file);
+            image.write_stream(&mut output_file)?;
+            output_file.flush()?;
+        } else if args.sparse {
+            image.write_sparse(&mut output_file, buffer_size)?;
+        } else {
+            image.write_seek(&mut output_file, buffer_size)?;
+        }
+        image.progress = None;
+        timings.step("Copy");
+
+        if args.verify {
+            // Writing in place leaves the rest of the image alone, so only the
+            // chunks are known
+            let gaps = match in_place {
+                true => verify::Gaps::Ignore,
+                false => verify::Gaps::Fill(0),
+            };
+            let mismatches = verify::compare(image, File::open(&write_path)?, gaps)?;
+            if !mismatches.is_empty() {
+                verify::report(&mismatches, image_start);
+                bail!("Output file {output:?} does not have what was written to it")
+            }
+            timings.step("Verify");
+        }
     }
 
-    for p in &phdrs {
-        output_file.seek(SeekFrom::Start(p.address() - base))?;
-        input_file.seek(SeekFrom::Start(p.file_offset()))?;
-        std::io::copy(&mut (&mut input_file).take(p.file_size()), &mut output_file)?;
+    if let Some(atomic) = atomic {
+        atomic.commit()?;
+    }
+
+    write_manifests(&output, base, segment_hashes, &args)?;
+
+    if args.stats {
+        print_stats(&layout.segments, image_start, image);
+    }
+    if let Some(size) = args.preview {
+        preview::print(image, &layout.segments, image_start, size)?;
+    }
+
+    write_side_outputs(&layout, &args, &appended)?;
+    write_scripts(&layout.placements, base, layout.entry, &args, &output)?;
+
+    timings.step("Finish");
+    if args.timings {
+        print_timings(
+            &timings,
+            &layout.segments,
+            base,
+            &layout.image,
+            &args,
+            &appended,
+        );
     }
 
     Ok(())
 }
+
+fn main() -> anyhow::Result<()> {
+    let matches = Args::command().get_matches_from(argsfile::expand(env::args_os())?);
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    match (&mut args.command, matches.subcommand()) {
+        (None, _) => args.copy.image_options = image_options(&matches),
+        (Some(Command::Copy(copy_args)), Some((_, sub_matches))) => {
+            copy_args.image_options = image_options(sub_matches);
+        }
+        _ => (),
+    }
+    log::init(args.quiet, args.verbose);
+
+    match args.command {
+        None => copy(args.copy),
+        Some(Command::Completions(completions_args)) => {
+            completions::run(completions_args, Args::command())
+        }
+        Some(Command::Copy(copy_args)) => copy(*copy_args),
+        Some(Command::Delta(delta_args)) => delta::run(delta_args),
+        Some(Command::Bench(bench_args)) => bench::run(bench_args, |args| {
+            let mut args = parse_copy_args(args)?;
+            args.force = true;
+            copy(args)
+        }),
+        Some(Command::Diff(diff_args)) => diff::run(diff_args),
+        Some(Command::Explain(explain_args)) => explain::run(explain_args),
+        #[cfg(feature = "flash")]
+        Some(Command::Flash(flash_args)) => flash::run(flash_args),
+        Some(Command::Info(info_args)) => info::run(info_args),
+        Some(Command::Mangen) => {
+            print!("{}", mangen::render(Args::command()));
+            Ok(())
+        }
+        Some(Command::Patch(patch_args)) => patch::run(patch_args),
+        Some(Command::Serve(serve_args)) => serve::run(serve_args),
+        Some(Command::Upload(upload_args)) => upload::run(upload_args),
+        Some(Command::Verify(verify_args)) => verify::run(verify_args),
+        Some(Command::Wrap(wrap_args)) => wrap::run(wrap_args),
+    }
+}
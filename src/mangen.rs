@@ -0,0 +1,121 @@
+use std::fmt::Write;
+
+/// Escape text for roff
+fn escape(s: &str) -> String {
+    let s = s.replace('\\', "\\e").replace('-', "\\-");
+    let mut res = String::new();
+    for line in s.lines() {
+        if line.starts_with(['.', '\'']) {
+            res.push_str("\\&");
+        }
+        res.push_str(line);
+        res.push('\n');
+    }
+    res
+}
+
+fn help(arg: &clap::Arg) -> String {
+    (arg.get_long_help().or(arg.get_help()))
+        .map(|h| h.to_string())
+        .unwrap_or_default()
+}
+
+fn value_name(arg: &clap::Arg) -> String {
+    match arg.get_value_names() {
+        Some(names) => names
+            .iter()
+            .map(|n| n.as_str())
+            .collect::<Vec<_>>()
+            .join(" "),
+        None => arg.get_id().as_str().to_uppercase(),
+    }
+}
+
+/// Write the usage lines of a command, one per line
+fn usage(res: &mut String, cmd: &mut clap::Command) {
+    let usage = cmd.render_usage().to_string();
+    let usage = usage.strip_prefix("Usage: ").unwrap_or(&usage);
+    let lines: Vec<String> = usage.lines().map(|l| escape(l.trim())).collect();
+    res.push_str(&lines.join(".br\n"));
+}
+
+/// Write options and arguments of a command
+fn arguments(res: &mut String, cmd: &clap::Command) {
+    for arg in cmd.get_arguments().filter(|a| !a.is_hide_set()) {
+        res.push_str(".TP\n");
+        if arg.is_positional() {
+            let _ = write!(res, "\\fI{}\\fR", escape(&value_name(arg)).trim_end());
+        } else {
+            let flags: Vec<String> = [
+                arg.get_short().map(|s| format!("\\fB\\-{s}\\fR")),
+                (arg.get_long()).map(|l| format!("\\fB\\-\\-{}\\fR", escape(l).trim_end())),
+            ]
+            .into_iter()
+            .flatten()
+            .collect();
+            res.push_str(&flags.join(", "));
+            if arg.get_action().takes_values() {
+                let _ = write!(res, " \\fI{}\\fR", escape(&value_name(arg)).trim_end());
+            }
+        }
+        res.push('\n');
+        res.push_str(&escape(&help(arg)));
+
+        let values: Vec<_> = (arg.get_possible_values().into_iter())
+            .filter(|v| !v.is_hide_set())
+            .map(|v| v.get_name().to_owned())
+            .collect();
+        if !values.is_empty() {
+            res.push_str(&escape(&format!("(One of: {})", values.join(", "))));
+        }
+    }
+}
+
+/// Render a man page for `cmd`
+pub fn render(mut cmd: clap::Command) -> String {
+    cmd.build();
+    let name = cmd.get_name().to_owned();
+    let about = cmd.get_about().map(|a| a.to_string()).unwrap_or_default();
+    let about = about
+        .split_once(": ")
+        .map_or(&*about, |(_, a)| a)
+        .to_owned();
+
+    let mut res = String::new();
+    let _ = writeln!(
+        res,
+        ".TH {upper} 1 \"\" \"{name} {version}\"",
+        upper = name.to_uppercase(),
+        version = env!("CARGO_PKG_VERSION"),
+    );
+    res.push_str(".SH NAME\n");
+    res.push_str(&escape(&format!("{name} - {about}")));
+
+    res.push_str(".SH SYNOPSIS\n");
+    usage(&mut res, &mut cmd);
+
+    res.push_str(".SH OPTIONS\n");
+    arguments(&mut res, &cmd);
+
+    let subcommands: Vec<clap::Command> = (cmd.get_subcommands())
+        .filter(|c| !c.is_hide_set() && c.get_name() != "help")
+        .cloned()
+        .collect();
+
+    res.push_str(".SH COMMANDS\n");
+    for sub in &subcommands {
+        let about = sub.get_about().map(|a| a.to_string()).unwrap_or_default();
+        let _ = writeln!(res, ".TP\n\\fB{}\\fR", escape(sub.get_name()).trim_end());
+        res.push_str(&escape(&about));
+    }
+
+    for mut sub in subcommands {
+        let title = format!("{name} {}", sub.get_name()).to_uppercase();
+        let _ = writeln!(res, ".SH \"{}\"", escape(&title).trim_end());
+        usage(&mut res, &mut sub);
+        res.push_str(".PP\n");
+        arguments(&mut res, &sub);
+    }
+
+    res
+}
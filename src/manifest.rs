@@ -0,0 +1,175 @@
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    hash::{self, hex},
+    image::Image,
+    layout::Segment,
+};
+
+#[derive(Debug, Serialize)]
+pub struct Manifest {
+    pub image: ImageEntry,
+    pub segments: Vec<SegmentEntry>,
+}
+
+/// The output file, as written
+#[derive(Debug, Serialize)]
+pub struct ImageEntry {
+    pub path: String,
+    /// Base address, which is at the start of the flat binary, or right after
+    /// the reserved header
+    pub base: u64,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// The file contents of a segment
+#[derive(Debug, Clone, Serialize)]
+pub struct SegmentEntry {
+    pub address: u64,
+    /// Offset in the flat binary
+    pub offset: u64,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// What a signer needs to know to sign the image, saying exactly which bytes
+/// the signature covers
+#[derive(Debug, Serialize)]
+pub struct SigningManifest {
+    /// Always "elfcopyflat-signing-manifest"
+    pub format: &'static str,
+    pub format_version: u32,
+    pub image: ImageEntry,
+    /// Ranges of the output file covered by the signature
+    pub covered: Vec<RangeEntry>,
+    pub segments: Vec<SegmentEntry>,
+    pub version: Option<String>,
+    /// Anti-rollback counter
+    pub security_version: Option<u32>,
+    /// Left empty, for the signer to fill in
+    pub signature: SignatureEntry,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RangeEntry {
+    /// Offset in the output file
+    pub offset: u64,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct SignatureEntry {
+    pub algorithm: Option<String>,
+    pub key_id: Option<String>,
+    pub value: Option<String>,
+}
+
+/// Versions to put in the signing manifest
+#[derive(Debug, Default)]
+pub struct Versions {
+    pub version: Option<String>,
+    pub security_version: Option<u32>,
+}
+
+/// Hash the image where the file contents of each selected segment are
+///
+/// This is done once everything is in the image, so that the hashes are of
+/// what ends up there, after gap fill, removed ranges, overlapping segments
+/// and `--onto`. Addresses are at offset `address - base + header` in the
+/// image, after a header of `header` bytes.
+pub fn segments(
+    segments: &[Segment],
+    base: u64,
+    header: u64,
+    image: &mut Image,
+) -> io::Result<Vec<SegmentEntry>> {
+    let mut res = Vec::new();
+
+    for s in segments {
+        let offset = s.phdr.address() - base + header;
+        let size = s.phdr.file_size();
+        let mut hasher = Sha256::new();
+        image.write_range(offset..offset + size, &mut hasher)?;
+        res.push(SegmentEntry {
+            address: s.phdr.address(),
+            offset,
+            size,
+            sha256: hex(&hasher.finalize()),
+        });
+    }
+
+    Ok(res)
+}
+
+/// Hash the written output file
+fn image_entry(output: &OsStr, base: u64) -> anyhow::Result<ImageEntry> {
+    let output_file = File::open(output)?;
+    Ok(ImageEntry {
+        path: output.to_string_lossy().into_owned(),
+        base,
+        size: output_file.metadata()?.len(),
+        sha256: hash::sha256(output_file)?,
+    })
+}
+
+/// Hash the written output file and write the manifest
+pub fn write(
+    path: &OsStr,
+    output: &OsStr,
+    base: u64,
+    segments: Vec<SegmentEntry>,
+) -> anyhow::Result<()> {
+    let image = image_entry(output, base)?;
+    let manifest = Manifest { image, segments };
+    let mut json = serde_json::to_string_pretty(&manifest)?;
+    json.push('\n');
+    std::fs::write(path, json)?;
+    Ok(())
+}
+
+/// Hash the written output file and write the signing manifest
+///
+/// The signature covers the whole output file, except for `header` bytes
+/// reserved at the start, where it may go.
+pub fn write_signing(
+    path: &OsStr,
+    output: &OsStr,
+    base: u64,
+    header: u64,
+    segments: Vec<SegmentEntry>,
+    versions: Versions,
+) -> anyhow::Result<()> {
+    let image = image_entry(output, base)?;
+    let offset = header.min(image.size);
+    let mut output_file = File::open(output)?;
+    output_file.seek(SeekFrom::Start(offset))?;
+    let covered = RangeEntry {
+        offset,
+        size: image.size - offset,
+        sha256: hash::sha256(output_file.take(image.size - offset))?,
+    };
+
+    let manifest = SigningManifest {
+        format: "elfcopyflat-signing-manifest",
+        format_version: 1,
+        image,
+        covered: vec![covered],
+        segments,
+        version: versions.version,
+        security_version: versions.security_version,
+        signature: SignatureEntry::default(),
+    };
+    let mut json = serde_json::to_string_pretty(&manifest)?;
+    json.push('\n');
+    std::fs::write(path, json)?;
+    Ok(())
+}
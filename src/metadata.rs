@@ -0,0 +1,74 @@
+//! A trailer after the image saying where it came from
+//!
+//! The trailer is a series of records, each a one byte tag, a little endian
+//! 16-bit length and the value, followed by a footer of the little endian
+//! 32-bit size of the whole trailer and the magic "ECFM". Readers find it from
+//! the end of the image, and skip tags they do not know.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+
+const MAGIC: &[u8; 4] = b"ECFM";
+
+/// "elfcopyflat" and its version, as text
+const TAG_TOOL_VERSION: u8 = 1;
+/// The GNU build ID of the first input file, as is
+const TAG_BUILD_ID: u8 = 2;
+/// The SHA-256 of the options that affect the image, each followed by a NUL
+const TAG_OPTIONS_SHA256: u8 = 3;
+/// Seconds since the Unix epoch, as a little endian 64-bit number
+const TAG_TIMESTAMP: u8 = 4;
+
+/// What goes in the trailer
+pub struct Metadata {
+    pub build_id: Option<Vec<u8>>,
+    /// Options that affect the image, in a canonical form that does not
+    /// change between runs with the same options
+    pub options: Vec<String>,
+    pub timestamp: Option<u64>,
+}
+
+/// The time to put in the trailer, from `SOURCE_DATE_EPOCH` if set so that
+/// builds can be reproduced
+pub fn timestamp() -> anyhow::Result<u64> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch
+            .parse()
+            .with_context(|| format!("Invalid SOURCE_DATE_EPOCH {epoch:?}")),
+        Err(_) => Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()),
+    }
+}
+
+fn record(res: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    res.push(tag);
+    res.extend((value.len() as u16).to_le_bytes());
+    res.extend(value);
+}
+
+impl Metadata {
+    /// The bytes of the trailer
+    pub fn trailer(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        let version = concat!("elfcopyflat ", env!("CARGO_PKG_VERSION"));
+        record(&mut res, TAG_TOOL_VERSION, version.as_bytes());
+        if let Some(build_id) = &self.build_id {
+            record(&mut res, TAG_BUILD_ID, build_id);
+        }
+        let mut options = Sha256::new();
+        for option in &self.options {
+            options.update(option.as_bytes());
+            options.update([0]);
+        }
+        record(&mut res, TAG_OPTIONS_SHA256, &options.finalize());
+        if let Some(timestamp) = self.timestamp {
+            record(&mut res, TAG_TIMESTAMP, &timestamp.to_le_bytes());
+        }
+
+        let size = (res.len() + 8) as u32;
+        res.extend(size.to_le_bytes());
+        res.extend(MAGIC);
+        res
+    }
+}
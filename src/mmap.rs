@@ -0,0 +1,85 @@
+use std::{
+    fs::File,
+    io,
+    ops::Deref,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// Read files instead of mapping them from now on, for files that may be
+/// truncated while they are read
+pub fn disable() {
+    DISABLED.store(true, Ordering::Relaxed);
+}
+
+/// A read-only memory map of a whole file
+///
+/// The file must not be truncated while it is mapped, or reading from the map
+/// crashes.
+#[derive(Debug)]
+pub struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mmap {
+    #[cfg(unix)]
+    pub fn new(file: &File) -> io::Result<Self> {
+        use std::os::fd::AsRawFd;
+
+        if DISABLED.load(Ordering::Relaxed) {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+
+        let len = usize::try_from(file.metadata()?.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::OutOfMemory, "File too large to map"))?;
+        if len == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Empty file"));
+        }
+
+        // SAFETY: Mapping a file read-only with a length from its metadata
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            ptr: ptr.cast(),
+            len,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn new(_file: &File) -> io::Result<Self> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: The map is readable for `len` bytes until dropped
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: Unmapping what `new` mapped, which is not used after this
+        #[cfg(unix)]
+        unsafe {
+            libc::munmap(self.ptr.cast(), self.len);
+        }
+    }
+}
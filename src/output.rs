@@ -0,0 +1,53 @@
+use std::{
+    ffi::OsStr,
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// An output file, written as a temporary file next to it and renamed into
+/// place once complete
+///
+/// This way, an interrupted run never leaves a partly written output behind.
+/// The temporary file is removed if it is not committed.
+pub struct AtomicFile {
+    path: PathBuf,
+    temp: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    pub fn new(path: &OsStr) -> Self {
+        let path = PathBuf::from(path);
+        let mut name = OsStr::new(".").to_owned();
+        name.push(path.file_name().unwrap_or(OsStr::new("output")));
+        name.push(format!(".elfcopyflat-{}.tmp", process::id()));
+        let temp = path.with_file_name(name);
+
+        Self {
+            path,
+            temp,
+            committed: false,
+        }
+    }
+
+    /// Path to write the contents to
+    pub fn temp_path(&self) -> &Path {
+        &self.temp
+    }
+
+    /// Move the written file into place
+    pub fn commit(mut self) -> io::Result<()> {
+        fs::rename(&self.temp, &self.path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
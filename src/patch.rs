@@ -0,0 +1,67 @@
+use std::{
+    ffi::OsString,
+    fs::{File, OpenOptions},
+};
+
+use anyhow::bail;
+use clap::Args;
+
+use crate::{
+    image::DEFAULT_BUFFER_SIZE,
+    layout::{self, LayoutArgs},
+    verify,
+};
+
+#[derive(Debug, Args)]
+pub struct PatchArgs {
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// Read the image back after writing it, and check the patched parts
+    #[arg(long)]
+    verify: bool,
+
+    /// Existing flat image to write segments over in place, keeping its other
+    /// contents
+    image: OsString,
+
+    /// Input ELF, PE or Mach-O files
+    #[arg(required = true)]
+    input: Vec<OsString>,
+}
+
+/// Write segments over an existing image in place, as with `--onto IMAGE`
+/// given the same image as output
+pub fn run(args: PatchArgs) -> anyhow::Result<()> {
+    if args.layout.base.is_none() {
+        bail!("Patching needs --base, the address of the start of the image")
+    }
+
+    for path in &args.input {
+        if crate::same_file(path, &args.image) {
+            bail!(
+                "Image {image:?} is the same as input file {path:?}",
+                image = args.image
+            )
+        }
+    }
+
+    let mut layout = layout::layout(&args.input, &args.layout)?;
+    let mut image_file = OpenOptions::new().write(true).open(&args.image)?;
+    let image = &mut layout.image;
+    image.min_size = image_file.metadata()?.len();
+    image.write_seek(&mut image_file, DEFAULT_BUFFER_SIZE)?;
+
+    if args.verify {
+        let mismatches = verify::compare(image, File::open(&args.image)?, verify::Gaps::Ignore)?;
+        if !mismatches.is_empty() {
+            verify::report(&mismatches, layout.base);
+            bail!(
+                "Image {:?} does not have what was written to it",
+                args.image
+            )
+        }
+    }
+
+    Ok(())
+}
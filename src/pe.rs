@@ -0,0 +1,208 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    mem::size_of,
+};
+
+use anyhow::bail;
+use zerocopy::{FromBytes, FromZeroes, LittleEndian, U16, U32, U64};
+
+use crate::elf::{Class, Flags, Machine, Phdr, SectionFlags, Shdr};
+
+/// COFF file header, after the `PE\0\0` signature
+///
+/// All fields are little endian.
+#[derive(Debug, Clone, FromZeroes, FromBytes)]
+#[repr(C)]
+struct FileHeader {
+    machine: U16<LittleEndian>,
+    number_of_sections: U16<LittleEndian>,
+    time_date_stamp: U32<LittleEndian>,
+    pointer_to_symbol_table: U32<LittleEndian>,
+    number_of_symbols: U32<LittleEndian>,
+    size_of_optional_header: U16<LittleEndian>,
+    characteristics: U16<LittleEndian>,
+}
+
+/// Start of the optional header of a PE32 image
+#[derive(Debug, Clone, FromZeroes, FromBytes)]
+#[repr(C)]
+struct OptionalHeader32 {
+    magic: U16<LittleEndian>,
+    linker_version: [u8; 2],
+    size_of_code: U32<LittleEndian>,
+    size_of_initialized_data: U32<LittleEndian>,
+    size_of_uninitialized_data: U32<LittleEndian>,
+    address_of_entry_point: U32<LittleEndian>,
+    base_of_code: U32<LittleEndian>,
+    base_of_data: U32<LittleEndian>,
+    image_base: U32<LittleEndian>,
+}
+
+/// Start of the optional header of a PE32+ image
+#[derive(Debug, Clone, FromZeroes, FromBytes)]
+#[repr(C)]
+struct OptionalHeader64 {
+    magic: U16<LittleEndian>,
+    linker_version: [u8; 2],
+    size_of_code: U32<LittleEndian>,
+    size_of_initialized_data: U32<LittleEndian>,
+    size_of_uninitialized_data: U32<LittleEndian>,
+    address_of_entry_point: U32<LittleEndian>,
+    base_of_code: U32<LittleEndian>,
+    image_base: U64<LittleEndian>,
+}
+
+#[derive(Debug, Clone, FromZeroes, FromBytes)]
+#[repr(C)]
+struct SectionHeader {
+    name: [u8; 8],
+    virtual_size: U32<LittleEndian>,
+    virtual_address: U32<LittleEndian>,
+    size_of_raw_data: U32<LittleEndian>,
+    pointer_to_raw_data: U32<LittleEndian>,
+    pointer_to_relocations: U32<LittleEndian>,
+    pointer_to_linenumbers: U32<LittleEndian>,
+    number_of_relocations: U16<LittleEndian>,
+    number_of_linenumbers: U16<LittleEndian>,
+    characteristics: U32<LittleEndian>,
+}
+
+impl SectionHeader {
+    const IMAGE_SCN_MEM_DISCARDABLE: u32 = 0x02000000;
+    const IMAGE_SCN_MEM_EXECUTE: u32 = 0x20000000;
+    const IMAGE_SCN_MEM_READ: u32 = 0x40000000;
+    const IMAGE_SCN_MEM_WRITE: u32 = 0x80000000;
+}
+
+pub const DOS_MAGIC: &[u8; 2] = b"MZ";
+const PE_MAGIC: &[u8; 4] = b"PE\0\0";
+const PE32_MAGIC: u16 = 0x10b;
+const PE32_PLUS_MAGIC: u16 = 0x20b;
+
+/// Sections of a PE image, in the same form as ELF
+pub struct Image {
+    pub machine: Machine,
+    pub class: Class,
+    /// Address of the entry point, if there is one
+    pub entry: Option<u64>,
+    /// Index of each section, with a loadable segment for it
+    pub phdrs: Vec<(usize, Phdr)>,
+    pub shdrs: Vec<Shdr>,
+}
+
+fn read_at<T: FromBytes>(file: &mut File, offset: u64) -> anyhow::Result<T> {
+    let mut buf = vec![0; size_of::<T>()];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+    Ok(T::read_from(&buf[..]).unwrap())
+}
+
+/// Read the section table of a PE image
+///
+/// Sections marked as discardable, such as `.reloc`, are left out.
+pub fn read(file: &mut File) -> anyhow::Result<Image> {
+    let pe_offset = u64::from(read_at::<U32<LittleEndian>>(file, 0x3c)?.get());
+    if &read_at::<[u8; 4]>(file, pe_offset)? != PE_MAGIC {
+        bail!("Not a PE image, only a DOS executable")
+    }
+
+    let header_offset = pe_offset + PE_MAGIC.len() as u64;
+    let header: FileHeader = read_at(file, header_offset)?;
+    let optional_offset = header_offset + size_of::<FileHeader>() as u64;
+
+    let magic = read_at::<U16<LittleEndian>>(file, optional_offset)?.get();
+    let (image_base, entry, is_64) = match magic {
+        PE32_MAGIC => {
+            let optional: OptionalHeader32 = read_at(file, optional_offset)?;
+            let entry = optional.address_of_entry_point.get();
+            (u64::from(optional.image_base.get()), entry, false)
+        }
+        PE32_PLUS_MAGIC => {
+            let optional: OptionalHeader64 = read_at(file, optional_offset)?;
+            let entry = optional.address_of_entry_point.get();
+            (optional.image_base.get(), entry, true)
+        }
+        _ => bail!("Unknown PE optional header magic {magic:#x}"),
+    };
+
+    let machine = match header.machine.get() {
+        0x14c => Machine::EM_386,
+        0x8664 => Machine::EM_X86_64,
+        0x1c0 | 0x1c2 | 0x1c4 => Machine::EM_ARM,
+        0xaa64 => Machine::EM_AARCH64,
+        0x5032 | 0x5064 => Machine::EM_RISCV,
+        0x6264 => Machine::EM_LOONGARCH,
+        _ => Machine::EM_NONE,
+    };
+    let class = match is_64 {
+        true => Class::ELFCLASS64,
+        false => Class::ELFCLASS32,
+    };
+
+    let mut image = Image {
+        machine,
+        class,
+        // DLLs may have no entry point, given as zero
+        entry: (entry != 0).then(|| image_base.wrapping_add(u64::from(entry))),
+        phdrs: Vec::new(),
+        shdrs: Vec::new(),
+    };
+
+    let table_offset = optional_offset + u64::from(header.size_of_optional_header.get());
+    for i in 0..usize::from(header.number_of_sections.get()) {
+        let offset = table_offset + (i * size_of::<SectionHeader>()) as u64;
+        let section: SectionHeader = read_at(file, offset)?;
+        let characteristics = section.characteristics.get();
+        if characteristics & SectionHeader::IMAGE_SCN_MEM_DISCARDABLE != 0 {
+            continue;
+        }
+
+        let name = String::from_utf8_lossy(&section.name);
+        let name = name.trim_end_matches('\0').to_owned();
+
+        // The virtual size may be zero in object files, and the raw data is
+        // padded to the file alignment past the virtual size
+        let raw_size = u64::from(section.size_of_raw_data.get());
+        let memory_size = match section.virtual_size.get() {
+            0 => raw_size,
+            size => u64::from(size),
+        };
+        let file_size = raw_size.min(memory_size);
+        let file_offset = match file_size {
+            0 => 0,
+            _ => u64::from(section.pointer_to_raw_data.get()),
+        };
+        let Some(address) = image_base.checked_add(u64::from(section.virtual_address.get())) else {
+            bail!(
+                "Section {name:?} at {rva:#x} from image base {image_base:#x} goes past the end of the address space",
+                rva = section.virtual_address.get(),
+            )
+        };
+
+        let mut flags = 0;
+        let mut section_flags = 0;
+        if characteristics & SectionHeader::IMAGE_SCN_MEM_READ != 0 {
+            flags |= Flags::PF_R.0;
+        }
+        if characteristics & SectionHeader::IMAGE_SCN_MEM_WRITE != 0 {
+            flags |= Flags::PF_W.0;
+            section_flags |= SectionFlags::SHF_WRITE.0;
+        }
+        if characteristics & SectionHeader::IMAGE_SCN_MEM_EXECUTE != 0 {
+            flags |= Flags::PF_X.0;
+            section_flags |= SectionFlags::SHF_EXECINSTR.0;
+        }
+
+        let phdr = Phdr::synthetic(Flags(flags), file_offset, address, file_size, memory_size);
+        image.phdrs.push((i, phdr));
+        image.shdrs.push(Shdr::synthetic(
+            name,
+            SectionFlags(section_flags),
+            address,
+            memory_size,
+        ));
+    }
+
+    Ok(image)
+}
@@ -0,0 +1,57 @@
+use std::{io, ops::Range};
+
+use crate::{image::Image, layout::Segment};
+
+/// Print a hexdump of the start and end of the image, and around the start
+/// and end of each segment
+pub fn print(image: &mut Image, segments: &[Segment], base: u64, size: u64) -> io::Result<()> {
+    let image_size = image.size();
+    let around = |at: u64| at.saturating_sub(size / 2)..at.saturating_add(size / 2).min(image_size);
+
+    let mut ranges = Vec::new();
+    ranges.push(0..size.min(image_size));
+    for s in segments {
+        let start = s.phdr.address().wrapping_sub(base);
+        ranges.push(around(start));
+        ranges.push(around(start + s.phdr.file_size()));
+    }
+    ranges.push(image_size.saturating_sub(size)..image_size);
+
+    // Whole lines, merged where they touch
+    let mut ranges: Vec<Range<u64>> = (ranges.into_iter())
+        .map(|r| r.start / 16 * 16..r.end.next_multiple_of(16).min(image_size))
+        .filter(|r| r.start < r.end)
+        .collect();
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<u64>> = Vec::new();
+    for r in ranges {
+        match merged.last_mut() {
+            Some(last) if last.end >= r.start => last.end = last.end.max(r.end),
+            _ => merged.push(r),
+        }
+    }
+
+    for (i, r) in merged.into_iter().enumerate() {
+        if i > 0 {
+            eprintln!("...");
+        }
+        let data = image.read_range(r.clone())?;
+        for (line, bytes) in data.chunks(16).enumerate() {
+            let offset = r.start + line as u64 * 16;
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{b:02x}")).collect();
+            let text: String = (bytes.iter())
+                .map(|&b| match b {
+                    0x20..=0x7e => b as char,
+                    _ => '.',
+                })
+                .collect();
+            eprintln!(
+                "{offset:08x} {addr:#010x}  {hex:<47}  |{text}|",
+                addr = base.wrapping_add(offset),
+                hex = hex.join(" "),
+            );
+        }
+    }
+
+    Ok(())
+}
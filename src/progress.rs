@@ -0,0 +1,110 @@
+use std::{
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+/// A progress bar on standard error, for the current chunk and overall
+#[derive(Debug)]
+pub struct Progress {
+    total: u64,
+    done: u64,
+    chunk_size: u64,
+    chunk_done: u64,
+    last_draw: Option<Instant>,
+}
+
+impl Progress {
+    /// Show progress only if copying at least this much
+    pub const THRESHOLD: u64 = 64 << 20;
+
+    const INTERVAL: Duration = Duration::from_millis(100);
+    const WIDTH: u64 = 20;
+
+    pub fn new(total: u64) -> Self {
+        Self {
+            total,
+            done: 0,
+            chunk_size: 0,
+            chunk_done: 0,
+            last_draw: None,
+        }
+    }
+
+    pub fn start_chunk(&mut self, size: u64) {
+        self.chunk_size = size;
+        self.chunk_done = 0;
+    }
+
+    /// Count `size` bytes as done, e.g. if they were copied without writing
+    pub fn add(&mut self, size: u64) {
+        self.done += size;
+        self.chunk_done += size;
+
+        let now = Instant::now();
+        if self.last_draw.is_some_and(|t| now - t < Self::INTERVAL) {
+            return;
+        }
+        self.last_draw = Some(now);
+
+        eprint!(
+            "\rChunk {} Total {} {} / {}\x1b[K",
+            bar(self.chunk_done, self.chunk_size),
+            bar(self.done, self.total),
+            mib(self.done),
+            mib(self.total),
+        );
+    }
+
+    /// A writer that counts what is written through it
+    pub fn writer<'a>(&'a mut self, inner: &'a mut dyn Write) -> ProgressWriter<'a> {
+        ProgressWriter {
+            progress: self,
+            inner,
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if self.last_draw.is_some() {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+fn bar(done: u64, total: u64) -> String {
+    let done = done.min(total);
+    let (filled, percent) = match total {
+        0 => (Progress::WIDTH, 100),
+        _ => (
+            (done as u128 * Progress::WIDTH as u128 / total as u128) as u64,
+            (done as u128 * 100 / total as u128) as u64,
+        ),
+    };
+    format!(
+        "{percent:3}% [{}{}]",
+        "#".repeat(filled as usize),
+        " ".repeat((Progress::WIDTH - filled) as usize),
+    )
+}
+
+fn mib(size: u64) -> String {
+    format!("{} MiB", size >> 20)
+}
+
+pub struct ProgressWriter<'a> {
+    progress: &'a mut Progress,
+    inner: &'a mut dyn Write,
+}
+
+impl Write for ProgressWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.progress.add(n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,100 @@
+use anyhow::bail;
+use clap_num::maybe_hex;
+
+use crate::{
+    image::{Data, Image},
+    layout,
+};
+
+/// A range of memory for `--region`, e.g. flash
+#[derive(Debug, Clone)]
+pub struct Region {
+    name: String,
+    origin: u64,
+    length: u64,
+}
+
+/// Parse a region as "NAME=ORIGIN,LENGTH", e.g. "FLASH=0x8000000,256K"
+pub fn parse_region(s: &str) -> Result<Region, String> {
+    let Some((name, rest)) = s.split_once('=') else {
+        return Err("Expected NAME=ORIGIN,LENGTH".to_owned());
+    };
+    let Some((origin, length)) = rest.split_once(',') else {
+        return Err("Expected NAME=ORIGIN,LENGTH".to_owned());
+    };
+
+    let origin = maybe_hex::<u64>(origin.trim())?;
+    let length = layout::parse_size(length.trim())?;
+    if origin.checked_add(length).is_none() {
+        return Err(format!(
+            "Region {name} goes past the end of the address space"
+        ));
+    }
+
+    Ok(Region {
+        name: name.trim().to_owned(),
+        origin,
+        length,
+    })
+}
+
+fn kib(size: u64) -> String {
+    format!("{:.1} KiB", size as f64 / 1024.0)
+}
+
+/// Print how much of each region the contents of the image use, and fail if
+/// any region overflows
+///
+/// Contents belong to the region their start is in, like sections in GNU ld.
+pub fn check(regions: &[Region], image: &Image, base: u64) -> anyhow::Result<()> {
+    let mut used = vec![0; regions.len()];
+    let mut overflow: Vec<Option<u64>> = vec![None; regions.len()];
+    let mut outside = 0;
+
+    for chunk in image.resolved() {
+        if let Data::Fill { .. } = chunk.data {
+            continue;
+        }
+        let start = base + chunk.offset;
+        let end = start + chunk.size;
+
+        let Some(i) =
+            (regions.iter()).position(|r| start >= r.origin && start - r.origin < r.length)
+        else {
+            outside += chunk.size;
+            continue;
+        };
+
+        used[i] += chunk.size;
+        let region_end = regions[i].origin + regions[i].length;
+        if end > region_end {
+            overflow[i] = Some(overflow[i].unwrap_or(0).max(end - region_end));
+        }
+    }
+
+    let width = regions.iter().map(|r| r.name.len()).max().unwrap_or(0);
+    for (i, r) in regions.iter().enumerate() {
+        let percent = match r.length {
+            0 => 100.0,
+            _ => used[i] as f64 * 100.0 / r.length as f64,
+        };
+        println!(
+            "{name:width$}  {used:>12} of {length:>12}  {percent:5.1}%",
+            name = r.name,
+            used = kib(used[i]),
+            length = kib(r.length),
+        );
+    }
+    if outside > 0 {
+        println!("{:width$}  {:>12} outside of regions", "", kib(outside));
+    }
+
+    let over: Vec<String> = (regions.iter().zip(&overflow))
+        .filter_map(|(r, o)| Some(format!("{} by {:#x} bytes", r.name, (*o)?)))
+        .collect();
+    if !over.is_empty() {
+        bail!("Image does not fit in region {}", over.join(", "))
+    }
+
+    Ok(())
+}
@@ -0,0 +1,844 @@
+use std::{collections::HashMap, fs::File, rc::Rc};
+
+use anyhow::{bail, Context};
+
+use crate::elf::{
+    self, Class, Dyn, DynTag, Ehdr, Flags, Machine, Phdr, Rela, SectionType, Shdr, Sym, Type,
+};
+
+/// Contents of a section or segment being relocated, along with where it is
+/// in memory
+pub struct Target<'a> {
+    pub data: &'a mut [u8],
+    pub address: u64,
+    pub little_endian: bool,
+}
+
+impl Target<'_> {
+    fn bytes<const N: usize>(&mut self, offset: u64) -> anyhow::Result<&mut [u8; N]> {
+        let start = usize::try_from(offset)?;
+        let len = self.data.len();
+        match self.data.get_mut(start..start.saturating_add(N)) {
+            Some(b) => Ok(b.try_into().unwrap()),
+            None => {
+                bail!("Relocation at offset {offset:#x} is outside of section of size {len:#x}")
+            }
+        }
+    }
+
+    fn read<const N: usize>(&mut self, offset: u64) -> anyhow::Result<u64> {
+        let little_endian = self.little_endian;
+        let b = *self.bytes::<N>(offset)?;
+        let mut res = 0;
+        for i in 0..N {
+            let byte = if little_endian { b[N - 1 - i] } else { b[i] };
+            res = (res << 8) | u64::from(byte);
+        }
+        Ok(res)
+    }
+
+    fn write<const N: usize>(&mut self, offset: u64, value: u64) -> anyhow::Result<()> {
+        let little_endian = self.little_endian;
+        let b = self.bytes::<N>(offset)?;
+        for i in 0..N {
+            let byte = (value >> (8 * i)) as u8;
+            if little_endian {
+                b[i] = byte;
+            } else {
+                b[N - 1 - i] = byte;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read-modify-write a 32-bit instruction, keeping bits in `keep`
+    fn patch32(&mut self, offset: u64, keep: u32, bits: u32) -> anyhow::Result<()> {
+        let insn = self.read::<4>(offset)? as u32;
+        self.write::<4>(offset, u64::from((insn & keep) | (bits & !keep)))
+    }
+
+    fn patch16(&mut self, offset: u64, keep: u16, bits: u16) -> anyhow::Result<()> {
+        let insn = self.read::<2>(offset)? as u16;
+        self.write::<2>(offset, u64::from((insn & keep) | (bits & !keep)))
+    }
+}
+
+fn check_signed(value: i64, bits: u32, rtype: u32) -> anyhow::Result<()> {
+    let limit = 1i64 << (bits - 1);
+    if value < -limit || value >= limit {
+        bail!("Relocation type {rtype} out of range, value {value:#x} does not fit in {bits} bits")
+    }
+    Ok(())
+}
+
+fn check_32(value: i64, rtype: u32) -> anyhow::Result<()> {
+    if value < i64::from(i32::MIN) || value > i64::from(u32::MAX) {
+        bail!("Relocation type {rtype} out of range, value {value:#x} does not fit in 32 bits")
+    }
+    Ok(())
+}
+
+fn check_aligned(value: i64, align: i64, rtype: u32) -> anyhow::Result<()> {
+    if value % align != 0 {
+        bail!("Relocation type {rtype} target {value:#x} is not aligned to {align}")
+    }
+    Ok(())
+}
+
+fn sign_extend(value: u64, bits: u32) -> i64 {
+    ((value << (64 - bits)) as i64) >> (64 - bits)
+}
+
+/// Applies relocations for one machine
+#[derive(Debug)]
+pub struct Relocator {
+    machine: Machine,
+    class: Class,
+    /// RISC-V `%pcrel_lo` relocations refer to the `%pcrel_hi` relocation at
+    /// the symbol address, so the values of the latter are kept here
+    pcrel_hi: HashMap<u64, i64>,
+}
+
+impl Relocator {
+    pub fn new(ehdr: &Ehdr) -> Self {
+        Self {
+            machine: ehdr.machine(),
+            class: ehdr.class(),
+            pcrel_hi: HashMap::new(),
+        }
+    }
+
+    /// The `R_*_RELATIVE` relocation type, which adds the load bias
+    pub fn relative_type(&self) -> Option<u32> {
+        match self.machine {
+            Machine::EM_X86_64 | Machine::EM_386 => Some(8),
+            Machine::EM_ARM => Some(23),
+            Machine::EM_AARCH64 => Some(1027),
+            Machine::EM_RISCV => Some(3),
+            _ => None,
+        }
+    }
+
+    /// Addend stored at the location, for `SHT_REL` relocations
+    pub fn implicit_addend(
+        &self,
+        rtype: u32,
+        target: &mut Target,
+        offset: u64,
+    ) -> anyhow::Result<i64> {
+        let machine = self.machine;
+        let res = match (machine, rtype) {
+            (Machine::EM_386, 1 | 2 | 8) | (Machine::EM_ARM, 2 | 3 | 23) => {
+                sign_extend(target.read::<4>(offset)?, 32)
+            }
+            (Machine::EM_ARM, 28 | 29) => {
+                sign_extend(target.read::<4>(offset)? & 0xffffff, 24) << 2
+            }
+            (Machine::EM_X86_64, 8) | (Machine::EM_AARCH64, 1027) => {
+                target.read::<8>(offset)? as i64
+            }
+            (Machine::EM_RISCV, 3) => match self.class {
+                Class::ELFCLASS32 => sign_extend(target.read::<4>(offset)?, 32),
+                _ => target.read::<8>(offset)? as i64,
+            },
+            _ => {
+                bail!("Unsupported relocation type {rtype} without addend for machine {machine}")
+            }
+        };
+        Ok(res)
+    }
+
+    /// Whether this relocation needs other ones to be applied first
+    pub fn is_deferred(&self, rtype: u32) -> bool {
+        self.machine == Machine::EM_RISCV && matches!(rtype, 24 | 25)
+    }
+
+    /// Apply one relocation at `offset` in `target`, with symbol value `s` and
+    /// addend `a`
+    ///
+    /// For `*_RELATIVE` relocations, `s` should be the load bias.
+    pub fn apply(
+        &mut self,
+        rtype: u32,
+        target: &mut Target,
+        offset: u64,
+        s: u64,
+        a: i64,
+    ) -> anyhow::Result<()> {
+        let (machine, class) = (self.machine, self.class);
+        let p = target.address.wrapping_add(offset);
+        let sa = s.wrapping_add_signed(a) as i64;
+        let sap = sa.wrapping_sub(p as i64);
+        let t = target;
+
+        match (machine, rtype) {
+            (_, 0) => {}
+
+            // x86-64
+            (Machine::EM_X86_64, 1) => t.write::<8>(offset, sa as u64)?,
+            (Machine::EM_X86_64, 2 | 4) => {
+                check_signed(sap, 32, rtype)?;
+                t.write::<4>(offset, sap as u64)?
+            }
+            (Machine::EM_X86_64, 8) => t.write::<8>(offset, sa as u64)?,
+            (Machine::EM_X86_64, 10) => {
+                if !(0..=i64::from(u32::MAX)).contains(&sa) {
+                    bail!("Relocation type {rtype} out of range, value {sa:#x} does not fit in 32 bits")
+                }
+                t.write::<4>(offset, sa as u64)?
+            }
+            (Machine::EM_X86_64, 11) => {
+                check_signed(sa, 32, rtype)?;
+                t.write::<4>(offset, sa as u64)?
+            }
+            (Machine::EM_X86_64, 24) => t.write::<8>(offset, sap as u64)?,
+
+            // i386
+            (Machine::EM_386, 1 | 8) => t.write::<4>(offset, sa as u64)?,
+            (Machine::EM_386, 2) => t.write::<4>(offset, sap as u64)?,
+
+            // 32-bit Arm
+            (Machine::EM_ARM, 2 | 23) => t.write::<4>(offset, sa as u64)?,
+            (Machine::EM_ARM, 3) => t.write::<4>(offset, sap as u64)?,
+            (Machine::EM_ARM, 28 | 29) => {
+                check_signed(sap, 26, rtype)?;
+                check_aligned(sap, 4, rtype)?;
+                t.patch32(offset, 0xff000000, (sap >> 2) as u32 & 0xffffff)?
+            }
+
+            // AArch64
+            (Machine::EM_AARCH64, 257 | 1027) => t.write::<8>(offset, sa as u64)?,
+            (Machine::EM_AARCH64, 258) => {
+                check_32(sa, rtype)?;
+                t.write::<4>(offset, sa as u64)?
+            }
+            (Machine::EM_AARCH64, 260) => t.write::<8>(offset, sap as u64)?,
+            (Machine::EM_AARCH64, 261) => {
+                check_32(sap, rtype)?;
+                t.write::<4>(offset, sap as u64)?
+            }
+            (Machine::EM_AARCH64, 274 | 275) => {
+                let imm = if rtype == 275 {
+                    ((sa & !0xfff) - (p as i64 & !0xfff)) >> 12
+                } else {
+                    sap
+                };
+                check_signed(imm, 21, rtype)?;
+                let immlo = (imm as u32 & 3) << 29;
+                let immhi = ((imm >> 2) as u32 & 0x7ffff) << 5;
+                t.patch32(offset, !(3 << 29 | 0x7ffff << 5), immlo | immhi)?
+            }
+            (Machine::EM_AARCH64, 277 | 278 | 284 | 285 | 286 | 299) => {
+                let shift = match rtype {
+                    284 => 1,
+                    285 => 2,
+                    286 => 3,
+                    299 => 4,
+                    _ => 0,
+                };
+                let imm = (sa as u32 & 0xfff) >> shift;
+                t.patch32(offset, !(0xfff << 10), imm << 10)?
+            }
+            (Machine::EM_AARCH64, 279) => {
+                check_signed(sap, 16, rtype)?;
+                t.patch32(offset, !(0x3fff << 5), ((sap >> 2) as u32 & 0x3fff) << 5)?
+            }
+            (Machine::EM_AARCH64, 280) => {
+                check_signed(sap, 21, rtype)?;
+                t.patch32(offset, !(0x7ffff << 5), ((sap >> 2) as u32 & 0x7ffff) << 5)?
+            }
+            (Machine::EM_AARCH64, 282 | 283) => {
+                check_signed(sap, 28, rtype)?;
+                check_aligned(sap, 4, rtype)?;
+                t.patch32(offset, 0xfc000000, (sap >> 2) as u32 & 0x3ffffff)?
+            }
+
+            // RISC-V
+            (Machine::EM_RISCV, 1) => t.write::<4>(offset, sa as u64)?,
+            (Machine::EM_RISCV, 2) => t.write::<8>(offset, sa as u64)?,
+            (Machine::EM_RISCV, 3) => match class {
+                Class::ELFCLASS32 => t.write::<4>(offset, sa as u64)?,
+                _ => t.write::<8>(offset, sa as u64)?,
+            },
+            (Machine::EM_RISCV, 16) => {
+                check_signed(sap, 13, rtype)?;
+                let v = sap as u32;
+                let bits = (v >> 12 & 1) << 31
+                    | (v >> 5 & 0x3f) << 25
+                    | (v >> 1 & 0xf) << 8
+                    | (v >> 11 & 1) << 7;
+                t.patch32(offset, !0xfe000f80, bits)?
+            }
+            (Machine::EM_RISCV, 17) => {
+                check_signed(sap, 21, rtype)?;
+                let v = sap as u32;
+                let bits = (v >> 20 & 1) << 31
+                    | (v >> 1 & 0x3ff) << 21
+                    | (v >> 11 & 1) << 20
+                    | (v >> 12 & 0xff) << 12;
+                t.patch32(offset, 0xfff, bits)?
+            }
+            (Machine::EM_RISCV, 18 | 19) => {
+                check_signed(sap.wrapping_add(0x800), 32, rtype)?;
+                t.patch32(offset, 0xfff, hi20(sap))?;
+                t.patch32(offset + 4, 0xfffff, lo12(sap) << 20)?
+            }
+            (Machine::EM_RISCV, 23) => {
+                check_signed(sap.wrapping_add(0x800), 32, rtype)?;
+                self.pcrel_hi.insert(p, sap);
+                t.patch32(offset, 0xfff, hi20(sap))?
+            }
+            (Machine::EM_RISCV, 24 | 25) => {
+                let Some(&v) = self.pcrel_hi.get(&(sa as u64)) else {
+                    bail!("No %pcrel_hi relocation at {sa:#x} for %pcrel_lo relocation at {p:#x}")
+                };
+                if rtype == 24 {
+                    t.patch32(offset, 0xfffff, lo12(v) << 20)?
+                } else {
+                    t.patch32(offset, 0x1fff07f, s_imm(lo12(v)))?
+                }
+            }
+            (Machine::EM_RISCV, 26) => {
+                check_signed(sa.wrapping_add(0x800), 32, rtype)?;
+                t.patch32(offset, 0xfff, hi20(sa))?
+            }
+            (Machine::EM_RISCV, 27) => t.patch32(offset, 0xfffff, lo12(sa) << 20)?,
+            (Machine::EM_RISCV, 28) => t.patch32(offset, 0x1fff07f, s_imm(lo12(sa)))?,
+            (Machine::EM_RISCV, 33) => {
+                let v = t.read::<1>(offset)?.wrapping_add(sa as u64);
+                t.write::<1>(offset, v)?
+            }
+            (Machine::EM_RISCV, 34) => {
+                let v = t.read::<2>(offset)?.wrapping_add(sa as u64);
+                t.write::<2>(offset, v)?
+            }
+            (Machine::EM_RISCV, 35) => {
+                let v = t.read::<4>(offset)?.wrapping_add(sa as u64);
+                t.write::<4>(offset, v)?
+            }
+            (Machine::EM_RISCV, 36) => {
+                let v = t.read::<8>(offset)?.wrapping_add(sa as u64);
+                t.write::<8>(offset, v)?
+            }
+            (Machine::EM_RISCV, 37) => {
+                let v = t.read::<1>(offset)?.wrapping_sub(sa as u64);
+                t.write::<1>(offset, v)?
+            }
+            (Machine::EM_RISCV, 38) => {
+                let v = t.read::<2>(offset)?.wrapping_sub(sa as u64);
+                t.write::<2>(offset, v)?
+            }
+            (Machine::EM_RISCV, 39) => {
+                let v = t.read::<4>(offset)?.wrapping_sub(sa as u64);
+                t.write::<4>(offset, v)?
+            }
+            (Machine::EM_RISCV, 40) => {
+                let v = t.read::<8>(offset)?.wrapping_sub(sa as u64);
+                t.write::<8>(offset, v)?
+            }
+            // R_RISCV_ALIGN and R_RISCV_RELAX are only hints for linker
+            // relaxation, which is not done here. The padding stays in place.
+            (Machine::EM_RISCV, 43 | 51) => {}
+            (Machine::EM_RISCV, 44) => {
+                check_signed(sap, 9, rtype)?;
+                let v = sap as u16;
+                let bits = (v >> 8 & 1) << 12
+                    | (v >> 3 & 3) << 10
+                    | (v >> 6 & 3) << 5
+                    | (v >> 1 & 3) << 3
+                    | (v >> 5 & 1) << 2;
+                t.patch16(offset, 0xe383, bits)?
+            }
+            (Machine::EM_RISCV, 45) => {
+                check_signed(sap, 12, rtype)?;
+                let v = sap as u16;
+                let bits = (v >> 11 & 1) << 12
+                    | (v >> 4 & 1) << 11
+                    | (v >> 8 & 3) << 9
+                    | (v >> 10 & 1) << 8
+                    | (v >> 6 & 1) << 7
+                    | (v >> 7 & 1) << 6
+                    | (v >> 1 & 7) << 3
+                    | (v >> 5 & 1) << 2;
+                t.patch16(offset, 0xe003, bits)?
+            }
+            (Machine::EM_RISCV, 52) => {
+                let old = t.read::<1>(offset)?;
+                t.write::<1>(offset, (old & 0xc0) | (old.wrapping_sub(sa as u64) & 0x3f))?
+            }
+            (Machine::EM_RISCV, 53) => {
+                let old = t.read::<1>(offset)?;
+                t.write::<1>(offset, (old & 0xc0) | (sa as u64 & 0x3f))?
+            }
+            (Machine::EM_RISCV, 54) => t.write::<1>(offset, sa as u64)?,
+            (Machine::EM_RISCV, 55) => t.write::<2>(offset, sa as u64)?,
+            (Machine::EM_RISCV, 56) => t.write::<4>(offset, sa as u64)?,
+            (Machine::EM_RISCV, 57) => t.write::<4>(offset, sap as u64)?,
+
+            _ => bail!("Unsupported relocation type {rtype} for machine {machine}"),
+        }
+
+        Ok(())
+    }
+}
+
+/// Upper 20 bits for `lui`/`auipc`, adjusted for the sign of the lower 12 bits
+fn hi20(v: i64) -> u32 {
+    (v.wrapping_add(0x800) as u32) & 0xfffff000
+}
+
+fn lo12(v: i64) -> u32 {
+    v as u32 & 0xfff
+}
+
+/// RISC-V S-type immediate bits
+fn s_imm(imm: u32) -> u32 {
+    (imm >> 5 & 0x7f) << 25 | (imm & 0x1f) << 7
+}
+
+/// An allocated section of a relocatable object, after relocation
+///
+/// The file offset in `phdr` is relative to the start of `contents`.
+pub struct Section {
+    pub index: usize,
+    pub phdr: Phdr,
+    pub contents: Rc<[u8]>,
+}
+
+/// A relocatable object, laid out and relocated
+pub struct Relocated {
+    pub sections: Vec<Section>,
+    /// Section headers, with addresses assigned
+    pub shdrs: Vec<Shdr>,
+    /// Symbols, with final addresses
+    pub syms: Vec<Sym>,
+}
+
+/// Address of a symbol in a relocatable object, after its sections have been
+/// given addresses
+fn symbol_address(sym: &Sym, index: u32, shdrs: &[Shdr]) -> anyhow::Result<u64> {
+    let shndx = sym.section_index();
+    match shndx {
+        Sym::SHN_UNDEF if index == 0 || sym.binding() == Sym::STB_WEAK => Ok(0),
+        Sym::SHN_UNDEF => bail!("Undefined symbol {:?}", sym.name()),
+        Sym::SHN_ABS => Ok(sym.value()),
+        Sym::SHN_COMMON => {
+            bail!("Common symbol {:?} (Compile with -fno-common)", sym.name())
+        }
+        _ if shndx < Sym::SHN_LORESERVE => match shdrs.get(usize::from(shndx)) {
+            Some(shdr) => Ok(shdr.address().wrapping_add(sym.value())),
+            None => bail!("Symbol {:?} in invalid section {shndx}", sym.name()),
+        },
+        _ => bail!("Symbol {:?} in unsupported section {shndx:#x}", sym.name()),
+    }
+}
+
+/// Give allocated sections of a relocatable object consecutive addresses
+/// starting at `base`, and apply relocations to them
+pub fn relocate_object(file: &mut File, ehdr: &Ehdr, base: u64) -> anyhow::Result<Relocated> {
+    let mut shdrs = ehdr.read_shdrs(&mut *file)?;
+
+    // Sections without contents go last, so they don't take up space in the
+    // image
+    let mut alloc: Vec<usize> = (0..shdrs.len())
+        .filter(|&i| shdrs[i].flags().alloc() && shdrs[i].size() > 0)
+        .collect();
+    alloc.sort_by_key(|&i| shdrs[i].to_type() == SectionType::SHT_NOBITS);
+
+    let mut address = base;
+    for &i in &alloc {
+        let shdr = &mut shdrs[i];
+        let start = address.checked_next_multiple_of(shdr.alignment().max(1));
+        let Some(end) = start.and_then(|start| start.checked_add(shdr.size())) else {
+            bail!(
+                "Section {name:?} with size {size:#x} placed after {address:#x} goes past the end of the address space",
+                name = shdr.name(),
+                size = shdr.size(),
+            )
+        };
+        shdr.set_address(end - shdr.size());
+        address = end;
+    }
+
+    let mut contents: HashMap<usize, Vec<u8>> = HashMap::new();
+    for &i in &alloc {
+        contents.insert(i, elf::read_section(&mut *file, &shdrs[i])?);
+    }
+
+    for rel in &shdrs {
+        let has_addend = match rel.to_type() {
+            SectionType::SHT_RELA => true,
+            SectionType::SHT_REL => false,
+            _ => continue,
+        };
+
+        let target_index = rel.info() as usize;
+        let Some(data) = contents.get_mut(&target_index) else {
+            continue;
+        };
+        let target_shdr = &shdrs[target_index];
+
+        let syms = ehdr.read_symbol_table(&mut *file, &shdrs, rel.link() as usize)?;
+        let relas = Rela::parse_table(&elf::read_section(&mut *file, rel)?, ehdr, has_addend);
+        let mut target = Target {
+            data,
+            address: target_shdr.address(),
+            little_endian: ehdr.little_endian(),
+        };
+
+        let mut relocator = Relocator::new(ehdr);
+        let (deferred, immediate): (Vec<_>, Vec<_>) =
+            relas.iter().partition(|r| relocator.is_deferred(r.rtype));
+
+        for r in immediate.into_iter().chain(deferred) {
+            let context = || {
+                format!(
+                    "In relocation at offset {offset:#x} of section {name:?}",
+                    offset = r.offset,
+                    name = target_shdr.name(),
+                )
+            };
+            let Some(sym) = syms.get(r.sym as usize) else {
+                bail!("{}: Invalid symbol index {}", context(), r.sym)
+            };
+            let s = symbol_address(sym, r.sym, &shdrs).with_context(context)?;
+            let a = match r.addend {
+                Some(a) => a,
+                None => relocator
+                    .implicit_addend(r.rtype, &mut target, r.offset)
+                    .with_context(context)?,
+            };
+            (relocator.apply(r.rtype, &mut target, r.offset, s, a)).with_context(context)?;
+        }
+    }
+
+    let sections = alloc
+        .iter()
+        .map(|&i| {
+            let shdr = &shdrs[i];
+            let mut flags = Flags::PF_R.0;
+            if shdr.flags().write() {
+                flags |= Flags::PF_W.0;
+            }
+            if shdr.flags().execinstr() {
+                flags |= Flags::PF_X.0;
+            }
+            let data = contents.remove(&i).unwrap_or_default();
+            Section {
+                index: i,
+                phdr: Phdr::synthetic(
+                    Flags(flags),
+                    0,
+                    shdr.address(),
+                    data.len() as u64,
+                    shdr.size(),
+                ),
+                contents: data.into(),
+            }
+        })
+        .collect();
+
+    let mut syms = ehdr.read_symbols(&mut *file, &shdrs)?;
+    for (i, sym) in syms.iter_mut().enumerate() {
+        if let Ok(address) = symbol_address(sym, i as u32, &shdrs) {
+            sym.set_value(address);
+        }
+    }
+
+    Ok(Relocated {
+        sections,
+        shdrs,
+        syms,
+    })
+}
+
+/// A loadable segment of a position-independent executable, read for
+/// relocation
+struct Loaded {
+    index: usize,
+    address: u64,
+    data: Vec<u8>,
+}
+
+/// The loaded segment and offset in it for `size` bytes at link address
+/// `address`
+fn locate(loaded: &mut [Loaded], address: u64, size: u64) -> anyhow::Result<(&mut Loaded, u64)> {
+    let found = loaded.iter_mut().find(|l| {
+        address >= l.address && address.saturating_add(size) <= l.address + l.data.len() as u64
+    });
+    match found {
+        Some(l) => {
+            let offset = address - l.address;
+            Ok((l, offset))
+        }
+        None => bail!("Address {address:#x} is not in the file contents of any loadable segment"),
+    }
+}
+
+/// Apply the `R_*_RELATIVE` relocations of a position-independent executable
+/// for loading at `bias` from its link address
+///
+/// Segments are read with `read`, given the index of each. Returns the
+/// relocated contents of each of `phdrs`, or `None` for those not loaded or if
+/// there are no relocations. Segments going past the end of the file are
+/// always returned, as `read` has filled in the rest.
+pub fn relocate_dynamic(
+    file: &mut File,
+    ehdr: &Ehdr,
+    phdrs: &[Phdr],
+    bias: u64,
+    mut read: impl FnMut(&mut File, usize, &Phdr) -> anyhow::Result<Vec<u8>>,
+) -> anyhow::Result<Vec<Option<Rc<[u8]>>>> {
+    let mut res = vec![None; phdrs.len()];
+    let Some((index, dynamic)) =
+        (phdrs.iter().enumerate()).find(|(_, p)| p.to_type() == Type::PT_DYNAMIC)
+    else {
+        return Ok(res);
+    };
+    let dyns = Dyn::parse_table(&read(file, index, dynamic)?, ehdr);
+    let get = |tag| dyns.iter().find(|d| d.tag == tag).map(|d| d.value);
+
+    let mut loaded = Vec::new();
+    for (index, phdr) in phdrs.iter().enumerate() {
+        if phdr.to_type() == Type::PT_LOAD {
+            loaded.push(Loaded {
+                index,
+                address: phdr.address(),
+                data: read(file, index, phdr)?,
+            });
+        }
+    }
+
+    let mut relocator = Relocator::new(ehdr);
+    let machine = ehdr.machine();
+    let Some(relative) = relocator.relative_type() else {
+        bail!("Relocating position-independent executables for machine {machine} is unsupported")
+    };
+    let little_endian = ehdr.little_endian();
+
+    let pltrel_is_rela = get(DynTag::DT_PLTREL) == Some(DynTag::DT_RELA.0);
+    let tables = [
+        (DynTag::DT_RELA, DynTag::DT_RELASZ, true),
+        (DynTag::DT_REL, DynTag::DT_RELSZ, false),
+        (DynTag::DT_JMPREL, DynTag::DT_PLTRELSZ, pltrel_is_rela),
+    ];
+
+    let mut relas = Vec::new();
+    for (tag, size_tag, has_addend) in tables {
+        let (Some(address), Some(size)) = (get(tag), get(size_tag)) else {
+            continue;
+        };
+        let (l, offset) = locate(&mut loaded, address, size)?;
+        let offset = offset as usize;
+        let data = &l.data[offset..offset + size as usize];
+        relas.extend(Rela::parse_table(data, ehdr, has_addend));
+    }
+
+    let word = match ehdr.class() {
+        Class::ELFCLASS32 => 4,
+        _ => 8,
+    };
+
+    // Each address in DT_RELR gets a relative relocation with an implicit
+    // addend. It is followed by bitmaps for the next words.
+    if let (Some(address), Some(size)) = (get(DynTag::DT_RELR), get(DynTag::DT_RELRSZ)) {
+        let (l, offset) = locate(&mut loaded, address, size)?;
+        let mut table = Target {
+            data: &mut l.data[offset as usize..(offset + size) as usize],
+            address,
+            little_endian,
+        };
+        let mut next = 0;
+        for i in (0..size).step_by(word as usize) {
+            let entry = match word {
+                4 => table.read::<4>(i)?,
+                _ => table.read::<8>(i)?,
+            };
+            if entry & 1 == 0 {
+                relas.push(Rela {
+                    offset: entry,
+                    sym: 0,
+                    rtype: relative,
+                    addend: None,
+                });
+                next = entry + word;
+            } else {
+                for bit in 1..word * 8 {
+                    if entry >> bit & 1 != 0 {
+                        relas.push(Rela {
+                            offset: next + (bit - 1) * word,
+                            sym: 0,
+                            rtype: relative,
+                            addend: None,
+                        });
+                    }
+                }
+                next += (word * 8 - 1) * word;
+            }
+        }
+    }
+
+    for r in &relas {
+        if r.rtype == 0 {
+            continue;
+        }
+        if r.rtype != relative {
+            bail!(
+                "Unsupported dynamic relocation type {rtype} at {offset:#x}, only relative relocations can be applied without a dynamic loader",
+                rtype = r.rtype,
+                offset = r.offset,
+            )
+        }
+
+        let (l, offset) = locate(&mut loaded, r.offset, word)?;
+        let mut target = Target {
+            address: l.address,
+            data: &mut l.data,
+            little_endian,
+        };
+        let a = match r.addend {
+            Some(a) => a,
+            None => relocator.implicit_addend(r.rtype, &mut target, offset)?,
+        };
+        relocator.apply(r.rtype, &mut target, offset, bias, a)?;
+    }
+
+    let file_size = file.metadata()?.len();
+    for l in loaded {
+        let p = &phdrs[l.index];
+        if !relas.is_empty() || p.file_offset().saturating_add(p.file_size()) > file_size {
+            res[l.index] = Some(l.data.into());
+        }
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relocator(machine: Machine, class: Class) -> Relocator {
+        Relocator {
+            machine,
+            class,
+            pcrel_hi: HashMap::new(),
+        }
+    }
+
+    fn words(insns: &[u32]) -> Vec<u8> {
+        insns.iter().flat_map(|i| i.to_le_bytes()).collect()
+    }
+
+    fn word(data: &[u8], i: usize) -> u32 {
+        u32::from_le_bytes(data[i * 4..i * 4 + 4].try_into().unwrap())
+    }
+
+    fn apply(
+        r: &mut Relocator,
+        rtype: u32,
+        data: &mut [u8],
+        address: u64,
+        offset: u64,
+        s: u64,
+        a: i64,
+    ) -> anyhow::Result<()> {
+        let mut target = Target {
+            data,
+            address,
+            little_endian: true,
+        };
+        r.apply(rtype, &mut target, offset, s, a)
+    }
+
+    #[test]
+    fn riscv_jal() {
+        let mut r = relocator(Machine::EM_RISCV, Class::ELFCLASS64);
+        // jal ra, 0
+        let mut data = words(&[0x000000ef]);
+        apply(&mut r, 17, &mut data, 0x1000, 0, 0x1800, 0).unwrap();
+        assert_eq!(word(&data, 0), 0x001000ef);
+
+        let mut data = words(&[0x000000ef]);
+        apply(&mut r, 17, &mut data, 0x1000, 0, 0xffe, 0).unwrap();
+        assert_eq!(word(&data, 0), 0xfffff0ef);
+
+        let mut data = words(&[0x000000ef]);
+        assert!(apply(&mut r, 17, &mut data, 0x1000, 0, 0x10_1000, 0).is_err());
+    }
+
+    #[test]
+    fn riscv_pcrel_hi_lo() {
+        let mut r = relocator(Machine::EM_RISCV, Class::ELFCLASS64);
+        // auipc a0, 0; addi a0, a0, 0
+        let mut data = words(&[0x00000517, 0x00050513]);
+        // The low 12 bits are negative, so the upper 20 bits round up
+        apply(&mut r, 23, &mut data, 0x1000, 0, 0x1000 + 0x1234_5fff, 0).unwrap();
+        assert!(r.is_deferred(24));
+        apply(&mut r, 24, &mut data, 0x1000, 4, 0x1000, 0).unwrap();
+        assert_eq!(word(&data, 0), 0x12346517);
+        assert_eq!(word(&data, 1), 0xfff50513);
+
+        assert!(apply(&mut r, 24, &mut data, 0x1000, 4, 0x2000, 0).is_err());
+    }
+
+    #[test]
+    fn aarch64_adrp_add() {
+        let mut r = relocator(Machine::EM_AARCH64, Class::ELFCLASS64);
+        // adrp x0, 0; add x0, x0, #0
+        let mut data = words(&[0x90000000, 0x91000000]);
+        apply(&mut r, 275, &mut data, 0x1000, 0, 0x5000_0123, 0).unwrap();
+        apply(&mut r, 277, &mut data, 0x1000, 4, 0x5000_0123, 0).unwrap();
+        assert_eq!(word(&data, 0), 0xf027ffe0);
+        assert_eq!(word(&data, 1), 0x91048c00);
+    }
+
+    #[test]
+    fn big_endian_abs64() {
+        let mut r = relocator(Machine::EM_AARCH64, Class::ELFCLASS64);
+        let mut data = [0; 8];
+        let mut target = Target {
+            data: &mut data,
+            address: 0,
+            little_endian: false,
+        };
+        r.apply(257, &mut target, 0, 0x0102_0304_0506_0700, 8)
+            .unwrap();
+        assert_eq!(data, [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn x86_64_pc32() {
+        let mut r = relocator(Machine::EM_X86_64, Class::ELFCLASS64);
+        let mut data = [0; 4];
+        apply(&mut r, 2, &mut data, 0x1000, 0, 0x1014, -4).unwrap();
+        assert_eq!(data, 0x10u32.to_le_bytes());
+        assert!(apply(&mut r, 2, &mut data, 0x1000, 0, 0x1_0000_1000, 0).is_err());
+        assert!(apply(&mut r, 2, &mut data, 0x1000, 2, 0x1000, 0).is_err());
+    }
+
+    #[test]
+    fn arm_call() {
+        let mut r = relocator(Machine::EM_ARM, Class::ELFCLASS32);
+        // bl .
+        let mut data = words(&[0xebfffffe]);
+        let mut target = Target {
+            data: &mut data,
+            address: 0x1000,
+            little_endian: true,
+        };
+        let a = r.implicit_addend(28, &mut target, 0).unwrap();
+        assert_eq!(a, -8);
+        r.apply(28, &mut target, 0, 0x2000, a).unwrap();
+        assert_eq!(word(&data, 0), 0xeb0003fe);
+
+        assert!(apply(&mut r, 28, &mut data, 0x1000, 0, 0x2002, -8).is_err());
+    }
+
+    #[test]
+    fn unsupported() {
+        let mut r = relocator(Machine::EM_386, Class::ELFCLASS32);
+        assert!(apply(&mut r, 9999, &mut [0; 4], 0, 0, 0, 0).is_err());
+        assert_eq!(r.relative_type(), Some(8));
+    }
+}
@@ -0,0 +1,116 @@
+use std::{ffi::OsString, fs, io::Write};
+
+use serde::Serialize;
+
+use crate::{diag, layout::Segment};
+
+/// What was copied where, for other tools to read
+#[derive(Debug, Serialize)]
+pub struct Report {
+    /// Address of the start of the flat binary
+    pub base: u64,
+    /// Size of the flat binary, before compression or encryption
+    pub image_size: u64,
+    /// Size of the space left for a header at the start of the flat binary
+    pub reserved_header: u64,
+    /// Address of the entry point, if there is one
+    pub entry: Option<u64>,
+    /// Offset of the entry point in the flat binary, if it is in it
+    pub entry_offset: Option<u64>,
+    pub inputs: Vec<InputEntry>,
+    pub segments: Vec<SegmentEntry>,
+    pub appended: Vec<AppendedEntry>,
+    pub warnings: Vec<WarningEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InputEntry {
+    pub path: String,
+    /// CPU security features marked in GNU property notes, as in "BTI"
+    pub security_features: Vec<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SegmentEntry {
+    pub input: String,
+    /// Index of the program header in the input file
+    pub index: usize,
+    /// Offset in the input file
+    pub file_offset: u64,
+    pub address: u64,
+    /// Offset in the flat binary
+    pub offset: u64,
+    /// Size of the file contents, which are copied
+    pub size: u64,
+    pub memory_size: u64,
+    /// Flags as in "r-x"
+    pub flags: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AppendedEntry {
+    pub path: String,
+    /// Offset in the flat binary
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WarningEntry {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl Report {
+    pub fn new(
+        inputs: &[OsString],
+        segments: &[Segment],
+        base: u64,
+        reserved_header: u64,
+        image_size: u64,
+    ) -> Self {
+        let segments = (segments.iter())
+            .map(|s| SegmentEntry {
+                input: inputs[s.input].to_string_lossy().into_owned(),
+                index: s.index,
+                file_offset: s.phdr.file_offset(),
+                address: s.phdr.address(),
+                offset: s.phdr.address() - base + reserved_header,
+                size: s.phdr.file_size(),
+                memory_size: s.phdr.memory_size(),
+                flags: s.phdr.flags().to_string(),
+            })
+            .collect();
+
+        let warnings = (diag::take_emitted().into_iter())
+            .map(|(warning, message)| WarningEntry {
+                code: warning.code(),
+                message,
+            })
+            .collect();
+
+        Self {
+            base,
+            image_size,
+            reserved_header,
+            entry: None,
+            entry_offset: None,
+            inputs: Vec::new(),
+            segments,
+            appended: Vec::new(),
+            warnings,
+        }
+    }
+
+    /// Write the report as JSON to a file, or to standard output for `-`
+    pub fn write(&self, path: &OsString) -> anyhow::Result<()> {
+        let mut json = serde_json::to_string_pretty(self)?;
+        json.push('\n');
+        if path == "-" {
+            std::io::stdout().write_all(json.as_bytes())?;
+        } else {
+            fs::write(path, json)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+//! Scripts for loading the image with other tools, at the addresses it was
+//! laid out for
+
+use std::{
+    ffi::{OsStr, OsString},
+    fmt::Write,
+    fs,
+    path::PathBuf,
+};
+
+use crate::layout::Placement;
+
+/// Where the image and the input files ended up
+pub struct Loaded {
+    /// The output file
+    pub image: PathBuf,
+    pub base: u64,
+    pub entry: Option<u64>,
+    /// Input files, and where each ended up
+    pub inputs: Vec<(PathBuf, Placement)>,
+}
+
+/// An absolute path to a file, for scripts run from elsewhere
+pub fn absolute(path: &OsStr) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.into())
+}
+
+/// Quote an argument for a POSIX shell, if it needs it
+fn shell_quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./,=:+@%".contains(c);
+    match !arg.is_empty() && arg.chars().all(plain) {
+        true => arg.to_owned(),
+        false => format!("'{}'", arg.replace('\'', r"'\''")),
+    }
+}
+
+impl Loaded {
+    /// Write a GDB script restoring the image into memory, loading symbols
+    /// from the input files, and setting the PC to the entry point
+    pub fn write_gdb(&self, path: &OsString) -> anyhow::Result<()> {
+        let mut script = String::new();
+        writeln!(script, "# Written by elfcopyflat")?;
+        writeln!(
+            script,
+            "restore {} binary {:#x}",
+            self.image.display(),
+            self.base,
+        )?;
+        for (input, placement) in &self.inputs {
+            // Standard input and archive members are not files GDB can read
+            if !input.is_file() {
+                writeln!(script, "# No symbols from {}", input.display())?;
+                continue;
+            }
+            write!(script, "add-symbol-file {}", input.display())?;
+            match placement {
+                Placement::Moved(0) => {}
+                Placement::Moved(bias) => write!(script, " -o {bias:#x}")?,
+                Placement::Sections(sections) => {
+                    for (name, address) in sections {
+                        write!(script, " -s {name} {address:#x}")?;
+                    }
+                }
+            }
+            writeln!(script)?;
+        }
+        if let Some(entry) = self.entry {
+            writeln!(script, "set $pc = {entry:#x}")?;
+        }
+
+        fs::write(path, script)?;
+        Ok(())
+    }
+
+    /// Write an OpenOCD script programming the image into flash at its base
+    /// address, verifying it, and resetting the target
+    pub fn write_openocd(&self, path: &OsString) -> anyhow::Result<()> {
+        let mut script = String::new();
+        writeln!(script, "# Written by elfcopyflat")?;
+        // Braces quote the path in Tcl
+        writeln!(
+            script,
+            "program {{{}}} {:#x} verify reset",
+            self.image.display(),
+            self.base,
+        )?;
+
+        fs::write(path, script)?;
+        Ok(())
+    }
+
+    /// Write a Renode script loading the image onto the system bus at its base
+    /// address, and setting the PC of `cpu` to the entry point
+    pub fn write_renode(&self, path: &OsString, cpu: &str) -> anyhow::Result<()> {
+        let mut script = String::new();
+        writeln!(script, "# Written by elfcopyflat")?;
+        writeln!(
+            script,
+            "sysbus LoadBinary @{} {:#x}",
+            self.image.display(),
+            self.base,
+        )?;
+        if let Some(entry) = self.entry {
+            writeln!(script, "{cpu} PC {entry:#x}")?;
+        }
+
+        fs::write(path, script)?;
+        Ok(())
+    }
+
+    /// QEMU arguments loading the image at its base address with the generic
+    /// loader, and starting the first CPU at the entry point
+    pub fn qemu_args(&self) -> String {
+        // Commas in option values are doubled
+        let file = self.image.to_string_lossy().replace(',', ",,");
+        let mut args = vec![
+            "-device".to_owned(),
+            format!("loader,file={file},addr={:#x},force-raw=on", self.base),
+        ];
+        if let Some(entry) = self.entry {
+            args.push("-device".to_owned());
+            args.push(format!("loader,addr={entry:#x},cpu-num=0"));
+        }
+        let args: Vec<String> = args.iter().map(|arg| shell_quote(arg)).collect();
+        args.join(" ")
+    }
+}
@@ -0,0 +1,145 @@
+use crate::elf::Flags;
+
+/// Expression over segment flags, for `--select`
+///
+/// Flags are `r`, `w` and `x`, combined with `!`, `&` and `|` in order of
+/// decreasing precedence, with parentheses for grouping.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Flag(u32),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let tokens: Vec<char> = s.chars().filter(|c| !c.is_whitespace()).collect();
+        let mut parser = Parser { tokens, pos: 0 };
+        let res = parser.or()?;
+        match parser.peek() {
+            None => Ok(res),
+            Some(c) => Err(format!("Unexpected '{c}'")),
+        }
+    }
+
+    pub fn matches(&self, flags: Flags) -> bool {
+        match self {
+            Expr::Flag(f) => flags.0 & f != 0,
+            Expr::Not(e) => !e.matches(flags),
+            Expr::And(a, b) => a.matches(flags) && b.matches(flags),
+            Expr::Or(a, b) => a.matches(flags) || b.matches(flags),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let res = self.peek();
+        self.pos += 1;
+        res
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut res = self.and()?;
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            res = Expr::Or(Box::new(res), Box::new(self.and()?));
+        }
+        Ok(res)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut res = self.unary()?;
+        while self.peek() == Some('&') {
+            self.pos += 1;
+            res = Expr::And(Box::new(res), Box::new(self.unary()?));
+        }
+        Ok(res)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        let res = match self.next() {
+            Some('!') => Expr::Not(Box::new(self.unary()?)),
+            Some('(') => {
+                let res = self.or()?;
+                if self.next() != Some(')') {
+                    return Err("Missing ')'".to_owned());
+                }
+                res
+            }
+            Some('r' | 'R') => Expr::Flag(Flags::PF_R.0),
+            Some('w' | 'W') => Expr::Flag(Flags::PF_W.0),
+            Some('x' | 'X') => Expr::Flag(Flags::PF_X.0),
+            Some(c) => return Err(format!("Unexpected '{c}'")),
+            None => return Err("Unexpected end of expression".to_owned()),
+        };
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flag(f: Flags) -> Box<Expr> {
+        Box::new(Expr::Flag(f.0))
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(
+            Expr::parse("r | w & !x").unwrap(),
+            Expr::Or(
+                flag(Flags::PF_R),
+                Box::new(Expr::And(
+                    flag(Flags::PF_W),
+                    Box::new(Expr::Not(flag(Flags::PF_X)))
+                )),
+            ),
+        );
+        assert_eq!(
+            Expr::parse("(R|W)&X").unwrap(),
+            Expr::And(
+                Box::new(Expr::Or(flag(Flags::PF_R), flag(Flags::PF_W))),
+                flag(Flags::PF_X),
+            ),
+        );
+        assert_eq!(
+            Expr::parse("!!r").unwrap(),
+            Expr::Not(Box::new(Expr::Not(flag(Flags::PF_R)))),
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            Expr::parse("r &").unwrap_err(),
+            "Unexpected end of expression"
+        );
+        assert_eq!(Expr::parse("(r | w").unwrap_err(), "Missing ')'");
+        assert_eq!(Expr::parse("r w").unwrap_err(), "Unexpected 'w'");
+        assert_eq!(Expr::parse("r | z").unwrap_err(), "Unexpected 'z'");
+        assert!(Expr::parse("").is_err());
+    }
+
+    #[test]
+    fn matches() {
+        let rx = Flags(Flags::PF_R.0 | Flags::PF_X.0);
+        let rw = Flags(Flags::PF_R.0 | Flags::PF_W.0);
+        let e = Expr::parse("r & !w | x").unwrap();
+        assert!(e.matches(rx));
+        assert!(!e.matches(rw));
+        assert!(e.matches(Flags::PF_X));
+        assert!(!e.matches(Flags(0)));
+    }
+}
@@ -0,0 +1,467 @@
+//! Serving the image over the network, converted again whenever an input file
+//! changes, for boards that fetch it while booting or lab devices that pull it
+
+use std::{
+    ffi::OsString,
+    fs,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context};
+use clap::Args;
+use clap_num::maybe_hex;
+use serde::Serialize;
+
+use crate::{
+    hash,
+    image::{Chunk, Data},
+    layout::{self, LayoutArgs},
+    log, mmap,
+};
+
+#[derive(Debug, Args)]
+pub struct ServeArgs {
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// Fill gaps between segments with this byte instead of zeros
+    #[arg(long, value_name = "BYTE", value_parser = maybe_hex::<u8>, env = "ELFCOPYFLAT_GAP_FILL")]
+    gap_fill: Option<u8>,
+
+    /// Serve the image over TFTP on this address, such as 0.0.0.0:69
+    #[arg(long, value_name = "ADDRESS", required_unless_present = "http")]
+    tftp: Option<SocketAddr>,
+
+    /// Serve the image over HTTP on this address, such as 0.0.0.0:8080, with
+    /// a JSON index at `/`
+    #[arg(long, value_name = "ADDRESS")]
+    http: Option<SocketAddr>,
+
+    /// File name to serve the image as (Default: The first input file with
+    /// the extension .bin)
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Input ELF, PE or Mach-O files
+    #[arg(required = true)]
+    input: Vec<OsString>,
+}
+
+/// When each input file was last changed, to tell when to convert again
+type Stamps = Vec<Option<(SystemTime, u64)>>;
+
+/// A converted image, and what the index says about it
+struct Converted {
+    stamps: Stamps,
+    data: Arc<Vec<u8>>,
+    index: Index,
+}
+
+/// What the HTTP index says about the image
+#[derive(Debug, Clone, Serialize)]
+struct Index {
+    name: String,
+    base: u64,
+    entry: Option<u64>,
+    size: u64,
+    sha256: String,
+}
+
+/// The image, converted again when an input file changes
+struct Cache<'a> {
+    args: &'a ServeArgs,
+    name: String,
+    converted: Mutex<Option<Converted>>,
+}
+
+impl Cache<'_> {
+    fn stamps(&self) -> Stamps {
+        (self.args.input.iter())
+            .map(|path| {
+                let metadata = fs::metadata(path).ok()?;
+                Some((metadata.modified().ok()?, metadata.len()))
+            })
+            .collect()
+    }
+
+    fn convert(&self, stamps: Stamps) -> anyhow::Result<Converted> {
+        let mut layout = layout::layout(&self.args.input, &self.args.layout)?;
+        let image = &mut layout.image;
+        if let Some(byte) = self.args.gap_fill {
+            image.push_under(Chunk {
+                offset: 0,
+                size: image.size(),
+                data: Data::Fill { byte },
+            });
+        }
+        let data = image.read_to_vec()?;
+        let index = Index {
+            name: self.name.clone(),
+            base: layout.base,
+            entry: layout.entry,
+            size: data.len() as u64,
+            sha256: hash::sha256(&data[..])?,
+        };
+        Ok(Converted {
+            stamps,
+            data: Arc::new(data),
+            index,
+        })
+    }
+
+    /// The image and its index, converting it if it has not been, or an input
+    /// file changed
+    fn get(&self) -> anyhow::Result<(Arc<Vec<u8>>, Index)> {
+        let mut converted = self.converted.lock().unwrap();
+        let stamps = self.stamps();
+        let fresh = converted.as_ref().is_some_and(|c| c.stamps == stamps);
+        if !fresh {
+            let new = self.convert(stamps)?;
+            log::info!("Converted image of {:#x} bytes", new.data.len());
+            *converted = Some(new);
+        }
+
+        let converted = converted.as_ref().unwrap();
+        Ok((converted.data.clone(), converted.index.clone()))
+    }
+}
+
+const RRQ: u16 = 1;
+const DATA: u16 = 3;
+const ACK: u16 = 4;
+const ERROR: u16 = 5;
+const OACK: u16 = 6;
+
+const ERROR_UNDEFINED: u16 = 0;
+const ERROR_NOT_FOUND: u16 = 1;
+const ERROR_ACCESS: u16 = 2;
+const ERROR_OPTIONS: u16 = 8;
+
+const DEFAULT_BLOCK_SIZE: usize = 512;
+const MAX_BLOCK_SIZE: usize = 65464;
+/// How long to wait for an acknowledgement before sending again
+const TFTP_TIMEOUT: Duration = Duration::from_secs(1);
+const TFTP_RETRIES: u32 = 5;
+
+/// A read request, with its file name and options
+struct Request {
+    name: String,
+    options: Vec<(String, String)>,
+}
+
+fn parse_request(packet: &[u8]) -> Option<Request> {
+    let opcode = u16::from_be_bytes(packet.get(..2)?.try_into().ok()?);
+    if opcode != RRQ {
+        return None;
+    }
+    let mut fields = packet[2..]
+        .split(|&b| b == 0)
+        .map(|f| String::from_utf8_lossy(f).into_owned());
+    let name = fields.next()?;
+    let _mode = fields.next()?;
+    let mut options = Vec::new();
+    while let (Some(key), Some(value)) = (fields.next(), fields.next()) {
+        options.push((key.to_ascii_lowercase(), value));
+    }
+    Some(Request { name, options })
+}
+
+fn error_packet(code: u16, message: &str) -> Vec<u8> {
+    let mut packet = Vec::new();
+    packet.extend(ERROR.to_be_bytes());
+    packet.extend(code.to_be_bytes());
+    packet.extend(message.as_bytes());
+    packet.push(0);
+    packet
+}
+
+/// Send `packet` until the peer acknowledges `block`
+fn send_until_ack(socket: &UdpSocket, packet: &[u8], block: u16) -> anyhow::Result<()> {
+    let mut buf = [0; 516];
+    for _ in 0..TFTP_RETRIES {
+        socket.send(packet)?;
+        loop {
+            let n = match socket.recv(&mut buf) {
+                Ok(n) => n,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+                Err(e) => return Err(e.into()),
+            };
+            match u16::from_be_bytes([buf[0], buf[1]]) {
+                _ if n < 4 => {}
+                ACK if u16::from_be_bytes([buf[2], buf[3]]) == block => return Ok(()),
+                ERROR => bail!("Client gave up: {}", String::from_utf8_lossy(&buf[4..n])),
+                _ => {}
+            }
+        }
+    }
+    bail!("Client stopped acknowledging, at block {block}")
+}
+
+/// Send the image to a client, on a socket of its own
+fn transfer(
+    cache: &Cache,
+    request: Request,
+    peer: SocketAddr,
+    local: SocketAddr,
+) -> anyhow::Result<()> {
+    let name = &cache.name;
+    let socket = UdpSocket::bind(SocketAddr::new(local.ip(), 0))?;
+    socket.connect(peer)?;
+    socket.set_read_timeout(Some(TFTP_TIMEOUT))?;
+
+    if request.name.trim_start_matches('/') != name {
+        socket.send(&error_packet(ERROR_NOT_FOUND, "File not found"))?;
+        bail!("Client asked for {:?}, not {name:?}", request.name)
+    }
+
+    let data = match cache.get() {
+        Ok((data, _)) => data,
+        Err(e) => {
+            socket.send(&error_packet(ERROR_UNDEFINED, "Cannot convert image"))?;
+            return Err(e);
+        }
+    };
+
+    // Options for larger blocks and the transfer size are acknowledged, and
+    // others ignored
+    let mut block_size = DEFAULT_BLOCK_SIZE;
+    let mut oack = Vec::new();
+    for (key, value) in &request.options {
+        match key.as_str() {
+            "blksize" => {
+                let Ok(size) = value.parse::<usize>() else {
+                    socket.send(&error_packet(ERROR_OPTIONS, "Bad blksize"))?;
+                    bail!("Client asked for block size {value:?}")
+                };
+                block_size = size.clamp(8, MAX_BLOCK_SIZE);
+                oack.push(("blksize", block_size.to_string()));
+            }
+            "tsize" => oack.push(("tsize", data.len().to_string())),
+            _ => {}
+        }
+    }
+    if !oack.is_empty() {
+        let mut packet = OACK.to_be_bytes().to_vec();
+        for (key, value) in oack {
+            packet.extend(key.as_bytes());
+            packet.push(0);
+            packet.extend(value.as_bytes());
+            packet.push(0);
+        }
+        send_until_ack(&socket, &packet, 0)?;
+    }
+
+    // The last block is short, even if empty
+    let count = data.len() / block_size + 1;
+    for i in 0..count {
+        let block = (i + 1) as u16;
+        let start = i * block_size;
+        let end = (start + block_size).min(data.len());
+        let mut packet = Vec::with_capacity(4 + end - start);
+        packet.extend(DATA.to_be_bytes());
+        packet.extend(block.to_be_bytes());
+        packet.extend(&data[start..end]);
+        send_until_ack(&socket, &packet, block)?;
+    }
+    Ok(())
+}
+
+fn serve_tftp(cache: &Cache, addr: SocketAddr) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind(addr).with_context(|| format!("Cannot listen on {addr}"))?;
+    let local = socket.local_addr()?;
+    let name = &cache.name;
+    log::info!("Serving {name:?} over TFTP on {local}");
+
+    thread::scope(|s| loop {
+        let mut buf = [0; 1024];
+        let (n, peer) = match socket.recv_from(&mut buf) {
+            Ok(res) => res,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let Some(request) = parse_request(&buf[..n]) else {
+            let _ = socket.send_to(
+                &error_packet(ERROR_ACCESS, "Only reading is supported"),
+                peer,
+            );
+            continue;
+        };
+        s.spawn(move || match transfer(cache, request, peer, local) {
+            Ok(()) => log::info!("Sent {name:?} to {peer}"),
+            Err(e) => log::error!("Cannot send {name:?} to {peer}: {e:#}"),
+        });
+    })
+}
+
+/// Write an HTTP response, with a body unless answering HEAD
+fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+    head: bool,
+) -> anyhow::Result<()> {
+    let mut w = Vec::new();
+    write!(w, "HTTP/1.1 {status}\r\n")?;
+    for (key, value) in headers {
+        write!(w, "{key}: {value}\r\n")?;
+    }
+    write!(
+        w,
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(&w)?;
+    if !head {
+        stream.write_all(body)?;
+    }
+    Ok(())
+}
+
+/// Answer one request on a connection
+fn handle(cache: &Cache, stream: &mut TcpStream) -> anyhow::Result<String> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let path = target.split('?').next().unwrap_or("");
+
+    let mut if_none_match = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((key, value)) = header.split_once(':') {
+            if key.trim().eq_ignore_ascii_case("if-none-match") {
+                if_none_match = Some(value.trim().to_owned());
+            }
+        }
+    }
+
+    let head = method == "HEAD";
+    if method != "GET" && !head {
+        respond(
+            stream,
+            "405 Method Not Allowed",
+            &[("Allow", "GET, HEAD".to_owned())],
+            b"",
+            head,
+        )?;
+        return Ok(format!("{method} {target} 405"));
+    }
+
+    let (data, index) = match cache.get() {
+        Ok(res) => res,
+        Err(e) => {
+            let message = format!("Cannot convert image: {e:#}\n");
+            respond(
+                stream,
+                "500 Internal Server Error",
+                &[],
+                message.as_bytes(),
+                head,
+            )?;
+            return Err(e);
+        }
+    };
+
+    // The hash of the contents tells whether a client already has them
+    let etag = format!("\"{}\"", index.sha256);
+    let status = if path == "/" || path == "/index.json" {
+        let mut json = serde_json::to_string_pretty(&index)?;
+        json.push('\n');
+        let headers = [("Content-Type", "application/json".to_owned())];
+        respond(stream, "200 OK", &headers, json.as_bytes(), head)?;
+        "200"
+    } else if path.trim_start_matches('/') != cache.name {
+        respond(stream, "404 Not Found", &[], b"Not found\n", head)?;
+        "404"
+    } else if if_none_match
+        .as_deref()
+        .is_some_and(|tags| tags.split(',').any(|t| t.trim() == etag || t.trim() == "*"))
+    {
+        respond(stream, "304 Not Modified", &[("ETag", etag)], b"", true)?;
+        "304"
+    } else {
+        let headers = [
+            ("Content-Type", "application/octet-stream".to_owned()),
+            ("ETag", etag),
+        ];
+        respond(stream, "200 OK", &headers, &data, head)?;
+        "200"
+    };
+    Ok(format!("{method} {target} {status}"))
+}
+
+fn serve_http(cache: &Cache, addr: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).with_context(|| format!("Cannot listen on {addr}"))?;
+    log::info!(
+        "Serving {name:?} over HTTP on {local}",
+        name = cache.name,
+        local = listener.local_addr()?,
+    );
+
+    thread::scope(|s| {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            s.spawn(move || {
+                let peer = stream
+                    .peer_addr()
+                    .map(|a| a.to_string())
+                    .unwrap_or_default();
+                match handle(cache, &mut stream) {
+                    Ok(request) => log::info!("{peer}: {request}"),
+                    Err(e) => log::error!("Cannot answer {peer}: {e:#}"),
+                }
+            });
+        }
+        Ok(())
+    })
+}
+
+/// Serve the image, converting the input files on demand
+pub fn run(args: ServeArgs) -> anyhow::Result<()> {
+    if args.input.iter().any(|path| path == "-") {
+        bail!("Cannot serve an image from standard input, which can only be read once")
+    }
+    let name = match &args.name {
+        Some(name) => name.clone(),
+        None => {
+            let path = Path::new(&args.input[0]).with_extension("bin");
+            (path.file_name().unwrap_or_default().to_string_lossy()).into_owned()
+        }
+    };
+
+    // Input files are rebuilt while serving, and a mapped file that is
+    // truncated crashes whoever reads past its new end
+    mmap::disable();
+
+    let cache = Cache {
+        args: &args,
+        name,
+        converted: Mutex::new(None),
+    };
+    // Convert right away, to fail early on bad input files
+    cache.get()?;
+
+    let cache = &cache;
+    thread::scope(|s| {
+        let tftp = (args.tftp).map(|addr| s.spawn(move || serve_tftp(cache, addr)));
+        let http = (args.http).map(|addr| s.spawn(move || serve_http(cache, addr)));
+        for server in tftp.into_iter().chain(http) {
+            server.join().expect("Server thread panicked")?;
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,115 @@
+use std::{mem::size_of, rc::Rc};
+
+use anyhow::bail;
+use clap::Args;
+use clap_num::maybe_hex;
+use zerocopy::{AsBytes, FromBytes, FromZeroes, LittleEndian, U16, U32, U64};
+
+use crate::{hash::crc32, image::Image};
+
+/// Options for A/B dual-slot images
+#[derive(Debug, Clone, Args)]
+pub struct SlotArgs {
+    /// Put two copies of the image, each preceded by a slot header, into
+    /// consecutive slots of this size
+    #[arg(long, value_name = "SIZE", value_parser = maybe_hex::<u64>)]
+    pub ab_slot_size: Option<u64>,
+
+    /// Space reserved for the slot header before the image in each slot
+    #[arg(
+        long,
+        value_name = "SIZE",
+        default_value = "0x100",
+        value_parser = maybe_hex::<u64>,
+        requires = "ab_slot_size"
+    )]
+    pub ab_header_size: u64,
+
+    /// Sequence number for slot A
+    #[arg(
+        long,
+        value_name = "N",
+        default_value = "1",
+        value_parser = maybe_hex::<u32>,
+        requires = "ab_slot_size"
+    )]
+    pub ab_sequence: u32,
+
+    /// Sequence number for slot B (Defaults to the same as slot A)
+    #[arg(long, value_name = "N", value_parser = maybe_hex::<u32>, requires = "ab_slot_size")]
+    pub ab_sequence_b: Option<u32>,
+}
+
+/// Slot header at the start of each slot
+///
+/// All fields are little endian.
+#[derive(Debug, Clone, FromZeroes, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct SlotHeader {
+    magic: [u8; 4],
+    version: U16<LittleEndian>,
+    /// 0 for slot A, 1 for slot B
+    slot: U16<LittleEndian>,
+    sequence: U32<LittleEndian>,
+    /// CRC-32 of the image
+    crc32: U32<LittleEndian>,
+    /// Offset of the image from the start of the slot
+    offset: U64<LittleEndian>,
+    size: U64<LittleEndian>,
+    /// Address the image is to be loaded at
+    base: U64<LittleEndian>,
+}
+
+impl SlotHeader {
+    pub const MAGIC: [u8; 4] = *b"ECFS";
+    pub const VERSION: u16 = 1;
+}
+
+/// Build an A/B image containing two copies of `payload`
+pub fn ab_image(
+    payload: &mut Image,
+    slot_size: u64,
+    args: &SlotArgs,
+    base: u64,
+) -> anyhow::Result<Image> {
+    if args.ab_header_size < size_of::<SlotHeader>() as u64 {
+        bail!(
+            "Slot header size {:#x} is smaller than the header itself ({:#x} bytes)",
+            args.ab_header_size,
+            size_of::<SlotHeader>(),
+        )
+    }
+
+    let payload = payload.read_to_vec()?;
+    let needed = args.ab_header_size + payload.len() as u64;
+    if needed > slot_size {
+        bail!("Image with slot header needs {needed:#x} bytes, which does not fit in slot size {slot_size:#x}")
+    }
+
+    let crc32 = crc32(&payload);
+    let mut image = Image::default();
+    let payload: Rc<[u8]> = payload.into();
+    let sequences = [
+        args.ab_sequence,
+        args.ab_sequence_b.unwrap_or(args.ab_sequence),
+    ];
+
+    for (slot, sequence) in sequences.into_iter().enumerate() {
+        let header = SlotHeader {
+            magic: SlotHeader::MAGIC,
+            version: SlotHeader::VERSION.into(),
+            slot: (slot as u16).into(),
+            sequence: sequence.into(),
+            crc32: crc32.into(),
+            offset: args.ab_header_size.into(),
+            size: (payload.len() as u64).into(),
+            base: base.into(),
+        };
+        let start = slot_size * slot as u64;
+        image.push_bytes(start, header.as_bytes());
+        image.push_bytes(start + args.ab_header_size, payload.clone());
+    }
+
+    image.min_size = slot_size * 2;
+    Ok(image)
+}
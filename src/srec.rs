@@ -0,0 +1,138 @@
+//! Motorola S-records, which like Intel HEX give the address of each record,
+//! so that gaps between chunks take no space
+
+use std::io::Write;
+
+use anyhow::bail;
+
+use crate::image::Image;
+
+/// Data bytes in each record
+const RECORD_SIZE: usize = 16;
+
+fn record(w: &mut dyn Write, kind: u8, address: &[u8], data: &[u8]) -> anyhow::Result<()> {
+    // The count covers the address, data and checksum
+    let mut bytes = vec![(address.len() + data.len() + 1) as u8];
+    bytes.extend(address);
+    bytes.extend(data);
+    let checksum = !bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    bytes.push(checksum);
+
+    write!(w, "S{kind}")?;
+    for b in bytes {
+        write!(w, "{b:02X}")?;
+    }
+    writeln!(w)?;
+    Ok(())
+}
+
+/// Write the chunks of an image starting at address `base`, ending with the
+/// start address, which some programmers set the PC to, or zero
+///
+/// Addresses are 16, 24 or 32 bits, the fewest that fit everything, and the
+/// record types are picked to match (S1 and S9, S2 and S8, or S3 and S7).
+pub fn write(
+    image: &mut Image,
+    base: u64,
+    start: Option<u64>,
+    w: &mut dyn Write,
+) -> anyhow::Result<()> {
+    let chunks = image.resolved();
+    let end = (chunks.iter().map(|c| base + c.end()))
+        .chain(start.map(|start| start.saturating_add(1)))
+        .max()
+        .unwrap_or(0);
+    let (data_kind, start_kind, address_size) = match end {
+        0..=0x1_0000 => (1, 9, 2),
+        0x1_0001..=0x100_0000 => (2, 8, 3),
+        0x100_0001..=0x1_0000_0000 => (3, 7, 4),
+        _ => bail!("S-records only have 32-bit addresses, but contents end at {end:#x}"),
+    };
+    let address_bytes = |address: u64| address.to_be_bytes()[8 - address_size..].to_vec();
+
+    // An empty header record
+    record(w, 0, &[0, 0], &[])?;
+
+    let mut records = 0u32;
+    for chunk in chunks {
+        let mut data = Vec::new();
+        image.copy_chunk(&chunk, &mut data)?;
+        let mut address = base + chunk.offset;
+        for piece in data.chunks(RECORD_SIZE) {
+            record(w, data_kind, &address_bytes(address), piece)?;
+            address += piece.len() as u64;
+            records += 1;
+        }
+    }
+
+    // The count is 16 bits in S5 and 24 bits in S6, and left out if there are
+    // too many records for either
+    if records <= 0xffff {
+        record(w, 5, &(records as u16).to_be_bytes(), &[])?;
+    } else if records <= 0xff_ffff {
+        record(w, 6, &records.to_be_bytes()[1..], &[])?;
+    }
+    record(w, start_kind, &address_bytes(start.unwrap_or(0)), &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(image: &mut Image, base: u64, start: Option<u64>) -> Vec<String> {
+        let mut out = Vec::new();
+        write(image, base, start, &mut out).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    #[test]
+    fn record_checksum() {
+        let mut out = Vec::new();
+        let mut data = vec![0x0a, 0x0a, 0x0d];
+        data.resize(16, 0);
+        record(&mut out, 1, &[0x7a, 0xf0], &data).unwrap();
+        record(&mut out, 9, &[0, 0], &[]).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "S1137AF00A0A0D0000000000000000000000000061\nS9030000FC\n",
+        );
+    }
+
+    #[test]
+    fn address_size() {
+        let mut image = Image::default();
+        image.push_bytes(0, vec![0x55]);
+
+        let out = lines(&mut image, 0xfffe, None);
+        assert_eq!(out[1], "S104FFFE55A9");
+        assert_eq!(out[3], "S9030000FC");
+
+        let out = lines(&mut image, 0x1_0000, None);
+        assert_eq!(out[1], "S20501000055A4");
+        assert_eq!(out[3], "S804000000FB");
+
+        let out = lines(&mut image, 0x100_0000, Some(0x100_0000));
+        assert_eq!(out[1], "S3060100000055A3");
+        assert_eq!(out[3], "S70501000000F9");
+    }
+
+    #[test]
+    fn start_address_picks_size() {
+        let mut image = Image::default();
+        image.push_bytes(0, vec![0x55]);
+        let out = lines(&mut image, 0, Some(0x2_0000));
+        assert!(out[1].starts_with("S2"));
+        assert!(out.last().unwrap().starts_with("S8"));
+    }
+
+    #[test]
+    fn address_too_large() {
+        let mut image = Image::default();
+        image.push_bytes(0, vec![0; 2]);
+        assert!(write(&mut image, 0xffff_ffff, None, &mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+//! Temporary files with random names, only accessible to the current user
+
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io,
+    path::PathBuf,
+};
+
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+
+/// How many random names to try before giving up
+const ATTEMPTS: u32 = 16;
+
+/// Create a new file with a random name in the temporary directory, which
+/// nobody else can have created or opened
+///
+/// With `delete_on_close`, Windows removes the file once it is closed.
+fn create(suffix: &str, delete_on_close: bool) -> io::Result<(PathBuf, File)> {
+    for _ in 0..ATTEMPTS {
+        let path = env::temp_dir().join(format!("elfcopyflat-{:016x}{suffix}", OsRng.next_u64()));
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        #[cfg(windows)]
+        if delete_on_close {
+            use std::os::windows::fs::OpenOptionsExt;
+            const FILE_FLAG_DELETE_ON_CLOSE: u32 = 0x04000000;
+            const FILE_SHARE_ALL: u32 = 0x7;
+            options
+                .custom_flags(FILE_FLAG_DELETE_ON_CLOSE)
+                .share_mode(FILE_SHARE_ALL);
+        }
+        #[cfg(not(windows))]
+        let _ = delete_on_close;
+
+        match options.open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "Cannot find an unused name for a temporary file",
+    ))
+}
+
+/// Create an anonymous temporary file, which goes away once closed
+pub fn anonymous() -> io::Result<File> {
+    let (path, file) = create("", true)?;
+    // On Unix-like systems the open file stays usable after removal. Windows
+    // removes it on close instead.
+    if cfg!(not(windows)) {
+        fs::remove_file(&path)?;
+    }
+    Ok(file)
+}
+
+/// A temporary file with a name, for other programs to read, removed when
+/// dropped
+#[cfg(feature = "flash")]
+pub struct Named {
+    path: PathBuf,
+    file: File,
+}
+
+#[cfg(feature = "flash")]
+impl Named {
+    /// Create a named temporary file, with `suffix` at the end of its name
+    pub fn new(suffix: &str) -> io::Result<Self> {
+        let (path, file) = create(suffix, false)?;
+        Ok(Self { path, file })
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+#[cfg(feature = "flash")]
+impl Drop for Named {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
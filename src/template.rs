@@ -0,0 +1,53 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fs::File,
+};
+
+use anyhow::{bail, Context};
+
+use crate::{elf, hash};
+
+/// Expand placeholders in an output path
+///
+/// `{buildid}` is the GNU build ID of the first input file in hexadecimal, and
+/// `{buildidN}` is its first N digits. Anything else is left as is.
+pub fn expand(template: &OsStr, first_input: &File) -> anyhow::Result<OsString> {
+    let Some(mut rest) = template.to_str() else {
+        return Ok(template.to_owned());
+    };
+    if !rest.contains("{buildid") {
+        return Ok(template.to_owned());
+    }
+
+    let build_id =
+        elf::read_build_id(first_input).context("Cannot read build ID for output file name")?;
+    let Some(build_id) = build_id else {
+        bail!("First input file has no build ID for {template:?}")
+    };
+    let build_id = hash::hex(&build_id);
+
+    let mut res = String::new();
+    while let Some(start) = rest.find("{buildid") {
+        res.push_str(&rest[..start]);
+        let after = &rest[start + "{buildid".len()..];
+        let Some(end) = after.find('}') else {
+            bail!("Missing '}}' in {template:?}")
+        };
+
+        let digits = match &after[..end] {
+            "" => build_id.len(),
+            n => match n.parse::<usize>() {
+                Ok(n) if n > 0 && n <= build_id.len() => n,
+                _ => bail!(
+                    "Invalid build ID length {n:?} in {template:?} (Build ID has {} digits)",
+                    build_id.len()
+                ),
+            },
+        };
+        res.push_str(&build_id[..digits]);
+        rest = &after[end + 1..];
+    }
+    res.push_str(rest);
+
+    Ok(res.into())
+}
@@ -0,0 +1,85 @@
+use std::time::{Duration, Instant};
+
+/// How long each step of copying took, for `--timings`
+#[derive(Debug)]
+pub struct Timings {
+    start: Instant,
+    last: Instant,
+    steps: Vec<(&'static str, Duration)>,
+}
+
+/// How long copying one chunk took
+#[derive(Debug, Clone)]
+pub struct ChunkTime {
+    pub offset: u64,
+    pub size: u64,
+    pub time: Duration,
+}
+
+impl Timings {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            start: now,
+            last: now,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Record that `name` took the time since the last step
+    pub fn step(&mut self, name: &'static str) {
+        let now = Instant::now();
+        self.steps.push((name, now - self.last));
+        self.last = now;
+    }
+
+    /// Print the steps, and how long copying each of `contents` took
+    ///
+    /// `contents` are names with offsets and sizes. Chunks not in any of them,
+    /// such as gap fills, are counted separately.
+    pub fn print(&self, chunks: &[ChunkTime], contents: &[(String, u64, u64)]) {
+        let mut per_item = vec![(0, Duration::ZERO); contents.len()];
+        let mut other = (0, Duration::ZERO);
+        for c in chunks {
+            let item = (contents.iter())
+                .position(|&(_, offset, size)| c.offset >= offset && c.offset - offset < size);
+            let (size, time) = match item {
+                Some(i) => &mut per_item[i],
+                None => &mut other,
+            };
+            *size += c.size;
+            *time += c.time;
+        }
+
+        println!("Timings:");
+        for (name, time) in &self.steps {
+            match *name {
+                "Copy" => {
+                    let size = chunks.iter().map(|c| c.size).sum();
+                    println!("  {name}: {}", rate(size, *time));
+                    for ((name, _, _), &(size, time)) in contents.iter().zip(&per_item) {
+                        println!("    {name}: {}", rate(size, time));
+                    }
+                    if other.0 > 0 {
+                        println!("    Other: {}", rate(other.0, other.1));
+                    }
+                }
+                _ => println!("  {name}: {}", ms(*time)),
+            }
+        }
+        println!("  Total: {}", ms(self.last - self.start));
+    }
+}
+
+fn ms(time: Duration) -> String {
+    format!("{:.3} ms", time.as_secs_f64() * 1000.0)
+}
+
+/// Time taken for `size` bytes, and throughput
+fn rate(size: u64, time: Duration) -> String {
+    let mib = size as f64 / (1 << 20) as f64;
+    match time.as_secs_f64() {
+        0.0 => format!("{}, {size:#x} bytes", ms(time)),
+        secs => format!("{}, {size:#x} bytes, {:.1} MiB/s", ms(time), mib / secs),
+    }
+}
@@ -0,0 +1,317 @@
+//! Uploading an image to a serial bootloader with XMODEM or YMODEM
+
+use std::{
+    ffi::{OsStr, OsString},
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use clap::{Args, ValueEnum};
+use clap_num::maybe_hex;
+
+use crate::{
+    image::{Chunk, Data},
+    layout::{self, LayoutArgs},
+    log,
+};
+
+#[derive(Debug, Args)]
+pub struct UploadArgs {
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// Fill gaps between segments with this byte instead of zeros
+    #[arg(long, value_name = "BYTE", value_parser = maybe_hex::<u8>, env = "ELFCOPYFLAT_GAP_FILL")]
+    gap_fill: Option<u8>,
+
+    /// Serial port the bootloader is on, such as /dev/ttyUSB0
+    #[arg(long)]
+    port: OsString,
+
+    /// Baud rate of the serial port
+    #[arg(long, default_value_t = 115200)]
+    baud: u32,
+
+    /// Protocol the bootloader speaks
+    #[arg(long, default_value = "xmodem")]
+    protocol: Protocol,
+
+    /// Seconds to wait for the bootloader to ask for the image
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    timeout: u64,
+
+    /// Input ELF, PE or Mach-O files
+    #[arg(required = true)]
+    input: Vec<OsString>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Protocol {
+    /// XMODEM with 128 byte blocks, with a CRC or checksum as the receiver
+    /// asks
+    Xmodem,
+    /// XMODEM with 1024 byte blocks
+    #[value(name = "xmodem-1k")]
+    Xmodem1k,
+    /// YMODEM, sending the file name and size before 1024 byte blocks
+    Ymodem,
+}
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1a;
+/// Sent by the receiver instead of NAK to ask for CRCs
+const CRC: u8 = b'C';
+
+/// How many times to send a block before giving up
+const RETRIES: u32 = 10;
+/// How long to wait for the receiver to acknowledge a block
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A serial port in raw mode, with reads timing out
+struct Serial {
+    file: File,
+}
+
+#[cfg(unix)]
+fn configure(file: &File, baud: u32) -> anyhow::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let speed = match baud {
+        1200 => libc::B1200,
+        2400 => libc::B2400,
+        4800 => libc::B4800,
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        #[cfg(target_os = "linux")]
+        460800 => libc::B460800,
+        #[cfg(target_os = "linux")]
+        921600 => libc::B921600,
+        #[cfg(target_os = "linux")]
+        1000000 => libc::B1000000,
+        #[cfg(target_os = "linux")]
+        1500000 => libc::B1500000,
+        #[cfg(target_os = "linux")]
+        2000000 => libc::B2000000,
+        #[cfg(target_os = "linux")]
+        3000000 => libc::B3000000,
+        _ => bail!("Unsupported baud rate {baud}"),
+    };
+
+    let fd = file.as_raw_fd();
+    // SAFETY: termios is plain data, filled in by tcgetattr
+    let mut tio: libc::termios = unsafe { std::mem::zeroed() };
+    // SAFETY: `fd` is open and `tio` is valid
+    unsafe {
+        if libc::tcgetattr(fd, &mut tio) != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        libc::cfmakeraw(&mut tio);
+        libc::cfsetispeed(&mut tio, speed);
+        libc::cfsetospeed(&mut tio, speed);
+    }
+    tio.c_cflag |= libc::CLOCAL | libc::CREAD;
+    // Reads return after a tenth of a second without data
+    tio.c_cc[libc::VMIN] = 0;
+    tio.c_cc[libc::VTIME] = 1;
+    // SAFETY: As above
+    unsafe {
+        if libc::tcsetattr(fd, libc::TCSANOW, &tio) != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        libc::tcflush(fd, libc::TCIOFLUSH);
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn configure(_file: &File, _baud: u32) -> anyhow::Result<()> {
+    bail!("Serial ports can only be set up on Unix")
+}
+
+impl Serial {
+    fn open(path: &OsStr, baud: u32) -> anyhow::Result<Self> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.custom_flags(libc::O_NOCTTY);
+        }
+        let file = options
+            .open(path)
+            .with_context(|| format!("Cannot open serial port {path:?}"))?;
+        configure(&file, baud).with_context(|| format!("Cannot set up serial port {path:?}"))?;
+        Ok(Self { file })
+    }
+
+    /// Read a byte, or `None` if there is none within `timeout`
+    fn read_byte(&mut self, timeout: Duration) -> io::Result<Option<u8>> {
+        let deadline = Instant::now() + timeout;
+        let mut byte = [0];
+        loop {
+            if self.file.read(&mut byte)? == 1 {
+                return Ok(Some(byte[0]));
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Wait for the receiver to ask for blocks, returning whether it wants
+    /// CRCs instead of checksums
+    ///
+    /// Anything else, such as messages from the bootloader, is skipped.
+    fn wait_start(&mut self, timeout: Duration) -> anyhow::Result<bool> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.read_byte(left)? {
+                Some(CRC) => return Ok(true),
+                Some(NAK) => return Ok(false),
+                Some(CAN) => bail!("Receiver cancelled the transfer"),
+                Some(_) => {}
+                None => bail!("Receiver did not ask for the image in time"),
+            }
+        }
+    }
+
+    /// Wait for the receiver to answer, returning ACK, NAK or `None` for no
+    /// answer
+    fn wait_reply(&mut self) -> anyhow::Result<Option<u8>> {
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.read_byte(left)? {
+                Some(reply @ (ACK | NAK)) => return Ok(Some(reply)),
+                Some(CAN) => bail!("Receiver cancelled the transfer"),
+                Some(_) => {}
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Send a block until it is acknowledged, padding it to `size` bytes
+    fn send_block(
+        &mut self,
+        crc: bool,
+        number: u8,
+        data: &[u8],
+        size: usize,
+        pad: u8,
+    ) -> anyhow::Result<()> {
+        let mut block = data.to_vec();
+        block.resize(size, pad);
+
+        let header = match size {
+            1024 => STX,
+            _ => SOH,
+        };
+        let mut packet = vec![header, number, !number];
+        packet.extend(&block);
+        match crc {
+            true => packet.extend(crc16(&block).to_be_bytes()),
+            false => packet.push(block.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))),
+        }
+
+        for _ in 0..RETRIES {
+            self.file.write_all(&packet)?;
+            if self.wait_reply()? == Some(ACK) {
+                return Ok(());
+            }
+        }
+        bail!("Receiver did not take block {number} after {RETRIES} tries")
+    }
+
+    /// Send the end of the transfer until it is acknowledged
+    fn send_eot(&mut self) -> anyhow::Result<()> {
+        for _ in 0..RETRIES {
+            self.file.write_all(&[EOT])?;
+            if self.wait_reply()? == Some(ACK) {
+                return Ok(());
+            }
+        }
+        bail!("Receiver did not acknowledge the end of the transfer")
+    }
+}
+
+/// CRC-16/XMODEM
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in data {
+        crc ^= u16::from(b) << 8;
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x1021,
+            };
+        }
+    }
+    crc
+}
+
+/// Lay out the input files and send the image to a bootloader on a serial
+/// port
+pub fn run(args: UploadArgs) -> anyhow::Result<()> {
+    let mut layout = layout::layout(&args.input, &args.layout)?;
+    let image = &mut layout.image;
+    if let Some(byte) = args.gap_fill {
+        image.push_under(Chunk {
+            offset: 0,
+            size: image.size(),
+            data: Data::Fill { byte },
+        });
+    }
+    let data = image.read_to_vec()?;
+
+    let mut serial = Serial::open(&args.port, args.baud)?;
+    log::info!(
+        "Waiting for the receiver on {port:?} to ask for {size:#x} bytes",
+        port = args.port,
+        size = data.len(),
+    );
+    let crc = serial.wait_start(Duration::from_secs(args.timeout))?;
+
+    let block_size = match args.protocol {
+        Protocol::Xmodem => 128,
+        Protocol::Xmodem1k | Protocol::Ymodem => 1024,
+    };
+
+    if args.protocol == Protocol::Ymodem {
+        // Block zero has the file name and size
+        let name = Path::new(&args.input[0]).with_extension("bin");
+        let name = name.file_name().unwrap_or_default().to_string_lossy();
+        let header = format!("{name}\0{}\0", data.len());
+        serial.send_block(crc, 0, header.as_bytes(), 128, 0)?;
+        serial.wait_start(REPLY_TIMEOUT)?;
+    }
+
+    let mut blocks = 0;
+    for (i, block) in data.chunks(block_size).enumerate() {
+        serial.send_block(crc, (i + 1) as u8, block, block_size, SUB)?;
+        blocks += 1;
+        log::debug!("Sent block {blocks}");
+    }
+    serial.send_eot()?;
+
+    if args.protocol == Protocol::Ymodem {
+        // An empty block zero ends the batch
+        serial.wait_start(REPLY_TIMEOUT)?;
+        serial.send_block(crc, 0, &[], 128, 0)?;
+    }
+
+    log::info!("Sent {size:#x} bytes in {blocks} blocks", size = data.len());
+    Ok(())
+}
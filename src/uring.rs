@@ -0,0 +1,367 @@
+//! Writing images with Linux io_uring, so that reading the next chunk overlaps
+//! with writing earlier ones
+
+use std::{
+    fs::File,
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use crate::image::Image;
+
+/// How many writes can be in flight at once
+const DEPTH: u32 = 8;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_OP_WRITE: u8 = 23;
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+/// Submission queue entry
+#[repr(C)]
+#[derive(Debug, Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+/// Completion queue entry
+#[repr(C)]
+#[derive(Debug)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// A memory map of part of the ring
+struct Map {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Map {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        // SAFETY: Mapping ring memory shared with the kernel
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            ptr: ptr.cast(),
+            len,
+        })
+    }
+
+    /// The `u32` at `offset`, shared with the kernel
+    fn u32(&self, offset: u32) -> &AtomicU32 {
+        // SAFETY: Offsets from the kernel are aligned and within the map
+        unsafe { &*self.ptr.add(offset as usize).cast::<AtomicU32>() }
+    }
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        // SAFETY: Unmapping what `new` mapped
+        unsafe {
+            libc::munmap(self.ptr.cast(), self.len);
+        }
+    }
+}
+
+/// An io_uring instance
+struct Ring {
+    // Maps are dropped before the ring is closed
+    sq: Map,
+    cq: Map,
+    sqes: Map,
+    params: Params,
+    /// Entries queued that the kernel has not taken yet
+    unsubmitted: u32,
+    fd: OwnedFd,
+}
+
+impl Ring {
+    fn new(entries: u32) -> io::Result<Self> {
+        let mut params = Params::default();
+        // SAFETY: `params` is valid for the kernel to fill in
+        let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, entries, &mut params) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: The kernel just gave us this file descriptor
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+
+        let raw = fd.as_raw_fd();
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len = params.cq_off.cqes as usize + params.cq_entries as usize * size_of::<Cqe>();
+        let sqes_len = params.sq_entries as usize * size_of::<Sqe>();
+
+        Ok(Self {
+            sq: Map::new(raw, sq_len, IORING_OFF_SQ_RING)?,
+            cq: Map::new(raw, cq_len, IORING_OFF_CQ_RING)?,
+            sqes: Map::new(raw, sqes_len, IORING_OFF_SQES)?,
+            params,
+            unsubmitted: 0,
+            fd,
+        })
+    }
+
+    /// Submit queued entries, and wait for completions if asked to
+    fn enter(&mut self, min_complete: u32, flags: u32) -> io::Result<()> {
+        loop {
+            // SAFETY: No signal mask is passed
+            let res = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd.as_raw_fd(),
+                    self.unsubmitted,
+                    min_complete,
+                    flags,
+                    ptr::null::<libc::sigset_t>(),
+                    0,
+                )
+            };
+            if res >= 0 {
+                self.unsubmitted -= res as u32;
+                return Ok(());
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+
+    /// Start writing `buf` to `fd` at `offset`
+    ///
+    /// Even if this fails, the write is queued, and is submitted by a later
+    /// `wait`.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must stay valid until its completion is returned by `wait`, even
+    /// if this fails.
+    unsafe fn write(
+        &mut self,
+        fd: RawFd,
+        buf: &[u8],
+        offset: u64,
+        user_data: u64,
+    ) -> io::Result<()> {
+        let off = &self.params.sq_off;
+        let tail = self.sq.u32(off.tail).load(Ordering::Relaxed);
+        let index = tail & self.sq.u32(off.ring_mask).load(Ordering::Relaxed);
+
+        let sqe = Sqe {
+            opcode: IORING_OP_WRITE,
+            fd,
+            off: offset,
+            addr: buf.as_ptr() as u64,
+            len: buf.len() as u32,
+            user_data,
+            ..Default::default()
+        };
+        self.sqes.ptr.cast::<Sqe>().add(index as usize).write(sqe);
+        self.sq
+            .u32(off.array + index * 4)
+            .store(index, Ordering::Relaxed);
+        self.sq
+            .u32(off.tail)
+            .store(tail.wrapping_add(1), Ordering::Release);
+        self.unsubmitted += 1;
+
+        self.enter(0, 0)
+    }
+
+    /// Wait for a write to finish, returning its `user_data` and result
+    fn wait(&mut self) -> io::Result<(u64, i32)> {
+        let (head_offset, tail_offset) = (self.params.cq_off.head, self.params.cq_off.tail);
+        let head = self.cq.u32(head_offset).load(Ordering::Relaxed);
+        while self.cq.u32(tail_offset).load(Ordering::Acquire) == head {
+            self.enter(1, IORING_ENTER_GETEVENTS)?;
+        }
+        let off = &self.params.cq_off;
+
+        let index = head & self.cq.u32(off.ring_mask).load(Ordering::Relaxed);
+        // SAFETY: The entry at `head` was filled in by the kernel
+        let cqe = unsafe {
+            (self.cq.ptr.add(off.cqes as usize).cast::<Cqe>())
+                .add(index as usize)
+                .read()
+        };
+        self.cq
+            .u32(off.head)
+            .store(head.wrapping_add(1), Ordering::Release);
+        Ok((cqe.user_data, cqe.res))
+    }
+}
+
+/// A write in flight, and how much of it is done
+struct Pending {
+    data: Vec<u8>,
+    offset: u64,
+    done: usize,
+}
+
+/// Write the image by seeking, leaving gaps unwritten, with up to a few writes
+/// of `buffer_size` in flight
+pub fn write(image: &mut Image, output: &File, buffer_size: usize) -> io::Result<()> {
+    let mut ring = Ring::new(DEPTH)?;
+    let mut slots: Vec<Option<Pending>> = (0..DEPTH).map(|_| None).collect();
+
+    let res = write_chunks(image, output, buffer_size.max(4096), &mut ring, &mut slots);
+
+    // Buffers need to outlive their writes, even when giving up. If the
+    // writes cannot be waited for, the kernel may still be reading them, so
+    // they are leaked.
+    while slots.iter().any(Option::is_some) {
+        match ring.wait() {
+            Ok((slot, _)) => slots[slot as usize] = None,
+            Err(e) => {
+                std::mem::forget(slots);
+                return Err(e);
+            }
+        }
+    }
+
+    res?;
+    output.set_len(image.size())
+}
+
+fn write_chunks(
+    image: &mut Image,
+    output: &File,
+    buffer_size: usize,
+    ring: &mut Ring,
+    slots: &mut [Option<Pending>],
+) -> io::Result<()> {
+    let fd = output.as_raw_fd();
+
+    for chunk in image.resolved() {
+        let mut pos = chunk.offset;
+        while pos < chunk.end() {
+            let slot = match slots.iter().position(Option::is_none) {
+                Some(slot) => slot,
+                None => complete(ring, fd, slots)?,
+            };
+
+            let piece = chunk
+                .slice(pos, pos.saturating_add(buffer_size as u64))
+                .expect("Empty piece of chunk");
+            let mut data = Vec::with_capacity(piece.size as usize);
+            image.copy_chunk(&piece, &mut data)?;
+            pos = piece.end();
+
+            let pending = slots[slot].insert(Pending {
+                data,
+                offset: piece.offset,
+                done: 0,
+            });
+            // SAFETY: The buffer is kept in `slots` until the write completes
+            unsafe { ring.write(fd, &pending.data, pending.offset, slot as u64)? };
+        }
+    }
+
+    while slots.iter().any(Option::is_some) {
+        complete(ring, fd, slots)?;
+    }
+    Ok(())
+}
+
+/// Wait for a write to finish, writing the rest of it if it was short, and
+/// return its slot once it is free
+fn complete(ring: &mut Ring, fd: RawFd, slots: &mut [Option<Pending>]) -> io::Result<usize> {
+    loop {
+        let (slot, res) = ring.wait()?;
+        let slot = slot as usize;
+        let pending = slots[slot].as_mut().expect("Completion for an empty slot");
+
+        if res < 0 {
+            slots[slot] = None;
+            return Err(io::Error::from_raw_os_error(-res));
+        }
+        if res == 0 {
+            slots[slot] = None;
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+
+        pending.done += res as usize;
+        if pending.done == pending.data.len() {
+            slots[slot] = None;
+            return Ok(slot);
+        }
+
+        let rest = &pending.data[pending.done..];
+        let offset = pending.offset + pending.done as u64;
+        // SAFETY: The buffer is still kept in `slots`
+        unsafe { ring.write(fd, rest, offset, slot as u64)? };
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    ops::Range,
+};
+
+use anyhow::bail;
+use clap::Args;
+use clap_num::maybe_hex;
+
+use crate::{
+    image::Image,
+    layout::{self, LayoutArgs},
+    log,
+};
+
+#[derive(Debug, Args)]
+pub struct VerifyArgs {
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// Expect gaps between segments, and anything past the end of the image,
+    /// to be filled with this byte instead of zeros
+    #[arg(long, value_name = "BYTE", value_parser = maybe_hex::<u8>, env = "ELFCOPYFLAT_GAP_FILL")]
+    gap_fill: Option<u8>,
+
+    /// Do not check gaps between segments, or anything past the end of the
+    /// image
+    #[arg(long, conflicts_with = "gap_fill")]
+    ignore_gaps: bool,
+
+    /// Flat binary to check, such as one read back from flash
+    image: OsString,
+
+    /// Input ELF files the image should have been made from
+    #[arg(required = true)]
+    input: Vec<OsString>,
+}
+
+/// What gaps between chunks of an image should contain
+#[derive(Debug, Clone, Copy)]
+pub enum Gaps {
+    Fill(u8),
+    Ignore,
+}
+
+/// Compares everything written to it against a file
+struct Compare {
+    actual: BufReader<File>,
+    pos: u64,
+    buf: Vec<u8>,
+    mismatches: Vec<Range<u64>>,
+}
+
+impl Compare {
+    fn mismatch(&mut self, range: Range<u64>) {
+        match self.mismatches.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => self.mismatches.push(range),
+        }
+    }
+
+    /// Skip over bytes of the file without checking them
+    fn skip(&mut self, size: u64) -> io::Result<()> {
+        let skipped = io::copy(&mut (&mut self.actual).take(size), &mut io::sink())?;
+        self.pos += size;
+        if skipped < size {
+            self.mismatch(self.pos - (size - skipped)..self.pos);
+        }
+        Ok(())
+    }
+}
+
+impl Write for Compare {
+    fn write(&mut self, expected: &[u8]) -> io::Result<usize> {
+        self.buf.clear();
+        (&mut self.actual)
+            .take(expected.len() as u64)
+            .read_to_end(&mut self.buf)?;
+
+        let start = self.pos;
+        for (i, &byte) in expected.iter().enumerate() {
+            if self.buf.get(i) != Some(&byte) {
+                let at = start + i as u64;
+                self.mismatch(at..at + 1);
+            }
+        }
+
+        self.pos += expected.len() as u64;
+        Ok(expected.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Compare the contents of `actual` against `image`, returning the ranges of
+/// offsets that differ
+///
+/// Gaps between chunks are checked against `gaps`, and so is anything in
+/// `actual` past the end of the image. If `actual` is shorter than the image,
+/// the missing part differs.
+pub fn compare(image: &mut Image, actual: File, gaps: Gaps) -> io::Result<Vec<Range<u64>>> {
+    let mut cmp = Compare {
+        actual: BufReader::new(actual),
+        pos: 0,
+        buf: Vec::new(),
+        mismatches: Vec::new(),
+    };
+
+    // Check the gap from the current position up to `end`
+    let check_gap = |cmp: &mut Compare, end: u64| {
+        let size = end - cmp.pos;
+        match gaps {
+            Gaps::Fill(byte) => io::copy(&mut io::repeat(byte).take(size), cmp).map(|_| ()),
+            Gaps::Ignore => cmp.skip(size),
+        }
+    };
+
+    for chunk in image.resolved() {
+        check_gap(&mut cmp, chunk.offset)?;
+        let copied = image.copy_chunk(&chunk, &mut cmp)?;
+        // Chunks from files may be cut short by the end of the file, and the
+        // rest is zero
+        io::copy(&mut io::repeat(0).take(chunk.size - copied), &mut cmp)?;
+    }
+    check_gap(&mut cmp, image.size())?;
+
+    // Anything left is past the end of the image
+    let image_end = cmp.pos;
+    let mut rest = Vec::new();
+    cmp.actual.read_to_end(&mut rest)?;
+    if let Gaps::Fill(byte) = gaps {
+        if let Some(i) = rest.iter().position(|&b| b != byte) {
+            let end = image_end + rest.iter().rposition(|&b| b != byte).unwrap() as u64 + 1;
+            cmp.mismatch(image_end + i as u64..end);
+        }
+    }
+
+    Ok(cmp.mismatches)
+}
+
+/// Print up to a few of the differing ranges found by `compare`
+pub fn report(mismatches: &[Range<u64>], base: u64) {
+    const SHOWN: usize = 10;
+
+    for r in mismatches.iter().take(SHOWN) {
+        log::error!(
+            "Differs at offset {start:#x}..{end:#x} (address {addr_start:#x}..{addr_end:#x})",
+            start = r.start,
+            end = r.end,
+            addr_start = base.wrapping_add(r.start),
+            addr_end = base.wrapping_add(r.end),
+        );
+    }
+    if mismatches.len() > SHOWN {
+        log::error!("... and {} more", mismatches.len() - SHOWN);
+    }
+}
+
+pub fn run(args: VerifyArgs) -> anyhow::Result<()> {
+    let mut layout = layout::layout(&args.input, &args.layout)?;
+    let actual = File::open(&args.image)?;
+
+    let gaps = match (args.ignore_gaps, args.gap_fill) {
+        (true, _) => Gaps::Ignore,
+        (false, byte) => Gaps::Fill(byte.unwrap_or(0)),
+    };
+
+    let mismatches = compare(&mut layout.image, actual, gaps)?;
+    if !mismatches.is_empty() {
+        report(&mismatches, layout.base);
+        let count: u64 = mismatches.iter().map(|r| r.end - r.start).sum();
+        bail!(
+            "{path:?} does not match the input files, {count:#x} bytes differ",
+            path = args.image,
+        )
+    }
+
+    log::info!("{path:?} matches the input files", path = args.image);
+
+    Ok(())
+}
@@ -0,0 +1,236 @@
+use std::{ffi::OsString, fs};
+
+use anyhow::{bail, Context};
+use clap::Args;
+use clap_num::maybe_hex;
+
+use crate::{
+    elf::{Class, FileType, Flags, SectionFlags, SectionType, Type},
+    layout::{self, Arch},
+};
+
+#[derive(Debug, Args)]
+pub struct WrapArgs {
+    /// Address to load the flat binary at
+    #[arg(long, value_name = "ADDRESS", value_parser = maybe_hex::<u64>)]
+    base: u64,
+
+    /// Entry point (Defaults to the base address)
+    #[arg(long, value_name = "ADDRESS", value_parser = maybe_hex::<u64>)]
+    entry: Option<u64>,
+
+    /// Flags of the segment (among "rwx")
+    #[arg(long, value_name = "FLAGS", value_parser = layout::parse_flags, default_value = "rwx")]
+    flags: u32,
+
+    /// Architecture, which also picks the class and endianness unless given
+    #[arg(long, value_name = "ARCH")]
+    machine: Arch,
+
+    /// Write a 32-bit ELF file
+    #[arg(long, conflicts_with = "elf64")]
+    elf32: bool,
+
+    /// Write a 64-bit ELF file
+    #[arg(long)]
+    elf64: bool,
+
+    /// Write a big endian ELF file
+    #[arg(long, conflicts_with = "little_endian")]
+    big_endian: bool,
+
+    /// Write a little endian ELF file
+    #[arg(long)]
+    little_endian: bool,
+
+    /// Memory size of the segment, if larger than the flat binary, with the
+    /// rest being zero
+    #[arg(long, value_name = "SIZE", value_parser = layout::parse_size)]
+    mem_size: Option<u64>,
+
+    /// Alignment of the segment
+    #[arg(long, value_name = "ALIGN", value_parser = maybe_hex::<u64>, default_value = "0x1000")]
+    align: u64,
+
+    /// Input flat binary
+    input: OsString,
+
+    /// Output ELF file
+    output: OsString,
+}
+
+/// Writes ELF fields with the right size and endianness
+struct Writer {
+    buf: Vec<u8>,
+    elf64: bool,
+    little_endian: bool,
+}
+
+impl Writer {
+    fn bytes<const N: usize>(&mut self, le: [u8; N], be: [u8; N]) {
+        match self.little_endian {
+            true => self.buf.extend(le),
+            false => self.buf.extend(be),
+        }
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(value.to_le_bytes(), value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(value.to_le_bytes(), value.to_be_bytes());
+    }
+
+    /// An address or offset, which is 32 or 64 bits depending on the class
+    fn word(&mut self, value: u64) {
+        match self.elf64 {
+            true => self.bytes(value.to_le_bytes(), value.to_be_bytes()),
+            false => self.u32(value as u32),
+        }
+    }
+
+    fn pad_to(&mut self, offset: u64) {
+        self.buf.resize(offset as usize, 0);
+    }
+}
+
+/// Wrap a flat binary in an ELF file with one PT_LOAD segment, and a section
+/// for tools that want one
+pub fn run(args: WrapArgs) -> anyhow::Result<()> {
+    let data = fs::read(&args.input).with_context(|| format!("Cannot read {:?}", args.input))?;
+
+    let (machine, class, little_endian) = args.machine.elf_defaults();
+    let elf64 = match (args.elf32, args.elf64) {
+        (true, _) => false,
+        (_, true) => true,
+        _ => class == Class::ELFCLASS64,
+    };
+    let little_endian = match (args.big_endian, args.little_endian) {
+        (true, _) => false,
+        (_, true) => true,
+        _ => little_endian,
+    };
+
+    let file_size = data.len() as u64;
+    let mem_size = args.mem_size.unwrap_or(0).max(file_size);
+    let entry = args.entry.unwrap_or(args.base);
+    if !args.align.is_power_of_two() {
+        bail!("Alignment {:#x} is not a power of two", args.align)
+    }
+    let limit = match elf64 {
+        true => u64::MAX,
+        false => u32::MAX.into(),
+    };
+    if args
+        .base
+        .checked_add(mem_size)
+        .is_none_or(|end| end - 1 > limit)
+        || entry > limit
+    {
+        bail!("Addresses do not fit in a 32-bit ELF file (Use --elf64)")
+    }
+
+    let (ehdr_size, phdr_size, shdr_size): (u64, u64, u64) = match elf64 {
+        true => (64, 56, 64),
+        false => (52, 32, 40),
+    };
+    // The offset of the contents agrees with the address modulo the alignment
+    let data_offset = (ehdr_size + phdr_size).next_multiple_of(args.align) + args.base % args.align;
+    use SectionFlags as F;
+    let (section, section_flags) = match args.flags {
+        f if f & Flags::PF_X.0 != 0 => (".text", F::SHF_ALLOC.0 | F::SHF_EXECINSTR.0),
+        f if f & Flags::PF_W.0 != 0 => (".data", F::SHF_ALLOC.0 | F::SHF_WRITE.0),
+        _ => (".rodata", F::SHF_ALLOC.0),
+    };
+    let shstrtab = format!("\0{section}\0.shstrtab\0");
+    let shstrtab_offset = data_offset + file_size;
+    let sh_offset = (shstrtab_offset + shstrtab.len() as u64).next_multiple_of(8);
+    if !elf64 && sh_offset > limit {
+        bail!("Input is too large for a 32-bit ELF file (Use --elf64)")
+    }
+
+    let mut w = Writer {
+        buf: Vec::new(),
+        elf64,
+        little_endian,
+    };
+
+    // ELF header
+    w.buf.extend(b"\x7fELF");
+    w.buf.push(if elf64 { 2 } else { 1 });
+    w.buf.push(if little_endian { 1 } else { 2 });
+    w.buf.push(1); // EI_VERSION
+    w.pad_to(16);
+    w.u16(FileType::ET_EXEC.0);
+    w.u16(machine.0);
+    w.u32(1); // e_version
+    w.word(entry);
+    w.word(ehdr_size); // e_phoff
+    w.word(sh_offset);
+    w.u32(0); // e_flags
+    w.u16(ehdr_size as u16);
+    w.u16(phdr_size as u16);
+    w.u16(1); // e_phnum
+    w.u16(shdr_size as u16);
+    w.u16(3); // e_shnum
+    w.u16(2); // e_shstrndx
+
+    // Program header
+    w.u32(Type::PT_LOAD.0);
+    if elf64 {
+        w.u32(args.flags);
+    }
+    w.word(data_offset);
+    w.word(args.base); // p_vaddr
+    w.word(args.base); // p_paddr
+    w.word(file_size);
+    w.word(mem_size);
+    if !elf64 {
+        w.u32(args.flags);
+    }
+    w.word(args.align);
+
+    w.pad_to(data_offset);
+    w.buf.extend(&data);
+    w.buf.extend(shstrtab.as_bytes());
+    w.pad_to(sh_offset);
+
+    // Section headers: null, the contents, and section names
+    let sections = [
+        (0, SectionType::SHT_NULL.0, 0, 0, 0, 0, 0),
+        (
+            1,
+            SectionType::SHT_PROGBITS.0,
+            section_flags,
+            args.base,
+            data_offset,
+            file_size,
+            1,
+        ),
+        (
+            1 + section.len() as u32 + 1,
+            SectionType::SHT_STRTAB.0,
+            0,
+            0,
+            shstrtab_offset,
+            shstrtab.len() as u64,
+            1,
+        ),
+    ];
+    for (name, sh_type, flags, addr, offset, size, align) in sections {
+        w.u32(name);
+        w.u32(sh_type);
+        w.word(flags);
+        w.word(addr);
+        w.word(offset);
+        w.word(size);
+        w.u32(0); // sh_link
+        w.u32(0); // sh_info
+        w.word(align);
+        w.word(0); // sh_entsize
+    }
+
+    fs::write(&args.output, &w.buf).with_context(|| format!("Cannot write {:?}", args.output))?;
+    Ok(())
+}